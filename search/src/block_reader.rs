@@ -0,0 +1,740 @@
+use crate::chain::check_proof_of_work;
+use crate::network::Network;
+use crate::parser::{hash_twice, XorKey};
+use crate::transaction::{BlockHash, MerkleRoot};
+use std::collections::VecDeque;
+use std::io::{BufReader, ErrorKind, Read};
+
+const HEADER_SIZE: usize = 80;
+
+// Blocks can't be larger than this (`MAX_BLOCK_SERIALIZED_SIZE` in Bitcoin Core), so any larger
+// size must come from corrupt data.
+const MAX_BLOCK_SIZE: usize = 4_000_000;
+
+// Errors that come up while reading or decoding block data. They all carry the name of the input
+// and the byte offset in it where the problem was found.
+#[derive(Debug, thiserror::Error)]
+pub enum ParseError {
+    #[error("failed to read {file} at offset {offset}: {source}")]
+    Io {
+        file: String,
+        offset: u64,
+        source: std::io::Error,
+    },
+    #[error(
+        "expected {network} magic bytes in {file} at offset {offset}, found {}{}",
+        hex::encode(.found),
+        bad_magic_hint(.found, *.xor_hint)
+    )]
+    BadMagic {
+        file: String,
+        offset: u64,
+        network: Network,
+        found: Vec<u8>,
+        xor_hint: bool,
+    },
+    #[error("block in {file} at offset {offset} claims to be {size} bytes long, which is more than any valid block")]
+    Oversized {
+        file: String,
+        offset: u64,
+        size: u64,
+    },
+    #[error(
+        "block in {file} at offset {offset} needs {expected} bytes, but only {available} bytes are left"
+    )]
+    Truncated {
+        file: String,
+        offset: u64,
+        expected: u64,
+        available: u64,
+    },
+    #[error(
+        "failed to decode block{} in {file} at offset {offset}: {context}",
+        .block.map(|b| format!(" {:?}", b)).unwrap_or_default()
+    )]
+    Decode {
+        file: String,
+        offset: u64,
+        // The hash of the block, if its header could be read.
+        block: Option<BlockHash>,
+        // What was being decoded when the error happened.
+        context: String,
+    },
+    #[error("merkle root {expected:?} of block {block:?} in {file} at offset {offset} does not match its transactions")]
+    MerkleMismatch {
+        file: String,
+        offset: u64,
+        block: BlockHash,
+        expected: MerkleRoot,
+    },
+    #[error(
+        "block {block:?} in {file} at offset {offset} fails the proof-of-work check: {reason}"
+    )]
+    BadProofOfWork {
+        file: String,
+        offset: u64,
+        block: BlockHash,
+        reason: String,
+    },
+}
+
+impl ParseError {
+    // Whether parsing can continue after this error by skipping the affected data. I/O errors mean
+    // the rest of the input can't be read either.
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(self, ParseError::Io { .. })
+    }
+
+    pub fn offset(&self) -> u64 {
+        match self {
+            ParseError::Io { offset, .. }
+            | ParseError::BadMagic { offset, .. }
+            | ParseError::Oversized { offset, .. }
+            | ParseError::Truncated { offset, .. }
+            | ParseError::Decode { offset, .. }
+            | ParseError::MerkleMismatch { offset, .. }
+            | ParseError::BadProofOfWork { offset, .. } => *offset,
+        }
+    }
+}
+
+fn bad_magic_hint(found: &[u8], xor_hint: bool) -> String {
+    match (Network::from_magic(found), xor_hint) {
+        (Some(other), _) => format!(" (these are the magic bytes of {}; is the network set correctly?)", other),
+        (None, true) => String::from(" (blk files written by Bitcoin Core 28 or later are XOR-obfuscated; is xor.dat missing?)"),
+        (None, false) => String::new(),
+    }
+}
+
+// A block as stored in a blk file, without the magic bytes and size in front of it. It always holds
+// at least a block header.
+pub struct RawBlock {
+    // Offset of the block's magic bytes in its input.
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+// Reads the blocks of a single input one at a time, so only the block currently being looked at
+// has to be kept in memory, no matter how large the input is.
+pub struct BlockReader<R: Read> {
+    name: String,
+    reader: BufReader<R>,
+    // The network whose magic bytes precede every block.
+    network: Network,
+    // Offset of the next byte to be returned, counting bytes in `pending`.
+    offset: u64,
+    xor_key: Option<XorKey>,
+    // De-obfuscated bytes that were read, but have to be looked at again.
+    pending: VecDeque<u8>,
+    // Buffer for the part of a block after its header, when only the header is returned.
+    skipped_part: Vec<u8>,
+    // Offset right after the last block that was returned. Unlike `offset`, this doesn't include
+    // any padding or corrupt data that follows.
+    end_of_last_block: u64,
+}
+
+impl<R: Read> BlockReader<R> {
+    pub fn new(
+        name: String,
+        reader: R,
+        network: Network,
+        xor_key: Option<XorKey>,
+    ) -> BlockReader<R> {
+        BlockReader::new_at(name, reader, network, xor_key, 0)
+    }
+
+    // Like `new`, for a `reader` that starts at `offset` in its input rather than at the beginning.
+    pub fn new_at(
+        name: String,
+        reader: R,
+        network: Network,
+        xor_key: Option<XorKey>,
+        offset: u64,
+    ) -> BlockReader<R> {
+        BlockReader {
+            name,
+            reader: BufReader::with_capacity(1 << 20, reader),
+            network,
+            offset,
+            xor_key,
+            pending: VecDeque::new(),
+            skipped_part: Vec::new(),
+            end_of_last_block: offset,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn end_of_last_block(&self) -> u64 {
+        self.end_of_last_block
+    }
+
+    // Returns the next block, or `None` at the end of the data. Bitcoin Core preallocates blk
+    // files, so the data may end in padding, which is skipped.
+    pub fn next_block(&mut self) -> Result<Option<RawBlock>, ParseError> {
+        self.next(false)
+    }
+
+    // Like `next_block`, but only returns the 80-byte block header and skips the transactions.
+    pub fn next_header(&mut self) -> Result<Option<RawBlock>, ParseError> {
+        self.next(true)
+    }
+
+    fn next(&mut self, header_only: bool) -> Result<Option<RawBlock>, ParseError> {
+        let mut offset = self.offset;
+        let mut prefix = [0u8; 8];
+        let mut n = self.read_up_to(&mut prefix)?;
+
+        // Blk files end in padding, and concatenated blk files have it between their blocks.
+        if n > 0 {
+            if let Some(padding) = self.padding_kind(&prefix[..n], offset) {
+                match self.skip_padding(padding)? {
+                    None => return Ok(None),
+                    Some(first) => {
+                        offset = self.offset - 1;
+                        prefix[0] = first;
+                        n = 1 + self.read_up_to(&mut prefix[1..])?;
+                    }
+                }
+            }
+        }
+
+        let magic = self.network.magic();
+        let magic_len = n.min(magic.len());
+        if n == 0 {
+            return Ok(None);
+        }
+        if prefix[..magic_len] != magic[..magic_len] {
+            // The magic bytes of the next block might start anywhere in the data that was just read.
+            self.unread(&prefix[1..n]);
+            return Err(ParseError::BadMagic {
+                file: self.name.clone(),
+                offset,
+                network: self.network,
+                found: prefix[..magic_len].to_vec(),
+                xor_hint: self.xor_key.is_none(),
+            });
+        }
+        if n < prefix.len() {
+            return Err(self.truncated(offset, prefix.len(), n));
+        }
+
+        // From here on, a record that turns out not to be a block is put back, except for its
+        // magic bytes. A stray copy of the magic bytes followed by a large size could otherwise hide
+        // the real blocks in the range it claims, so they are looked for right after it.
+        let size_bytes = &prefix[4..8];
+        let size = u32::from_le_bytes(size_bytes.try_into().unwrap()) as usize;
+        if size == 0 {
+            return Ok(None);
+        }
+        if size > MAX_BLOCK_SIZE {
+            self.unread(size_bytes);
+            return Err(ParseError::Oversized {
+                file: self.name.clone(),
+                offset,
+                size: size as u64,
+            });
+        }
+
+        if size < HEADER_SIZE {
+            self.unread(size_bytes);
+            return Err(ParseError::Decode {
+                file: self.name.clone(),
+                offset,
+                block: None,
+                context: String::from("block is too short to hold a header"),
+            });
+        }
+
+        // Random data behind the magic bytes would otherwise pass for a block, so the header is
+        // checked before reading on.
+        let mut data = vec![0u8; HEADER_SIZE];
+        let n = self.read_up_to(&mut data)?;
+        if n < data.len() {
+            self.unread(&[size_bytes, &data[..n]].concat());
+            return Err(self.truncated(offset, 8 + size, 8 + n));
+        }
+        let block: BlockHash = hash_twice(&data).into();
+        let bits = u32::from_le_bytes(data[72..76].try_into().unwrap());
+        if let Err(reason) = check_proof_of_work(&block, bits, &self.network.pow_params()) {
+            self.unread(&[size_bytes, &data].concat());
+            return Err(ParseError::BadProofOfWork {
+                file: self.name.clone(),
+                offset,
+                block,
+                reason,
+            });
+        }
+
+        // When only the header is needed, the rest of the block is still read into `skipped_part`,
+        // so that it can be put back if the block turns out to be cut off.
+        let header_len = HEADER_SIZE;
+        let rest_len = size - header_len;
+        let n = match header_only {
+            true => {
+                let mut rest = std::mem::take(&mut self.skipped_part);
+                rest.resize(rest_len, 0);
+                let n = self.read_up_to(&mut rest)?;
+                self.skipped_part = rest;
+                n
+            }
+            false => {
+                data.resize(size, 0);
+                self.read_up_to(&mut data[header_len..])?
+            }
+        };
+        if n < rest_len {
+            let read = match header_only {
+                true => [size_bytes, &data, &self.skipped_part[..n]].concat(),
+                false => [size_bytes, &data[..header_len + n]].concat(),
+            };
+            self.unread(&read);
+            return Err(self.truncated(offset, 8 + size, 8 + header_len + n));
+        }
+
+        self.end_of_last_block = offset + 8 + size as u64;
+        Ok(Some(RawBlock { offset, data }))
+    }
+
+    // Skips ahead to the next network magic bytes, so that reading can continue after corrupt data.
+    // Does nothing if the next block starts right away.
+    pub fn resync(&mut self) -> Result<(), ParseError> {
+        let magic = self.network.magic();
+        let mut window = [0u8; 4];
+        let mut seen = 0;
+        let mut byte = [0u8; 1];
+        while self.read_up_to(&mut byte)? == 1 {
+            window = [window[1], window[2], window[3], byte[0]];
+            seen += 1;
+            if seen >= window.len() && window == magic {
+                self.unread(&magic);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Puts back bytes that were just read, so they are returned again by the next read.
+    fn unread(&mut self, data: &[u8]) {
+        for b in data.iter().rev() {
+            self.pending.push_front(*b);
+        }
+        self.offset -= data.len() as u64;
+    }
+
+    // Fills as much of `buf` as possible and de-obfuscates it. Returns less than `buf.len()` only at
+    // the end of the data.
+    fn read_up_to(&mut self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        let from_pending = self.pending.len().min(buf.len());
+        for (b, p) in buf.iter_mut().zip(self.pending.drain(..from_pending)) {
+            *b = p;
+        }
+        self.offset += from_pending as u64;
+
+        let buf = &mut buf[from_pending..];
+        let mut n = 0;
+        while n < buf.len() {
+            match self.reader.read(&mut buf[n..]) {
+                Ok(0) => break,
+                Ok(k) => n += k,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(self.io_error(e)),
+            }
+        }
+        if let Some(key) = self.xor_key {
+            xor_at_offset(&mut buf[..n], &key, self.offset);
+        }
+        self.offset += n as u64;
+        Ok(from_pending + n)
+    }
+
+    // Whether `data`, which starts at `offset`, is padding, and which kind. Bitcoin Core
+    // preallocates blk files with zeros, so that region reads as zeros, or as the repeating XOR key
+    // if it was never written through the obfuscation layer.
+    fn padding_kind(&self, data: &[u8], offset: u64) -> Option<Padding> {
+        let key = self.xor_key.unwrap_or_default();
+        match (
+            data.iter().all(|b| *b == 0),
+            data.iter()
+                .enumerate()
+                .all(|(i, b)| *b == key[((offset + i as u64) % 8) as usize]),
+        ) {
+            (true, _) => Some(Padding::Zeros),
+            (false, true) => Some(Padding::XorKey),
+            (false, false) => None,
+        }
+    }
+
+    // Consumes padding of the given kind. Returns the first byte after it, or `None` if the padding
+    // runs until the end of the data.
+    fn skip_padding(&mut self, padding: Padding) -> Result<Option<u8>, ParseError> {
+        let key = self.xor_key.unwrap_or_default();
+        let mut byte = [0u8; 1];
+        loop {
+            let offset = self.offset;
+            if self.read_up_to(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            let expected = match padding {
+                Padding::Zeros => 0,
+                Padding::XorKey => key[(offset % 8) as usize],
+            };
+            if byte[0] != expected {
+                return Ok(Some(byte[0]));
+            }
+        }
+    }
+
+    fn io_error(&self, source: std::io::Error) -> ParseError {
+        ParseError::Io {
+            file: self.name.clone(),
+            offset: self.offset,
+            source,
+        }
+    }
+
+    fn truncated(&self, offset: u64, expected: usize, available: usize) -> ParseError {
+        ParseError::Truncated {
+            file: self.name.clone(),
+            offset,
+            expected: expected as u64,
+            available: available as u64,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Padding {
+    Zeros,
+    XorKey,
+}
+
+// XORs `data`, which starts at `offset` in its file, with the obfuscation key.
+fn xor_at_offset(data: &mut [u8], key: &XorKey, offset: u64) {
+    let start = (offset % key.len() as u64) as usize;
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= key[(start + i) % key.len()];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // The mainnet genesis block.
+    const GENESIS: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c0101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+
+    const KEY: XorKey = [0x3c, 0xa1, 0x07, 0xfe, 0x52, 0x99, 0x10, 0xd4];
+
+    fn genesis() -> Vec<u8> {
+        hex::decode(GENESIS).unwrap()
+    }
+
+    // `blocks` as they are stored in a mainnet blk file.
+    fn blk(blocks: &[&[u8]]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for b in blocks {
+            data.extend_from_slice(&Network::Mainnet.magic());
+            data.extend_from_slice(&(b.len() as u32).to_le_bytes());
+            data.extend_from_slice(b);
+        }
+        data
+    }
+
+    fn reader(data: Vec<u8>, xor_key: Option<XorKey>) -> BlockReader<Cursor<Vec<u8>>> {
+        BlockReader::new(
+            String::from("blk00000.dat"),
+            Cursor::new(data),
+            Network::Mainnet,
+            xor_key,
+        )
+    }
+
+    // Reads blocks until the end of the data, and returns their offsets and contents.
+    fn read_all<R: Read>(reader: &mut BlockReader<R>) -> Vec<(u64, Vec<u8>)> {
+        let mut blocks = Vec::new();
+        while let Some(b) = reader.next_block().unwrap() {
+            blocks.push((b.offset, b.data));
+        }
+        blocks
+    }
+
+    #[test]
+    fn xor_at_offset_continues_the_key_where_the_data_starts() {
+        let mut data = vec![0u8; 12];
+        xor_at_offset(&mut data, &KEY, 5);
+        let expected: Vec<u8> = (5..17).map(|i| KEY[i % 8]).collect();
+        assert_eq!(data, expected);
+
+        // XORing twice gives the original data back.
+        xor_at_offset(&mut data, &KEY, 5);
+        assert_eq!(data, vec![0u8; 12]);
+    }
+
+    #[test]
+    fn reads_obfuscated_blocks() {
+        let genesis = genesis();
+        let mut data = blk(&[&genesis, &genesis]);
+        xor_at_offset(&mut data, &KEY, 0);
+
+        let blocks = read_all(&mut reader(data, Some(KEY)));
+        let second = 8 + genesis.len() as u64;
+        assert_eq!(blocks, vec![(0, genesis.clone()), (second, genesis)]);
+    }
+
+    #[test]
+    fn reads_obfuscated_blocks_from_the_middle_of_a_file() {
+        let genesis = genesis();
+        let mut data = blk(&[&genesis, &genesis]);
+        xor_at_offset(&mut data, &KEY, 0);
+
+        // The key is applied by offset in the file, so it has to carry on where reading resumes.
+        let start = 8 + genesis.len();
+        let mut reader = BlockReader::new_at(
+            String::from("blk00000.dat"),
+            Cursor::new(data[start..].to_vec()),
+            Network::Mainnet,
+            Some(KEY),
+            start as u64,
+        );
+        assert_eq!(read_all(&mut reader), vec![(start as u64, genesis)]);
+    }
+
+    #[test]
+    fn hints_at_a_missing_xor_key() {
+        let mut data = blk(&[&genesis()]);
+        xor_at_offset(&mut data, &KEY, 0);
+
+        let e = reader(data, None).next_block().err().unwrap();
+        assert!(matches!(
+            e,
+            ParseError::BadMagic {
+                offset: 0,
+                xor_hint: true,
+                ..
+            }
+        ));
+        assert!(e.to_string().contains("is xor.dat missing?"));
+    }
+
+    #[test]
+    fn skips_zero_padding_between_and_after_blocks() {
+        let genesis = genesis();
+        let mut data = blk(&[&genesis]);
+        data.extend_from_slice(&[0u8; 100]);
+        let second = data.len() as u64;
+        data.extend_from_slice(&blk(&[&genesis]));
+        data.extend_from_slice(&[0u8; 1000]);
+
+        let mut reader = reader(data, None);
+        assert_eq!(
+            read_all(&mut reader),
+            vec![(0, genesis.clone()), (second, genesis.clone())]
+        );
+        assert_eq!(
+            reader.end_of_last_block(),
+            second + 8 + genesis.len() as u64
+        );
+    }
+
+    #[test]
+    fn skips_padding_that_was_never_obfuscated() {
+        // Preallocated space reads as the key itself once it is de-obfuscated.
+        let genesis = genesis();
+        let mut data = blk(&[&genesis]);
+        xor_at_offset(&mut data, &KEY, 0);
+        data.extend_from_slice(&[0u8; 13]);
+        let second = data.len();
+        data.extend_from_slice(&blk(&[&genesis]));
+        xor_at_offset(&mut data[second..], &KEY, second as u64);
+        data.extend_from_slice(&[0u8; 1000]);
+
+        assert_eq!(
+            read_all(&mut reader(data, Some(KEY))),
+            vec![(0, genesis.clone()), (second as u64, genesis)]
+        );
+    }
+
+    #[test]
+    fn reads_only_headers() {
+        let genesis = genesis();
+        let data = blk(&[&genesis, &genesis]);
+
+        let mut reader = reader(data, None);
+        for i in 0..2 {
+            let b = reader.next_header().unwrap().unwrap();
+            assert_eq!(b.offset, i * (8 + genesis.len() as u64));
+            assert_eq!(b.data, genesis[..HEADER_SIZE]);
+        }
+        assert!(reader.next_header().unwrap().is_none());
+    }
+
+    #[test]
+    fn reports_truncated_blocks() {
+        let genesis = genesis();
+        let mut data = blk(&[&genesis, &genesis]);
+        data.truncate(data.len() - 10);
+
+        let mut reader = reader(data, None);
+        reader.next_block().unwrap().unwrap();
+        match reader.next_block() {
+            Err(ParseError::Truncated {
+                offset,
+                expected,
+                available,
+                ..
+            }) => {
+                assert_eq!(offset, 8 + genesis.len() as u64);
+                assert_eq!(expected, 8 + genesis.len() as u64);
+                assert_eq!(available, expected - 10);
+            }
+            r => panic!("expected a truncated block, got {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_blocks() {
+        let mut data = Network::Mainnet.magic().to_vec();
+        data.extend_from_slice(&(MAX_BLOCK_SIZE as u32 + 1).to_le_bytes());
+        data.extend_from_slice(&genesis());
+
+        assert!(matches!(
+            reader(data, None).next_block(),
+            Err(ParseError::Oversized { offset: 0, .. })
+        ));
+    }
+
+    // Bytes that don't form a block, and don't contain any magic bytes or padding.
+    fn junk(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 37 + 11) as u8).collect()
+    }
+
+    #[test]
+    fn resyncs_at_the_next_magic_bytes() {
+        let genesis = genesis();
+        let mut data = blk(&[&genesis]);
+        let junk_offset = data.len() as u64;
+        data.extend_from_slice(&junk(50));
+        let second = data.len() as u64;
+        data.extend_from_slice(&blk(&[&genesis]));
+
+        let mut reader = reader(data, None);
+        reader.next_block().unwrap().unwrap();
+        match reader.next_block() {
+            Err(ParseError::BadMagic { offset, .. }) => assert_eq!(offset, junk_offset),
+            r => panic!("expected bad magic bytes, got {:?}", r.map(|_| ())),
+        }
+        reader.resync().unwrap();
+        assert_eq!(read_all(&mut reader), vec![(second, genesis)]);
+    }
+
+    // Reads all blocks (or only their headers) like the parser does when it skips corrupt data, and
+    // returns the offsets of the blocks and of the errors.
+    fn read_skipping<R: Read>(
+        reader: &mut BlockReader<R>,
+        header_only: bool,
+    ) -> (Vec<u64>, Vec<u64>) {
+        let mut blocks = Vec::new();
+        let mut errors = Vec::new();
+        loop {
+            let result = match header_only {
+                true => reader.next_header(),
+                false => reader.next_block(),
+            };
+            match result {
+                Ok(Some(b)) => blocks.push(b.offset),
+                Ok(None) => return (blocks, errors),
+                Err(e) => {
+                    errors.push(e.offset());
+                    reader.resync().unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_junk_behind_magic_bytes() {
+        let genesis = genesis();
+        let junk = junk(100);
+        let data = blk(&[&genesis, &junk, &genesis]);
+        let junk_offset = 8 + genesis.len() as u64;
+
+        // Header-only reads check the proof of work too, so junk never makes it into the chain.
+        for header_only in [false, true] {
+            let mut reader = reader(data.clone(), None);
+            let mut next = || match header_only {
+                true => reader.next_header(),
+                false => reader.next_block(),
+            };
+            next().unwrap().unwrap();
+            match next() {
+                Err(ParseError::BadProofOfWork { offset, .. }) => assert_eq!(offset, junk_offset),
+                r => panic!("expected a proof-of-work failure, got {:?}", r.map(|_| ())),
+            }
+        }
+        for header_only in [false, true] {
+            assert_eq!(
+                read_skipping(&mut reader(data.clone(), None), header_only),
+                (
+                    vec![0, junk_offset + 8 + junk.len() as u64],
+                    vec![junk_offset]
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn finds_blocks_in_the_range_a_rejected_record_claims() {
+        let genesis = genesis();
+        let record = |size: usize, data: &[u8]| {
+            let mut r = Network::Mainnet.magic().to_vec();
+            r.extend_from_slice(&(size as u32).to_le_bytes());
+            r.extend_from_slice(data);
+            r
+        };
+
+        // Junk whose size covers the block after it, and more.
+        let mut data = record(1000, &junk(40));
+        data.extend_from_slice(&blk(&[&genesis, &genesis]));
+        data.extend_from_slice(&junk(1000));
+        let inner = 8 + 40;
+        let second = inner + 8 + genesis.len() as u64;
+        for header_only in [false, true] {
+            let (blocks, errors) = read_skipping(&mut reader(data.clone(), None), header_only);
+            assert_eq!(blocks, [inner, second]);
+            assert_eq!(errors[0], 0);
+        }
+
+        // A real header whose size runs past the end of the data, over the next block.
+        let mut data = record(100_000, &genesis);
+        data.extend_from_slice(&blk(&[&genesis]));
+        let inner = 8 + genesis.len() as u64;
+        for header_only in [false, true] {
+            let mut reader = reader(data.clone(), None);
+            assert_eq!(
+                read_skipping(&mut reader, header_only),
+                (vec![inner], vec![0])
+            );
+            assert_eq!(reader.end_of_last_block(), inner + 8 + genesis.len() as u64);
+        }
+
+        // A size that is too small for a header, but covers the start of the next block.
+        let mut data = record(HEADER_SIZE - 1, &junk(10));
+        data.extend_from_slice(&blk(&[&genesis]));
+        assert_eq!(
+            read_skipping(&mut reader(data, None), false),
+            (vec![8 + 10], vec![0])
+        );
+
+        // A size that is too large for any block.
+        let mut data = record(MAX_BLOCK_SIZE + 1, &[]);
+        data.extend_from_slice(&blk(&[&genesis]));
+        assert_eq!(
+            read_skipping(&mut reader(data, None), false),
+            (vec![8], vec![0])
+        );
+    }
+}
@@ -0,0 +1,308 @@
+use crate::node_rpc::{RpcClient, RpcEndpoint};
+use anyhow::{bail, Context};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+// Describes where the parser gets its raw block data from. Each variant is resolved into a list of
+// `BlockInput`s, which the parser then consumes in order.
+#[derive(Debug, Clone)]
+pub enum BlockSource {
+    // A Bitcoin Core `blocks` directory. All `blk*.dat` files in it are discovered automatically and
+    // ordered by their file index. Only files with an index in `start..end` are used (`end` is
+    // unbounded if `None`), and the selected files must not have any gaps.
+    Directory {
+        path: PathBuf,
+        start: u32,
+        end: Option<u32>,
+    },
+    // An explicit list of block files, parsed in the given order.
+    Files(Vec<PathBuf>),
+    // A glob pattern such as `/data/blocks/blk0000*.dat`. Matches are ordered by file index.
+    Glob(String),
+    // Raw block data (in the same format as a blk file) piped through stdin.
+    Stdin,
+    // The best chain of a Bitcoin Core node, fetched over JSON-RPC.
+    Rpc(RpcEndpoint),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockInput {
+    File(PathBuf),
+    Stdin,
+    // The blocks of the node's best chain from the genesis block up to `tip_height`. The height is
+    // fixed when the source is resolved, and the parser fetches the blocks by the hashes it found
+    // while indexing their headers, so that both of its passes see the same blocks.
+    Rpc {
+        endpoint: RpcEndpoint,
+        tip_height: u32,
+    },
+}
+
+impl BlockInput {
+    pub fn name(&self) -> String {
+        match self {
+            BlockInput::File(p) => p.display().to_string(),
+            BlockInput::Stdin => String::from("<stdin>"),
+            BlockInput::Rpc { endpoint, .. } => endpoint.url.clone(),
+        }
+    }
+
+    // Opens the input for reading, starting at `offset`. Stdin can only be read from the start, and
+    // blocks from a node are opened with `RpcBlockStream` instead.
+    pub fn open_at(&self, offset: u64) -> std::io::Result<Box<dyn Read>> {
+        Ok(match self {
+            BlockInput::File(p) => {
+                let mut file = std::fs::File::open(p)?;
+                file.seek(SeekFrom::Start(offset))?;
+                Box::new(file)
+            }
+            BlockInput::Stdin if offset > 0 => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "stdin can't be read from an offset",
+                ))
+            }
+            BlockInput::Stdin => Box::new(std::io::stdin()),
+            BlockInput::Rpc { .. } => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "blocks from a node can't be read as a file",
+                ))
+            }
+        })
+    }
+
+    // The name a checkpoint records the progress in this input under. Only block files can be
+    // resumed, and they are identified by their file name, so that a checkpoint stays valid when the
+    // blocks directory is moved.
+    pub fn checkpoint_key(&self) -> Option<String> {
+        match self {
+            BlockInput::File(p) => p.file_name().map(|n| n.to_string_lossy().into_owned()),
+            BlockInput::Stdin | BlockInput::Rpc { .. } => None,
+        }
+    }
+}
+
+impl BlockSource {
+    // Resolves the source into the ordered list of inputs to parse. Missing directories, missing
+    // files and patterns that match nothing are reported here, before any parsing starts.
+    pub fn inputs(&self) -> anyhow::Result<Vec<BlockInput>> {
+        let files = match self {
+            BlockSource::Stdin => return Ok(vec![BlockInput::Stdin]),
+            BlockSource::Rpc(endpoint) => {
+                let tip_height =
+                    RpcClient::connect(endpoint)?
+                        .block_count()
+                        .with_context(|| {
+                            format!("failed to get the block count from {}", endpoint.url)
+                        })?;
+                return Ok(vec![BlockInput::Rpc {
+                    endpoint: endpoint.clone(),
+                    tip_height,
+                }]);
+            }
+            BlockSource::Directory { path, start, end } => discover_blk_files(path, *start, *end)?,
+            BlockSource::Files(files) => {
+                for f in files.iter() {
+                    if !f.is_file() {
+                        bail!("block file {} does not exist", f.display());
+                    }
+                }
+                files.clone()
+            }
+            BlockSource::Glob(pattern) => {
+                let mut files = Vec::new();
+                for entry in glob::glob(pattern)
+                    .with_context(|| format!("invalid block file pattern {}", pattern))?
+                {
+                    let path = entry.with_context(|| format!("failed to expand {}", pattern))?;
+                    if path.is_file() {
+                        files.push(path);
+                    }
+                }
+                if files.is_empty() {
+                    bail!("block file pattern {} did not match any files", pattern);
+                }
+                files.sort_by_key(|p| (blk_file_index(p), p.clone()));
+                files
+            }
+        };
+
+        Ok(files.into_iter().map(BlockInput::File).collect())
+    }
+
+    // The directory the block files live in, which is where Bitcoin Core keeps auxiliary files such
+    // as `xor.dat`. For a file list or glob, this is the directory of the first file.
+    pub fn blocks_dir(&self) -> anyhow::Result<Option<PathBuf>> {
+        Ok(match self {
+            BlockSource::Directory { path, .. } => Some(path.clone()),
+            BlockSource::Stdin | BlockSource::Rpc(_) => None,
+            BlockSource::Files(_) | BlockSource::Glob(_) => match self.inputs()?.first() {
+                Some(BlockInput::File(p)) => p.parent().map(Path::to_path_buf),
+                _ => None,
+            },
+        })
+    }
+}
+
+// Returns N for a file named `blkN.dat`.
+pub fn blk_file_index(path: &Path) -> Option<u32> {
+    let name = path.file_name()?.to_str()?;
+    name.strip_prefix("blk")?.strip_suffix(".dat")?.parse().ok()
+}
+
+fn discover_blk_files(dir: &Path, start: u32, end: Option<u32>) -> anyhow::Result<Vec<PathBuf>> {
+    let entries = std::fs::read_dir(dir)
+        .with_context(|| format!("failed to read blocks directory {}", dir.display()))?;
+
+    let mut files: Vec<(u32, PathBuf)> = Vec::new();
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("failed to read blocks directory {}", dir.display()))?
+            .path();
+        if let Some(index) = blk_file_index(&path) {
+            if index >= start && end.is_none_or(|e| index < e) {
+                files.push((index, path));
+            }
+        }
+    }
+    files.sort_unstable();
+
+    if files.is_empty() {
+        bail!(
+            "no blk*.dat files with index {} or higher found in {}",
+            start,
+            dir.display()
+        );
+    }
+
+    // Bitcoin Core numbers its block files consecutively, so a gap means a file is missing.
+    let last = match end {
+        Some(e) => e,
+        None => files.last().unwrap().0 + 1,
+    };
+    let mut found = files.iter().map(|(i, _)| *i).peekable();
+    for expected in start..last {
+        if found.next_if_eq(&expected).is_none() {
+            bail!(
+                "block file blk{:05}.dat is missing from {}",
+                expected,
+                dir.display()
+            );
+        }
+    }
+
+    Ok(files.into_iter().map(|(_, p)| p).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Creates a directory with empty files named `names`.
+    fn blocks_dir(names: &[&str]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for n in names {
+            std::fs::write(dir.path().join(n), []).unwrap();
+        }
+        dir
+    }
+
+    fn directory(dir: &tempfile::TempDir, start: u32, end: Option<u32>) -> BlockSource {
+        BlockSource::Directory {
+            path: dir.path().to_path_buf(),
+            start,
+            end,
+        }
+    }
+
+    fn file_names(inputs: Vec<BlockInput>) -> Vec<String> {
+        inputs.iter().map(|i| i.checkpoint_key().unwrap()).collect()
+    }
+
+    #[test]
+    fn finds_the_blk_files_of_a_directory_in_order() {
+        let dir = blocks_dir(&[
+            "blk00002.dat",
+            "rev00000.dat",
+            "blk00000.dat",
+            "xor.dat",
+            "blk00001.dat",
+        ]);
+        let inputs = directory(&dir, 0, None).inputs().unwrap();
+        assert_eq!(
+            file_names(inputs),
+            ["blk00000.dat", "blk00001.dat", "blk00002.dat"]
+        );
+    }
+
+    #[test]
+    fn only_uses_the_files_in_the_range() {
+        let dir = blocks_dir(&[
+            "blk00000.dat",
+            "blk00001.dat",
+            "blk00002.dat",
+            "blk00003.dat",
+        ]);
+        let inputs = directory(&dir, 1, Some(3)).inputs().unwrap();
+        assert_eq!(file_names(inputs), ["blk00001.dat", "blk00002.dat"]);
+    }
+
+    #[test]
+    fn reports_a_gap_in_the_files() {
+        let dir = blocks_dir(&["blk00000.dat", "blk00002.dat"]);
+        let e = directory(&dir, 0, None).inputs().unwrap_err();
+        assert!(format!("{:#}", e).contains("blk00001.dat is missing"));
+    }
+
+    #[test]
+    fn reports_files_missing_at_the_end_of_the_range() {
+        let dir = blocks_dir(&["blk00000.dat", "blk00001.dat"]);
+        let e = directory(&dir, 0, Some(3)).inputs().unwrap_err();
+        assert!(format!("{:#}", e).contains("blk00002.dat is missing"));
+    }
+
+    #[test]
+    fn reports_a_directory_without_blk_files() {
+        let dir = blocks_dir(&["blk00000.dat", "rev00001.dat"]);
+        let e = directory(&dir, 1, None).inputs().unwrap_err();
+        assert!(format!("{:#}", e).contains("no blk*.dat files with index 1 or higher"));
+
+        let missing = dir.path().join("missing");
+        let e = BlockSource::Directory {
+            path: missing,
+            start: 0,
+            end: None,
+        }
+        .inputs()
+        .unwrap_err();
+        assert!(format!("{:#}", e).contains("failed to read blocks directory"));
+    }
+
+    #[test]
+    fn reports_missing_files_in_a_file_list() {
+        let dir = blocks_dir(&["blk00000.dat"]);
+        let files = vec![
+            dir.path().join("blk00000.dat"),
+            dir.path().join("blk00001.dat"),
+        ];
+        let e = BlockSource::Files(files).inputs().unwrap_err();
+        assert!(format!("{:#}", e).contains("blk00001.dat does not exist"));
+    }
+
+    #[test]
+    fn orders_glob_matches_by_file_index() {
+        let dir = blocks_dir(&["blk10000.dat", "blk9999.dat", "blk00001.dat"]);
+        let pattern = format!("{}/blk*.dat", dir.path().display());
+        let source = BlockSource::Glob(pattern);
+        assert_eq!(
+            file_names(source.inputs().unwrap()),
+            ["blk00001.dat", "blk9999.dat", "blk10000.dat"]
+        );
+        assert_eq!(source.blocks_dir().unwrap().unwrap(), dir.path());
+
+        let pattern = format!("{}/rev*.dat", dir.path().display());
+        let e = BlockSource::Glob(pattern).inputs().unwrap_err();
+        assert!(format!("{:#}", e).contains("did not match any files"));
+    }
+}
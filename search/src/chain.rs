@@ -0,0 +1,500 @@
+use crate::transaction::BlockHash;
+use primitive_types::U256;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+// Height given to blocks that are not on the main chain, or whose height cannot be determined.
+pub const UNKNOWN_HEIGHT: u32 = u32::MAX;
+
+// The target is adjusted every this many blocks, to aim for one block every ten minutes.
+const RETARGET_INTERVAL: u32 = 2016;
+const TARGET_TIMESPAN: i64 = 14 * 24 * 60 * 60;
+const TARGET_SPACING: u32 = 10 * 60;
+
+// How far a block's timestamp may be ahead of the current time.
+const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+
+// Number of previous blocks whose median timestamp a block's timestamp must exceed.
+const MEDIAN_TIME_SPAN: usize = 11;
+
+// The proof-of-work rules that header validation checks blocks against.
+#[derive(Debug, Clone, Copy)]
+pub struct PowParams {
+    // The highest (easiest) allowed target, in compact form.
+    pub pow_limit: u32,
+    // Whether the target is recomputed every `RETARGET_INTERVAL` blocks. Regtest keeps it constant.
+    pub retargeting: bool,
+    // Whether a block may use `pow_limit` if it comes more than twice the target spacing after its
+    // parent, as on testnet.
+    pub allow_min_difficulty_blocks: bool,
+}
+
+impl PowParams {
+    pub const MAINNET: PowParams = PowParams {
+        pow_limit: 0x1d00ffff,
+        retargeting: true,
+        allow_min_difficulty_blocks: false,
+    };
+    pub const TESTNET: PowParams = PowParams {
+        pow_limit: 0x1d00ffff,
+        retargeting: true,
+        allow_min_difficulty_blocks: true,
+    };
+    pub const SIGNET: PowParams = PowParams {
+        pow_limit: 0x1e0377ae,
+        retargeting: true,
+        allow_min_difficulty_blocks: false,
+    };
+    pub const REGTEST: PowParams = PowParams {
+        pow_limit: 0x207fffff,
+        retargeting: false,
+        allow_min_difficulty_blocks: true,
+    };
+}
+
+// A block header that failed validation. Its descendants are excluded along with it.
+#[derive(Debug, Clone)]
+pub struct InvalidHeader {
+    pub block: BlockHash,
+    pub height: u32,
+    pub reason: String,
+}
+
+// The parts of a block header that the chain is built from, in a form that can be saved and loaded.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct StoredHeader {
+    pub id: BlockHash,
+    pub prev: BlockHash,
+    pub bits: u32,
+    pub time: u32,
+}
+
+struct HeaderEntry {
+    prev: BlockHash,
+    bits: u32,
+    time: u32,
+    work: U256,
+    // Order in which the header was first seen. Used to break ties between equal-work tips the same
+    // way Bitcoin Core does, by preferring the one it saw first.
+    seq: usize,
+}
+
+// The block header tree as found in a set of blk files. Blk files store blocks roughly in the order
+// they were downloaded, which is not the chain order and includes stale blocks, so heights can only
+// be assigned once all headers have been collected.
+#[derive(Default)]
+pub struct HeaderChain {
+    headers: HashMap<BlockHash, HeaderEntry>,
+}
+
+impl HeaderChain {
+    pub fn new() -> HeaderChain {
+        HeaderChain::default()
+    }
+
+    pub fn insert(&mut self, id: BlockHash, prev: BlockHash, bits: u32, time: u32) {
+        let seq = self.headers.len();
+        self.headers.entry(id).or_insert(HeaderEntry {
+            prev,
+            bits,
+            time,
+            work: block_work(bits),
+            seq,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    // All headers, in the order they were inserted, so that inserting them again gives the same
+    // chain.
+    pub fn stored_headers(&self) -> Vec<StoredHeader> {
+        let mut result: Vec<(usize, StoredHeader)> = self
+            .headers
+            .iter()
+            .map(|(id, h)| {
+                (
+                    h.seq,
+                    StoredHeader {
+                        id: *id,
+                        prev: h.prev,
+                        bits: h.bits,
+                        time: h.time,
+                    },
+                )
+            })
+            .collect();
+        result.sort_unstable_by_key(|(seq, _)| *seq);
+        result.into_iter().map(|(_, h)| h).collect()
+    }
+
+    // Picks the tip with the most cumulative work among the blocks that connect to the `genesis`
+    // block, and returns the chain leading up to it. Blocks on other branches, and blocks whose
+    // ancestors are missing, are not part of the result. If `pow` is given, headers are validated
+    // against it, and invalid headers and their descendants can't be part of the main chain.
+    pub fn main_chain(&self, genesis: &BlockHash, pow: Option<&PowParams>) -> MainChain {
+        if !self.headers.contains_key(genesis) {
+            return MainChain::default();
+        }
+        let genesis = *genesis;
+
+        let mut children: HashMap<BlockHash, Vec<BlockHash>> = HashMap::new();
+        for (id, h) in self.headers.iter() {
+            children.entry(h.prev).or_default().push(*id);
+        }
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let check = |id: &BlockHash, height: u32| {
+            let pow = pow?;
+            let reason = self.check_header(id, height, pow, now).err()?;
+            Some(InvalidHeader {
+                block: *id,
+                height,
+                reason,
+            })
+        };
+        if let Some(h) = check(&genesis, 0) {
+            return MainChain {
+                excluded: self.descendants(&children, std::iter::once(h.block)),
+                invalid: vec![h],
+                ..MainChain::default()
+            };
+        }
+
+        let mut invalid: Vec<InvalidHeader> = Vec::new();
+        // Walk the whole tree below the genesis block to find the most-work tip. This is iterative
+        // because the main chain is far too deep to recurse over.
+        let mut best = (genesis, 0u32, self.headers[&genesis].work);
+        let mut stack = vec![best];
+        while let Some((id, height, chain_work)) = stack.pop() {
+            let (best_id, _, best_work) = best;
+            if chain_work > best_work
+                || (chain_work == best_work && self.headers[&id].seq < self.headers[&best_id].seq)
+            {
+                best = (id, height, chain_work);
+            }
+            for c in children.get(&id).into_iter().flatten() {
+                match check(c, height + 1) {
+                    None => stack.push((*c, height + 1, chain_work + self.headers[c].work)),
+                    Some(h) => invalid.push(h),
+                }
+            }
+        }
+
+        let (tip, tip_height, _) = best;
+        let mut heights = HashMap::with_capacity(tip_height as usize + 1);
+        let mut id = tip;
+        for height in (0..=tip_height).rev() {
+            heights.insert(id, height);
+            id = self.headers[&id].prev;
+        }
+
+        MainChain {
+            heights,
+            tip: Some(tip),
+            excluded: self.descendants(&children, invalid.iter().map(|h| h.block)),
+            invalid,
+        }
+    }
+
+    // Checks the header `id` at `height` against the proof-of-work and timestamp rules, given that
+    // all its ancestors are valid.
+    fn check_header(
+        &self,
+        id: &BlockHash,
+        height: u32,
+        pow: &PowParams,
+        now: u64,
+    ) -> Result<(), String> {
+        let header = &self.headers[id];
+
+        check_proof_of_work(id, header.bits, pow)?;
+
+        if height > 0 {
+            let expected = self.next_bits(&header.prev, height, header.time, pow);
+            if header.bits != expected {
+                return Err(format!(
+                    "nBits is {:08x}, but should be {:08x}",
+                    header.bits, expected
+                ));
+            }
+            let median = self.median_time_past(&header.prev);
+            if header.time <= median {
+                return Err(format!(
+                    "timestamp {} is not after the median time of the previous blocks, {}",
+                    header.time, median
+                ));
+            }
+        }
+
+        if u64::from(header.time) > now + MAX_FUTURE_BLOCK_TIME {
+            return Err(format!(
+                "timestamp {} is more than two hours in the future",
+                header.time
+            ));
+        }
+        Ok(())
+    }
+
+    // The nBits a block at `height` with timestamp `time` whose parent is `prev` must have.
+    fn next_bits(&self, prev: &BlockHash, height: u32, time: u32, pow: &PowParams) -> u32 {
+        let parent = &self.headers[prev];
+        if !height.is_multiple_of(RETARGET_INTERVAL) {
+            if !pow.allow_min_difficulty_blocks {
+                return parent.bits;
+            }
+            if time > parent.time.saturating_add(2 * TARGET_SPACING) {
+                return pow.pow_limit;
+            }
+            // Otherwise, the block has the same target as the last block that didn't make use of
+            // the rule above.
+            let mut id = *prev;
+            let mut h = height - 1;
+            while h > 0
+                && !h.is_multiple_of(RETARGET_INTERVAL)
+                && self.headers[&id].bits == pow.pow_limit
+            {
+                id = self.headers[&id].prev;
+                h -= 1;
+            }
+            return self.headers[&id].bits;
+        }
+        if !pow.retargeting {
+            return parent.bits;
+        }
+
+        // The time it took to mine the last interval, as measured from its first to its last
+        // block, limited to a factor of 4 from the target timespan.
+        let mut first = *prev;
+        for _ in 0..RETARGET_INTERVAL - 1 {
+            first = self.headers[&first].prev;
+        }
+        let timespan = (i64::from(parent.time) - i64::from(self.headers[&first].time))
+            .clamp(TARGET_TIMESPAN / 4, TARGET_TIMESPAN * 4);
+
+        let target =
+            target_from_bits(parent.bits) * U256::from(timespan) / U256::from(TARGET_TIMESPAN);
+        bits_from_target(target.min(target_from_bits(pow.pow_limit)))
+    }
+
+    // The median timestamp of the block `id` and the ones before it.
+    fn median_time_past(&self, id: &BlockHash) -> u32 {
+        let mut times = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut id = *id;
+        while let Some(header) = self.headers.get(&id) {
+            times.push(header.time);
+            if times.len() == MEDIAN_TIME_SPAN {
+                break;
+            }
+            id = header.prev;
+        }
+        times.sort_unstable();
+        times[times.len() / 2]
+    }
+
+    // All blocks in the subtrees below `roots`, including the roots themselves.
+    fn descendants(
+        &self,
+        children: &HashMap<BlockHash, Vec<BlockHash>>,
+        roots: impl Iterator<Item = BlockHash>,
+    ) -> HashSet<BlockHash> {
+        let mut result = HashSet::new();
+        let mut stack: Vec<BlockHash> = roots.collect();
+        while let Some(id) = stack.pop() {
+            if result.insert(id) {
+                stack.extend(children.get(&id).into_iter().flatten());
+            }
+        }
+        result
+    }
+}
+
+// The blocks on the most-work chain, by height.
+#[derive(Default)]
+pub struct MainChain {
+    heights: HashMap<BlockHash, u32>,
+    tip: Option<BlockHash>,
+    // Headers that failed validation, and all blocks that are excluded because of them.
+    invalid: Vec<InvalidHeader>,
+    excluded: HashSet<BlockHash>,
+}
+
+impl MainChain {
+    // Returns the height of `id` if it is on the main chain.
+    pub fn height(&self, id: &BlockHash) -> Option<u32> {
+        self.heights.get(id).copied()
+    }
+
+    pub fn tip(&self) -> Option<BlockHash> {
+        self.tip
+    }
+
+    pub fn tip_height(&self) -> Option<u32> {
+        self.tip.and_then(|t| self.height(&t))
+    }
+
+    // Whether a main chain could be determined at all, i.e. whether the genesis block was found.
+    pub fn is_known(&self) -> bool {
+        self.tip.is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.heights.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heights.is_empty()
+    }
+
+    pub fn invalid_headers(&self) -> &[InvalidHeader] {
+        &self.invalid
+    }
+
+    // Whether `id` failed header validation or descends from a block that did.
+    pub fn is_excluded(&self, id: &BlockHash) -> bool {
+        self.excluded.contains(id)
+    }
+}
+
+// Checks that the hash `id` of a block header meets the target in its `bits`, and that the target
+// is one `pow` allows. Unlike the other checks, this only needs the header itself.
+pub fn check_proof_of_work(id: &BlockHash, bits: u32, pow: &PowParams) -> Result<(), String> {
+    let target = target_from_bits(bits);
+    if target.is_zero() || target > target_from_bits(pow.pow_limit) {
+        return Err(format!("nBits {:08x} is not a valid target", bits));
+    }
+    if U256::from_little_endian(id.as_ref()) > target {
+        return Err(format!(
+            "block hash is above the target of nBits {:08x}",
+            bits
+        ));
+    }
+    Ok(())
+}
+
+// Expands the compact `nBits` encoding from a block header into the full 256-bit target. Negative
+// and overflowing encodings are invalid and give a zero target.
+pub fn target_from_bits(bits: u32) -> U256 {
+    let exponent = bits >> 24;
+    let mantissa = bits & 0x007f_ffff;
+
+    if mantissa == 0 || bits & 0x0080_0000 != 0 {
+        return U256::zero();
+    }
+    let overflows =
+        exponent > 34 || (mantissa > 0xff && exponent > 33) || (mantissa > 0xffff && exponent > 32);
+    if overflows {
+        return U256::zero();
+    }
+
+    match exponent <= 3 {
+        true => U256::from(mantissa >> (8 * (3 - exponent))),
+        false => U256::from(mantissa) << (8 * (exponent - 3)),
+    }
+}
+
+// Encodes a target in the compact `nBits` form. This is the inverse of `target_from_bits`, up to the
+// precision the compact form can hold.
+pub fn bits_from_target(target: U256) -> u32 {
+    let mut size = target.bits().div_ceil(8) as u32;
+    let mut mantissa = match size <= 3 {
+        true => target.low_u32() << (8 * (3 - size)),
+        false => (target >> (8 * (size - 3))).low_u32(),
+    };
+    // The mantissa's top bit is a sign bit, so it has to stay clear.
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+    mantissa | (size << 24)
+}
+
+// The expected number of hashes needed to find a block with the given `nBits`, i.e.
+// 2^256 / (target + 1).
+pub fn block_work(bits: u32) -> U256 {
+    let target = target_from_bits(bits);
+    if target.is_zero() {
+        return U256::zero();
+    }
+    // 2^256 doesn't fit into a U256, but 2^256 / (target + 1) == ~target / (target + 1) + 1.
+    (!target / (target + 1)) + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A distinct block hash for each `i`.
+    fn id(i: u32) -> BlockHash {
+        let mut hash = [0xaa; 32];
+        hash[..4].copy_from_slice(&i.to_le_bytes());
+        BlockHash::new(hash)
+    }
+
+    #[test]
+    fn bits_and_targets_round_trip() {
+        // The pow limit of mainnet, block 100000, and small values that exercise the sign bit.
+        let cases = [
+            (0x1d00ffff, U256::from(0xffff) << 208),
+            (0x1b04864c, U256::from(0x04864c) << 192),
+            (0x05009234, U256::from(0x9234_0000u64)),
+            (0x04123456, U256::from(0x1234_5600u64)),
+            (0x01120000, U256::from(0x12)),
+        ];
+        for (bits, target) in cases {
+            assert_eq!(target_from_bits(bits), target, "{:08x}", bits);
+            assert_eq!(bits_from_target(target), bits, "{:08x}", bits);
+        }
+
+        // Negative and overflowing encodings.
+        assert!(target_from_bits(0x04923456).is_zero());
+        assert!(target_from_bits(0xff123456).is_zero());
+    }
+
+    #[test]
+    fn first_mainnet_retarget() {
+        // Blocks 30240 to 32255. Only the timestamps of the first and last one matter.
+        let mut chain = HeaderChain::new();
+        let mut prev = id(u32::MAX);
+        for i in 0..RETARGET_INTERVAL {
+            let time = match i {
+                0 => 1261130161,
+                i if i == RETARGET_INTERVAL - 1 => 1262152739,
+                i => 1261130161 + i * 500,
+            };
+            chain.insert(id(i), prev, 0x1d00ffff, time);
+            prev = id(i);
+        }
+        let last = id(RETARGET_INTERVAL - 1);
+
+        // Block 32256 is the first one with a higher difficulty.
+        let pow = PowParams::MAINNET;
+        assert_eq!(chain.next_bits(&last, 32256, 1262153464, &pow), 0x1d00d86a);
+        // Blocks in between keep the target of their parent.
+        assert_eq!(
+            chain.next_bits(&id(100), 30341, 1261180661, &pow),
+            0x1d00ffff
+        );
+    }
+
+    #[test]
+    fn checks_proof_of_work() {
+        let pow = PowParams::MAINNET;
+        let genesis = BlockHash::new_from_str(
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+        );
+        assert_eq!(check_proof_of_work(&genesis, 0x1d00ffff, &pow), Ok(()));
+        // A target easier than the network allows.
+        assert!(check_proof_of_work(&genesis, 0x1e00ffff, &pow).is_err());
+        // A target the hash doesn't meet.
+        assert!(check_proof_of_work(&genesis, 0x1b04864c, &pow).is_err());
+    }
+}
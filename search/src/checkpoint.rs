@@ -0,0 +1,114 @@
+use crate::chain::StoredHeader;
+use crate::network::Network;
+use crate::parser::OutputHashAndIndex;
+use crate::transaction::Input;
+use crate::utxo_store::SavedOutputs;
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// Bumped whenever the layout of `Checkpoint` changes, so that old checkpoints are rejected instead of
+// being misread.
+const CHECKPOINT_VERSION: u32 = 5;
+
+// Everything the parser needs to continue where an earlier run stopped, without parsing the same
+// blocks again: how far each block file was parsed, the block headers seen so far, and the inputs
+// and outputs that have not been matched up yet. If the unspent outputs are kept in a UTXO
+// database, the checkpoint only refers to it, and the database has to be kept along with it.
+//
+// Blocks are only ever added. If a block that was dropped as stale later becomes part of the main
+// chain, it is not parsed again.
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    version: u32,
+    pub(crate) network: Network,
+    // For each block file, by file name, the offset right after the last block that was parsed.
+    pub(crate) consumed: HashMap<String, u64>,
+    pub(crate) headers: Vec<StoredHeader>,
+    pub(crate) unmatched_inputs: HashMap<OutputHashAndIndex, Input>,
+    pub(crate) unmatched_outputs: SavedOutputs,
+}
+
+impl Checkpoint {
+    pub(crate) fn new(
+        network: Network,
+        consumed: HashMap<String, u64>,
+        headers: Vec<StoredHeader>,
+        unmatched_inputs: HashMap<OutputHashAndIndex, Input>,
+        unmatched_outputs: SavedOutputs,
+    ) -> Checkpoint {
+        Checkpoint {
+            version: CHECKPOINT_VERSION,
+            network,
+            consumed,
+            headers,
+            unmatched_inputs,
+            unmatched_outputs,
+        }
+    }
+
+    // Loads the checkpoint at `path`. Returns `None` if there is none yet.
+    pub fn load(path: &Path) -> anyhow::Result<Option<Checkpoint>> {
+        let file = match File::open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("failed to open checkpoint {}", path.display()))
+            }
+        };
+        let checkpoint: Checkpoint = bincode::deserialize_from(BufReader::new(file))
+            .with_context(|| format!("failed to read checkpoint {}", path.display()))?;
+        if checkpoint.version != CHECKPOINT_VERSION {
+            bail!(
+                "checkpoint {} has version {}, but only version {} is supported",
+                path.display(),
+                checkpoint.version,
+                CHECKPOINT_VERSION
+            );
+        }
+        Ok(Some(checkpoint))
+    }
+
+    // Saves the checkpoint to `path`. It is written to a temporary file first and then moved into
+    // place, so an interrupted save leaves the previous checkpoint intact.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(
+            File::create(&tmp_path)
+                .with_context(|| format!("failed to create {}", tmp_path.display()))?,
+        );
+        bincode::serialize_into(&mut writer, self)
+            .with_context(|| format!("failed to write checkpoint {}", tmp_path.display()))?;
+        writer
+            .flush()
+            .with_context(|| format!("failed to write checkpoint {}", tmp_path.display()))?;
+        drop(writer);
+        std::fs::rename(&tmp_path, path)
+            .with_context(|| format!("failed to move checkpoint into place at {}", path.display()))
+    }
+
+    // The offset up to which the block file with the given name has been parsed.
+    pub fn consumed(&self, file_name: &str) -> u64 {
+        self.consumed.get(file_name).copied().unwrap_or(0)
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn header_count(&self) -> usize {
+        self.headers.len()
+    }
+
+    // The database holding the unspent outputs, if they are not stored in the checkpoint itself.
+    pub fn utxo_db(&self) -> Option<&PathBuf> {
+        match &self.unmatched_outputs {
+            SavedOutputs::InMemory(_) => None,
+            SavedOutputs::OnDisk(path) => Some(path),
+        }
+    }
+}
@@ -1,65 +1,167 @@
 use crate::{
+    network::Network,
     output_writer::OutputWriter,
-    transaction::{Block, InputOutputPair, Transaction, TxHash},
+    transaction::{Block, BlockHash, InputOutputPair, Transaction, TxHash},
 };
+use anyhow::{bail, Context};
 use bincode::serialize_into;
 use cached::proc_macro::once;
 use itertools::Itertools;
-use serde::de::DeserializeOwned;
-use std::fs::File;
-use std::io::BufWriter;
+use serde::{de::DeserializeOwned, Serialize};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
 use std::sync::Arc;
 
-pub const TRANSACTIONS_DBFILE_UNSORTED: &'static str = "transactions.customdb";
-pub const BLOCKS_DBFILE_UNSORTED: &'static str = "blocks.customdb";
-pub const IOPAIRS_DBFILE_UNSORTED: &'static str = "iopairs.customdb";
+pub const TRANSACTIONS_DBFILE_UNSORTED: &str = "transactions.customdb";
+pub const BLOCKS_DBFILE_UNSORTED: &str = "blocks.customdb";
+pub const IOPAIRS_DBFILE_UNSORTED: &str = "iopairs.customdb";
+// Blocks that were written out but later left the main chain in a reorg.
+pub const RETRACTED_BLOCKS_DBFILE_UNSORTED: &str = "retracted-blocks.customdb";
 
-pub const TRANSACTIONS_DBFILE_SORTED: &'static str = "sorted-transactions.customdb";
-pub const BLOCKS_DBFILE_SORTED: &'static str = "sorted-blocks.customdb";
-pub const IOPAIRS_DBFILE_SORTED_SRC: &'static str = "sorted-src-iopairs.customdb";
-pub const IOPAIRS_DBFILE_SORTED_DEST: &'static str = "sorted-dest-iopairs.customdb";
+pub const TRANSACTIONS_DBFILE_SORTED: &str = "sorted-transactions.customdb";
+pub const BLOCKS_DBFILE_SORTED: &str = "sorted-blocks.customdb";
+pub const IOPAIRS_DBFILE_SORTED_SRC: &str = "sorted-src-iopairs.customdb";
+pub const IOPAIRS_DBFILE_SORTED_DEST: &str = "sorted-dest-iopairs.customdb";
 
 pub struct CustomWriter {
     tx_writer: BufWriter<std::fs::File>,
     block_writer: BufWriter<std::fs::File>,
     iopair_writer: BufWriter<std::fs::File>,
+    retracted_block_writer: BufWriter<std::fs::File>,
 }
 
 impl CustomWriter {
-    pub fn new() -> CustomWriter {
-        CustomWriter::new_with_files(
-            TRANSACTIONS_DBFILE_UNSORTED,
-            BLOCKS_DBFILE_UNSORTED,
-            IOPAIRS_DBFILE_UNSORTED,
-        )
+    pub fn new(network: Network) -> anyhow::Result<CustomWriter> {
+        CustomWriter::in_dir(Path::new(""), network, false)
     }
 
-    fn new_with_files(tx_dbfile: &str, blocks_dbfile: &str, iopairs_dbfile: &str) -> CustomWriter {
-        CustomWriter {
-            tx_writer: BufWriter::new(File::create(tx_dbfile).unwrap()),
-            block_writer: BufWriter::new(File::create(blocks_dbfile).unwrap()),
-            iopair_writer: BufWriter::new(File::create(iopairs_dbfile).unwrap()),
-        }
+    // Appends to the files written by an earlier run, which the parser resumes from.
+    pub fn resume(network: Network) -> anyhow::Result<CustomWriter> {
+        CustomWriter::in_dir(Path::new(""), network, true)
+    }
+
+    // Writes the files in `dir`, adding to the files of an earlier run if `append` is set.
+    fn in_dir(dir: &Path, network: Network, append: bool) -> anyhow::Result<CustomWriter> {
+        let open = |f: &str| match append {
+            true => append_with_network(&dir.join(f), network),
+            false => create_with_network(&dir.join(f), network),
+        };
+        Ok(CustomWriter {
+            tx_writer: open(TRANSACTIONS_DBFILE_UNSORTED)?,
+            block_writer: open(BLOCKS_DBFILE_UNSORTED)?,
+            iopair_writer: open(IOPAIRS_DBFILE_UNSORTED)?,
+            retracted_block_writer: open(RETRACTED_BLOCKS_DBFILE_UNSORTED)?,
+        })
     }
 }
 
 impl OutputWriter for CustomWriter {
-    fn insert_tx(&mut self, tx: Transaction) {
-        serialize_into(&mut self.tx_writer, &tx).unwrap();
+    fn insert_tx(&mut self, tx: Transaction) -> anyhow::Result<()> {
+        serialize_into(&mut self.tx_writer, &tx).context("failed to write transaction")
+    }
+
+    fn insert_block(&mut self, b: Block) -> anyhow::Result<()> {
+        serialize_into(&mut self.block_writer, &b).context("failed to write block")
     }
 
-    fn insert_block(&mut self, b: Block) {
-        serialize_into(&mut self.block_writer, &b).unwrap();
+    fn insert_iopair(&mut self, iopair: InputOutputPair) -> anyhow::Result<()> {
+        serialize_into(&mut self.iopair_writer, &iopair).context("failed to write iopair")
     }
 
-    fn insert_iopair(&mut self, iopair: InputOutputPair) {
-        serialize_into(&mut self.iopair_writer, &iopair).unwrap();
+    // The files are append-only, so the block is only recorded as retracted here. Its records are
+    // left out when the data is sorted.
+    fn retract_block(&mut self, b: Block) -> anyhow::Result<()> {
+        serialize_into(&mut self.retracted_block_writer, &b.id)
+            .context("failed to write retracted block")
     }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.tx_writer
+            .flush()
+            .and_then(|_| self.block_writer.flush())
+            .and_then(|_| self.iopair_writer.flush())
+            .and_then(|_| self.retracted_block_writer.flush())
+            .context("failed to flush the custom format files")
+    }
+}
+
+// Every custom-format file starts with the network its data is from, followed by the records. This
+// keeps data from different networks from being mixed up.
+fn create_with_network(custom_db_file: &Path, network: Network) -> anyhow::Result<BufWriter<File>> {
+    let file = File::create(custom_db_file)
+        .with_context(|| format!("failed to create {}", custom_db_file.display()))?;
+    let mut writer = BufWriter::new(file);
+    serialize_into(&mut writer, &network)
+        .with_context(|| format!("failed to write {}", custom_db_file.display()))?;
+    Ok(writer)
 }
 
-pub fn read_custom_format<T: DeserializeOwned>(custom_db_file: &str) -> Vec<T> {
+// Opens a custom-format file to add records to it, creating it if it doesn't exist yet. An existing
+// file has to hold data from the same network.
+fn append_with_network(custom_db_file: &Path, network: Network) -> anyhow::Result<BufWriter<File>> {
+    if custom_db_file.metadata().is_ok_and(|m| m.len() > 0) {
+        let existing = read_custom_format_network(custom_db_file)?;
+        if existing != network {
+            bail!(
+                "{} holds {} data, not {}",
+                custom_db_file.display(),
+                existing,
+                network
+            );
+        }
+    }
+    let file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(custom_db_file)
+        .with_context(|| format!("failed to open {}", custom_db_file.display()))?;
+    let is_new = file
+        .metadata()
+        .with_context(|| format!("failed to open {}", custom_db_file.display()))?
+        .len()
+        == 0;
+    let mut writer = BufWriter::new(file);
+    if is_new {
+        serialize_into(&mut writer, &network)
+            .with_context(|| format!("failed to write {}", custom_db_file.display()))?;
+    }
+    Ok(writer)
+}
+
+// Writes `records` to the files of the workers in turn, so that each worker gets every
+// `writers.len()`th record, and flushes the files.
+fn write_to_workers<T: Serialize>(
+    writers: &mut [BufWriter<File>],
+    custom_db_file: &str,
+    records: impl Iterator<Item = T>,
+) -> anyhow::Result<()> {
+    let n = writers.len();
+    for (i, r) in records.enumerate() {
+        serialize_into(&mut writers[i % n], &r)
+            .with_context(|| format!("failed to write {}-{}", i % n, custom_db_file))?;
+    }
+    for (i, w) in writers.iter_mut().enumerate() {
+        w.flush()
+            .with_context(|| format!("failed to write {}-{}", i, custom_db_file))?;
+    }
+    Ok(())
+}
+
+// Reads only the network that the data in a custom-format file is from.
+pub fn read_custom_format_network(custom_db_file: &Path) -> anyhow::Result<Network> {
+    let file = File::open(custom_db_file)
+        .with_context(|| format!("failed to open {}", custom_db_file.display()))?;
+    bincode::deserialize_from(BufReader::new(file))
+        .with_context(|| format!("failed to read the network of {}", custom_db_file.display()))
+}
+
+pub fn read_custom_format<T: DeserializeOwned>(custom_db_file: &Path) -> (Network, Vec<T>) {
     let data = std::fs::read(custom_db_file).unwrap();
-    let mut cursor = &data.as_slice()[..];
+    let mut cursor = data.as_slice();
+    let network: Network = bincode::deserialize_from(&mut cursor).unwrap();
     let mut vec: Vec<T> = Vec::new();
 
     loop {
@@ -77,29 +179,126 @@ pub fn read_custom_format<T: DeserializeOwned>(custom_db_file: &str) -> Vec<T> {
         };
     }
 
-    vec
+    (network, vec)
 }
 
+// Checks that all files hold data from the same network, and returns it.
+fn same_network(files: &[(&Path, Network)]) -> anyhow::Result<Network> {
+    let (first_file, network) = files[0];
+    for (file, n) in files.iter() {
+        if *n != network {
+            bail!(
+                "{} holds {} data, but {} holds {} data",
+                first_file.display(),
+                network,
+                file.display(),
+                n
+            );
+        }
+    }
+    Ok(network)
+}
+
+// The data written by the parser, before sorting: the network it is from, transactions, blocks and
+// iopairs.
+pub type UnsortedData = (Network, Vec<Transaction>, Vec<Block>, Vec<InputOutputPair>);
+
 pub fn read_custom_formats(
-    tx_dbfile: &str,
-    blocks_dbfile: &str,
-    iopairs_dbfile: &str,
-) -> (Vec<Transaction>, Vec<Block>, Vec<InputOutputPair>) {
-    let txs: Vec<Transaction> = read_custom_format(tx_dbfile);
-    let blocks: Vec<Block> = read_custom_format(blocks_dbfile);
-    let iopairs: Vec<InputOutputPair> = read_custom_format(iopairs_dbfile);
+    tx_dbfile: &Path,
+    blocks_dbfile: &Path,
+    iopairs_dbfile: &Path,
+) -> anyhow::Result<UnsortedData> {
+    let (tx_network, txs): (Network, Vec<Transaction>) = read_custom_format(tx_dbfile);
+    let (block_network, blocks): (Network, Vec<Block>) = read_custom_format(blocks_dbfile);
+    let (iopair_network, iopairs): (Network, Vec<InputOutputPair>) =
+        read_custom_format(iopairs_dbfile);
+    let network = same_network(&[
+        (tx_dbfile, tx_network),
+        (blocks_dbfile, block_network),
+        (iopairs_dbfile, iopair_network),
+    ])?;
+
+    Ok((network, txs, blocks, iopairs))
+}
 
-    (txs, blocks, iopairs)
+// The blocks that were retracted for good. A block that came back to the main chain after it was
+// retracted has been written once more than it was retracted. Data written before retractions
+// were recorded has no file for them.
+fn read_retracted_blocks(
+    retracted_blocks_dbfile: &Path,
+    network: Network,
+    blocks: &[Block],
+) -> anyhow::Result<HashSet<BlockHash>> {
+    if !retracted_blocks_dbfile.exists() {
+        return Ok(HashSet::new());
+    }
+    let (retracted_network, retracted): (Network, Vec<BlockHash>) =
+        read_custom_format(retracted_blocks_dbfile);
+    same_network(&[
+        (
+            &retracted_blocks_dbfile.with_file_name(BLOCKS_DBFILE_UNSORTED),
+            network,
+        ),
+        (retracted_blocks_dbfile, retracted_network),
+    ])?;
+
+    let mut balance: HashMap<BlockHash, i64> = HashMap::new();
+    for b in blocks.iter() {
+        *balance.entry(b.id).or_default() += 1;
+    }
+    for id in retracted.iter() {
+        *balance.entry(*id).or_default() -= 1;
+    }
+    Ok(balance
+        .into_iter()
+        .filter(|(_, n)| *n <= 0)
+        .map(|(id, _)| id)
+        .collect())
 }
 
-pub fn sort_and_write_data(for_num_workers: usize) {
+// Leaves out the records of retracted blocks, and turns iopairs whose inputs were in one of them
+// back into unspent outputs.
+fn apply_retractions(
+    retracted: &HashSet<BlockHash>,
+    txs: &mut Vec<Transaction>,
+    blocks: &mut Vec<Block>,
+    iopairs: &mut Vec<InputOutputPair>,
+) {
+    txs.retain(|t| !retracted.contains(&t.block));
+    blocks.retain(|b| !retracted.contains(&b.id));
+    iopairs.retain(|p| !retracted.contains(&p.source.src_block));
+    for p in iopairs.iter_mut() {
+        if p.dest.is_some_and(|d| retracted.contains(&d.dest_block)) {
+            p.dest = None;
+        }
+    }
+}
+
+pub fn sort_and_write_data(for_num_workers: usize) -> anyhow::Result<()> {
+    sort_and_write_data_in(Path::new(""), for_num_workers)
+}
+
+// Sorts the files written by the parser in `dir`, and writes the sorted files next to them.
+fn sort_and_write_data_in(dir: &Path, for_num_workers: usize) -> anyhow::Result<()> {
     assert!(for_num_workers >= 1);
 
-    let (mut txs, mut blocks, mut iopairs) = read_custom_formats(
-        TRANSACTIONS_DBFILE_UNSORTED,
-        BLOCKS_DBFILE_UNSORTED,
-        IOPAIRS_DBFILE_UNSORTED,
-    );
+    let (network, mut txs, mut blocks, mut iopairs) = read_custom_formats(
+        &dir.join(TRANSACTIONS_DBFILE_UNSORTED),
+        &dir.join(BLOCKS_DBFILE_UNSORTED),
+        &dir.join(IOPAIRS_DBFILE_UNSORTED),
+    )?;
+    let retracted = read_retracted_blocks(
+        &dir.join(RETRACTED_BLOCKS_DBFILE_UNSORTED),
+        network,
+        &blocks,
+    )?;
+    if !retracted.is_empty() {
+        apply_retractions(&retracted, &mut txs, &mut blocks, &mut iopairs);
+        println!(
+            "Left out {} blocks that were retracted in reorgs",
+            retracted.len()
+        );
+    }
 
     let mut txs_out: Vec<BufWriter<std::fs::File>> = Vec::with_capacity(for_num_workers);
     let mut blocks_out: Vec<BufWriter<std::fs::File>> = Vec::with_capacity(for_num_workers);
@@ -108,82 +307,482 @@ pub fn sort_and_write_data(for_num_workers: usize) {
         Vec::with_capacity(for_num_workers);
 
     for i in 0..for_num_workers {
-        txs_out.push(BufWriter::new(
-            File::create(format!("{}-{}", i, TRANSACTIONS_DBFILE_SORTED)).unwrap(),
-        ));
-        blocks_out.push(BufWriter::new(
-            File::create(format!("{}-{}", i, BLOCKS_DBFILE_SORTED)).unwrap(),
-        ));
-        iopairs_by_src_out.push(BufWriter::new(
-            File::create(format!("{}-{}", i, IOPAIRS_DBFILE_SORTED_SRC)).unwrap(),
-        ));
-        iopairs_by_dest_out.push(BufWriter::new(
-            File::create(format!("{}-{}", i, IOPAIRS_DBFILE_SORTED_DEST)).unwrap(),
-        ));
+        txs_out.push(create_with_network(
+            &dir.join(format!("{}-{}", i, TRANSACTIONS_DBFILE_SORTED)),
+            network,
+        )?);
+        blocks_out.push(create_with_network(
+            &dir.join(format!("{}-{}", i, BLOCKS_DBFILE_SORTED)),
+            network,
+        )?);
+        iopairs_by_src_out.push(create_with_network(
+            &dir.join(format!("{}-{}", i, IOPAIRS_DBFILE_SORTED_SRC)),
+            network,
+        )?);
+        iopairs_by_dest_out.push(create_with_network(
+            &dir.join(format!("{}-{}", i, IOPAIRS_DBFILE_SORTED_DEST)),
+            network,
+        )?);
     }
 
     // We use unstable sorts because they are in-place and faster than stable sorts in rust. We
     // also use up vectors explicitly (with into_iter) to minimize memory usage, especially when we are sorting
     // larger data.
 
-    txs.sort_unstable_by_key(|k| k.id);
+    // Blocks that were retracted and then came back to the main chain were written twice.
+    txs.sort_unstable_by_key(|k| (k.id, k.block));
+    txs.dedup_by_key(|k| (k.id, k.block));
     println!("Sorted transactions");
 
-    for (i, t) in txs.into_iter().enumerate() {
-        serialize_into(&mut txs_out[i % for_num_workers], &t).unwrap();
-    }
+    write_to_workers(&mut txs_out, TRANSACTIONS_DBFILE_SORTED, txs.iter())?;
     println!("Wrote sorted transactions");
 
+    drop(txs);
+
     blocks.sort_unstable_by_key(|k| k.id);
+    blocks.dedup_by_key(|k| k.id);
     println!("Sorted blocks");
-    for (i, b) in blocks.into_iter().enumerate() {
-        serialize_into(&mut blocks_out[i % for_num_workers], &b).unwrap();
-    }
+    write_to_workers(&mut blocks_out, BLOCKS_DBFILE_SORTED, blocks.into_iter())?;
     println!("Wrote sorted blocks");
 
-    iopairs.sort_unstable_by_key(|k| k.source.src_tx);
+    // When parsing resumes from a checkpoint, an output that an earlier run wrote out as unspent is
+    // written again once it gets spent. Only keep the spent one, and only one copy of iopairs that
+    // were written twice because of a reorg.
+    iopairs.sort_unstable_by_key(|k| {
+        (
+            k.source.src_tx,
+            k.source.src_index,
+            k.source.src_block,
+            k.dest.is_none(),
+            k.dest,
+        )
+    });
+    iopairs.dedup_by(|later, earlier| {
+        later.source == earlier.source && (later.dest.is_none() || later.dest == earlier.dest)
+    });
     println!("Sorted iopairs by source tx");
-    for (i, p) in iopairs.iter().enumerate() {
-        serialize_into(&mut iopairs_by_src_out[i % for_num_workers], &p).unwrap();
-    }
+    write_to_workers(
+        &mut iopairs_by_src_out,
+        IOPAIRS_DBFILE_SORTED_SRC,
+        iopairs.iter(),
+    )?;
     println!("Wrote iopairs sorted by source tx");
 
-    iopairs.retain(|x| match x.dest {
-        Some(_) => true,
-        None => false,
-    });
+    iopairs.retain(|x| x.dest.is_some());
     println!("Filtered out iopairs without dest tx");
 
     iopairs.sort_unstable_by_key(|k| k.dest.unwrap().dest_tx);
     println!("Sorted iopairs by dest tx");
 
-    for (i, p) in iopairs.iter().enumerate() {
-        serialize_into(&mut iopairs_by_dest_out[i % for_num_workers], &p).unwrap();
-    }
+    write_to_workers(
+        &mut iopairs_by_dest_out,
+        IOPAIRS_DBFILE_SORTED_DEST,
+        iopairs.iter(),
+    )?;
     println!("Wrote iopairs sorted by dest tx");
+
+    Ok(())
 }
 
-#[once(sync_writes = true)]
-pub fn load_data_sorted() -> (
+// The sorted data held by a worker: transactions, the positions of the transactions that have a fee
+// sorted by fee and by feerate, blocks, iopairs sorted by source tx, iopairs sorted by dest tx, the
+// positions of the iopairs sorted by source tx that have an address, sorted by address, and the
+// positions of those of OP_RETURN outputs, sorted by their data.
+pub type SortedData = (
     Arc<Vec<Transaction>>,
+    Arc<Vec<u32>>,
+    Arc<Vec<u32>>,
     Arc<Vec<Block>>,
     Arc<Vec<InputOutputPair>>,
     Arc<Vec<InputOutputPair>>,
-) {
-    let txs: Vec<Transaction> = read_custom_format(TRANSACTIONS_DBFILE_SORTED);
-    let blocks: Vec<Block> = read_custom_format(BLOCKS_DBFILE_SORTED);
-    let iopairs_sorted_src: Vec<InputOutputPair> = read_custom_format(IOPAIRS_DBFILE_SORTED_SRC);
-    let iopairs_sorted_dest: Vec<InputOutputPair> = read_custom_format(IOPAIRS_DBFILE_SORTED_DEST);
+    Arc<Vec<u32>>,
+    Arc<Vec<u32>>,
+);
+
+// The network of the sorted data held by a worker. Fails if its files are from different networks.
+pub fn sorted_data_network() -> anyhow::Result<Network> {
+    let files = [
+        TRANSACTIONS_DBFILE_SORTED,
+        BLOCKS_DBFILE_SORTED,
+        IOPAIRS_DBFILE_SORTED_SRC,
+        IOPAIRS_DBFILE_SORTED_DEST,
+    ];
+    let networks = files
+        .iter()
+        .map(|f| Ok((Path::new(f), read_custom_format_network(Path::new(f))?)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    same_network(&networks)
+}
+
+#[once(sync_writes = true)]
+pub fn load_data_sorted() -> SortedData {
+    load_data_sorted_from(Path::new(""))
+}
+
+// Loads the sorted files of a worker from `dir`.
+fn load_data_sorted_from(dir: &Path) -> SortedData {
+    let (_, txs): (Network, Vec<Transaction>) =
+        read_custom_format(&dir.join(TRANSACTIONS_DBFILE_SORTED));
+    let (_, blocks): (Network, Vec<Block>) = read_custom_format(&dir.join(BLOCKS_DBFILE_SORTED));
+    let (_, iopairs_sorted_src): (Network, Vec<InputOutputPair>) =
+        read_custom_format(&dir.join(IOPAIRS_DBFILE_SORTED_SRC));
+    let (_, iopairs_sorted_dest): (Network, Vec<InputOutputPair>) =
+        read_custom_format(&dir.join(IOPAIRS_DBFILE_SORTED_DEST));
+    index_sorted_data(txs, blocks, iopairs_sorted_src, iopairs_sorted_dest)
+}
+
+// Adds the indexes that let a worker search its sorted data in other orders.
+pub fn index_sorted_data(
+    txs: Vec<Transaction>,
+    blocks: Vec<Block>,
+    iopairs_sorted_src: Vec<InputOutputPair>,
+    iopairs_sorted_dest: Vec<InputOutputPair>,
+) -> SortedData {
+    // Coinbase transactions and transactions whose inputs were not all found have no fee, and are
+    // left out. Ties are broken by txid and block, so that the order doesn't depend on the sort.
+    let txs_by_fee = sorted_positions(
+        &txs,
+        |t| t.fee.is_some(),
+        |a, b| (a.fee, a.id, a.block).cmp(&(b.fee, b.id, b.block)),
+    );
+    println!("Sorted transactions by fee");
+    let txs_by_feerate = sorted_positions(
+        &txs,
+        |t| t.feerate.is_some(),
+        |a, b| {
+            let feerate = |t: &Transaction| t.feerate.unwrap_or_default();
+            feerate(a)
+                .total_cmp(&feerate(b))
+                .then((a.id, a.block).cmp(&(b.id, b.block)))
+        },
+    );
+    println!("Sorted transactions by feerate");
+
+    // Ties are broken by the whole iopair, so that the order doesn't depend on the sort.
+    let iopairs_by_address = sorted_positions(
+        &iopairs_sorted_src,
+        |p| p.source.address.is_some(),
+        |a, b| a.source.address.cmp(&b.source.address).then(a.cmp(b)),
+    );
+    println!("Sorted iopairs by address");
+    let iopairs_by_data = sorted_positions(
+        &iopairs_sorted_src,
+        |p| p.source.data.is_some(),
+        |a, b| a.source.data.cmp(&b.source.data).then(a.cmp(b)),
+    );
+    println!("Sorted iopairs by OP_RETURN data");
 
     (
         Arc::new(txs),
+        Arc::new(txs_by_fee),
+        Arc::new(txs_by_feerate),
         Arc::new(blocks),
         Arc::new(iopairs_sorted_src),
         Arc::new(iopairs_sorted_dest),
+        Arc::new(iopairs_by_address),
+        Arc::new(iopairs_by_data),
     )
 }
 
-pub fn load_tx_ids_sorted() -> Vec<TxHash> {
-    let txs: Vec<Transaction> = read_custom_format(TRANSACTIONS_DBFILE_SORTED);
-    txs.into_iter().map(|x| x.id).collect_vec()
+// The positions of the elements of `v` that `keep` selects, sorted by `compare`. This lets a worker
+// search `v` in another order without keeping a second copy of it.
+fn sorted_positions<T>(
+    v: &[T],
+    keep: impl Fn(&T) -> bool,
+    compare: impl Fn(&T, &T) -> Ordering,
+) -> Vec<u32> {
+    let len = u32::try_from(v.len()).expect("too much data for one worker");
+    let mut positions: Vec<u32> = (0..len).filter(|i| keep(&v[*i as usize])).collect();
+    positions.sort_unstable_by(|a, b| compare(&v[*a as usize], &v[*b as usize]));
+    positions
+}
+
+pub fn load_tx_ids_sorted() -> (Network, Vec<TxHash>) {
+    let (network, txs): (Network, Vec<Transaction>) =
+        read_custom_format(Path::new(TRANSACTIONS_DBFILE_SORTED));
+    (network, txs.into_iter().map(|x| x.id).collect_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_source::BlockSource;
+    use crate::checkpoint::Checkpoint;
+    use crate::parser::Parser;
+    use crate::test_chain::{self, coinbase, mine, p2pkh, spend};
+    use crate::transaction::COINBASE_SOURCE_TX;
+
+    // Parses `blocks` as a regtest blk file into the custom-format files in `dir`.
+    fn parse_into(dir: &Path, blocks: &[&test_chain::Block]) {
+        let blk_dir = tempfile::tempdir().unwrap();
+        let path = blk_dir.path().join("blk00000.dat");
+        std::fs::write(&path, test_chain::blk_data(blocks)).unwrap();
+
+        let mut writer = CustomWriter::in_dir(dir, Network::Regtest, false).unwrap();
+        let mut parser = Parser::new(&mut writer);
+        parser.set_network(Network::Regtest);
+        parser.parse_file(&path).unwrap();
+    }
+
+    // Loads the sorted files for worker `i`, as the worker would after they were copied to it.
+    fn load_worker(dir: &Path, i: usize) -> SortedData {
+        let worker_dir = tempfile::tempdir().unwrap();
+        for f in [
+            TRANSACTIONS_DBFILE_SORTED,
+            BLOCKS_DBFILE_SORTED,
+            IOPAIRS_DBFILE_SORTED_SRC,
+            IOPAIRS_DBFILE_SORTED_DEST,
+        ] {
+            std::fs::copy(dir.join(format!("{}-{}", i, f)), worker_dir.path().join(f)).unwrap();
+        }
+        load_data_sorted_from(worker_dir.path())
+    }
+
+    #[test]
+    fn keeps_transactions_and_outputs_that_share_a_txid() {
+        // Blocks 1 and 3 have the same coinbase, and block 4 spends its output.
+        let genesis = test_chain::genesis();
+        let duplicate = coinbase(1, &[(50_0000_0000, p2pkh(1))]);
+        let b1 = mine(&genesis, std::slice::from_ref(&duplicate));
+        let b2 = mine(&b1, &[coinbase(2, &[(50_0000_0000, p2pkh(2))])]);
+        let b3 = mine(&b2, std::slice::from_ref(&duplicate));
+        let s = spend(&[(duplicate.id, 0)], &[(49_0000_0000, p2pkh(4))], false);
+        let b4 = mine(&b3, &[coinbase(4, &[(50_0000_0000, p2pkh(3))]), s.clone()]);
+
+        let dir = tempfile::tempdir().unwrap();
+        parse_into(dir.path(), &[&genesis, &b1, &b2, &b3, &b4]);
+
+        // Block 1 was written a second time, as happens when a block comes back to the main chain
+        // after a reorg. Only one copy of it is kept.
+        let (_, txs, blocks, _) = read_custom_formats(
+            &dir.path().join(TRANSACTIONS_DBFILE_UNSORTED),
+            &dir.path().join(BLOCKS_DBFILE_UNSORTED),
+            &dir.path().join(IOPAIRS_DBFILE_UNSORTED),
+        )
+        .unwrap();
+        let mut writer = CustomWriter::in_dir(dir.path(), Network::Regtest, true).unwrap();
+        let block = blocks.iter().find(|b| b.id == b1.id).unwrap();
+        writer.insert_block(*block).unwrap();
+        for t in txs.iter().filter(|t| t.block == b1.id) {
+            writer.insert_tx(*t).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+
+        sort_and_write_data_in(dir.path(), 1).unwrap();
+        let (txs, _, _, blocks, by_src, by_dest, _, _) = load_worker(dir.path(), 0);
+
+        assert_eq!(blocks.len(), 5);
+        let copies: Vec<_> = txs
+            .iter()
+            .filter(|t| t.id == duplicate.id)
+            .map(|t| t.block)
+            .sorted()
+            .collect();
+        assert_eq!(copies, [b1.id, b3.id].into_iter().sorted().collect_vec());
+        assert_eq!(txs.len(), 6);
+
+        let outputs: Vec<_> = by_src
+            .iter()
+            .filter(|p| p.source.src_tx == duplicate.id)
+            .map(|p| (p.source.src_block, p.dest.map(|d| d.dest_block)))
+            .sorted_by_key(|(_, dest)| dest.is_some())
+            .collect();
+        assert_eq!(outputs, [(b1.id, None), (b3.id, Some(b4.id))]);
+        // The coinbase source and coinbase output of each block, and the output of `s`.
+        assert_eq!(by_src.len(), 11);
+        assert!(by_src
+            .iter()
+            .map(|p| (p.source.src_tx, p.source.src_index, p.source.src_block))
+            .all_unique());
+
+        let spends: Vec<_> = by_dest
+            .iter()
+            .filter(|p| p.source.src_tx != COINBASE_SOURCE_TX)
+            .collect();
+        assert_eq!(spends.len(), 1);
+        assert_eq!(spends[0].source.src_block, b3.id);
+        assert_eq!(spends[0].dest.unwrap().dest_tx, s.id);
+    }
+
+    // Parses the blk files in `blocks_dir` into the custom-format files in `dir`, continuing from
+    // `checkpoint` if there is one, and returns the checkpoint to continue from next time.
+    fn parse_run(dir: &Path, blocks_dir: &Path, checkpoint: Option<Checkpoint>) -> Checkpoint {
+        let mut writer = CustomWriter::in_dir(dir, Network::Regtest, checkpoint.is_some()).unwrap();
+        let mut parser = Parser::new(&mut writer);
+        parser.set_network(Network::Regtest);
+        if let Some(c) = checkpoint {
+            parser.resume_from(c).unwrap();
+        }
+        let source = BlockSource::Directory {
+            path: blocks_dir.to_path_buf(),
+            start: 0,
+            end: None,
+        };
+        parser.parse(&source).unwrap();
+        parser.into_checkpoint().unwrap()
+    }
+
+    #[test]
+    fn resuming_spends_outputs_written_by_the_earlier_run() {
+        // The first run sees blocks 1 and 2 and writes the coinbase output of block 1 as unspent.
+        // The second run sees block 3, which was appended to the same file and spends it, and block
+        // 4 in a new file.
+        let genesis = test_chain::genesis();
+        let cb1 = coinbase(1, &[(50_0000_0000, p2pkh(1))]);
+        let b1 = mine(&genesis, std::slice::from_ref(&cb1));
+        let b2 = mine(&b1, &[coinbase(2, &[(50_0000_0000, p2pkh(2))])]);
+        let s = spend(&[(cb1.id, 0)], &[(49_0000_0000, p2pkh(3))], false);
+        let b3 = mine(&b2, &[coinbase(3, &[(50_0000_0000, p2pkh(3))]), s.clone()]);
+        let b4 = mine(&b3, &[coinbase(4, &[(50_0000_0000, p2pkh(4))])]);
+
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoint_path = dir.path().join("checkpoint");
+        test_chain::append_blk_file(blocks_dir.path(), 0, &[&genesis, &b1, &b2]);
+        parse_run(dir.path(), blocks_dir.path(), None)
+            .save(&checkpoint_path)
+            .unwrap();
+
+        test_chain::append_blk_file(blocks_dir.path(), 0, &[&b3]);
+        test_chain::append_blk_file(blocks_dir.path(), 1, &[&b4]);
+        let checkpoint = Checkpoint::load(&checkpoint_path).unwrap().unwrap();
+        parse_run(dir.path(), blocks_dir.path(), Some(checkpoint));
+
+        // Both runs wrote a row for the output, since the unsorted files are only ever appended to.
+        let (_, _, _, iopairs) = read_custom_formats(
+            &dir.path().join(TRANSACTIONS_DBFILE_UNSORTED),
+            &dir.path().join(BLOCKS_DBFILE_UNSORTED),
+            &dir.path().join(IOPAIRS_DBFILE_UNSORTED),
+        )
+        .unwrap();
+        let rows = iopairs.iter().filter(|p| p.source.src_tx == cb1.id).count();
+        assert_eq!(rows, 2);
+
+        sort_and_write_data_in(dir.path(), 1).unwrap();
+        let (txs, _, _, blocks, by_src, _, _, _) = load_worker(dir.path(), 0);
+        assert_eq!(blocks.len(), 5);
+        assert!(blocks.iter().map(|b| b.id).all_unique());
+        assert_eq!(txs.len(), 6);
+        assert!(txs.iter().map(|t| (t.id, t.block)).all_unique());
+
+        // The unspent row written by the first run gave way to the spent one.
+        let outputs: Vec<_> = by_src
+            .iter()
+            .filter(|p| p.source.src_tx == cb1.id)
+            .collect();
+        assert_eq!(outputs.len(), 1);
+        let dest = outputs[0].dest.unwrap();
+        assert_eq!((dest.dest_tx, dest.dest_block), (s.id, b3.id));
+        // The coinbase source and coinbase output of each block, and the output of `s`.
+        assert_eq!(by_src.len(), 11);
+        assert!(by_src
+            .iter()
+            .map(|p| (p.source.src_tx, p.source.src_index, p.source.src_block))
+            .all_unique());
+    }
+
+    #[test]
+    fn records_the_network_in_every_file() {
+        let genesis = test_chain::genesis();
+        let dir = tempfile::tempdir().unwrap();
+        parse_into(dir.path(), &[&genesis]);
+        sort_and_write_data_in(dir.path(), 2).unwrap();
+
+        let mut files = vec![
+            TRANSACTIONS_DBFILE_UNSORTED.to_string(),
+            BLOCKS_DBFILE_UNSORTED.to_string(),
+            IOPAIRS_DBFILE_UNSORTED.to_string(),
+        ];
+        for i in 0..2 {
+            for f in [
+                TRANSACTIONS_DBFILE_SORTED,
+                BLOCKS_DBFILE_SORTED,
+                IOPAIRS_DBFILE_SORTED_SRC,
+                IOPAIRS_DBFILE_SORTED_DEST,
+            ] {
+                files.push(format!("{}-{}", i, f));
+            }
+        }
+        for f in files {
+            let network = read_custom_format_network(&dir.path().join(&f)).unwrap();
+            assert_eq!(network, Network::Regtest, "{}", f);
+        }
+    }
+
+    #[test]
+    fn refuses_to_append_data_from_another_network() {
+        let genesis = test_chain::genesis();
+        let dir = tempfile::tempdir().unwrap();
+        parse_into(dir.path(), &[&genesis]);
+
+        let error = CustomWriter::in_dir(dir.path(), Network::Mainnet, true)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("holds regtest data, not mainnet"));
+    }
+
+    #[test]
+    fn leaves_out_blocks_retracted_in_reorgs() {
+        let fork = test_chain::Fork::new();
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = CustomWriter::in_dir(dir.path(), Network::Regtest, false).unwrap();
+        let mut parser = Parser::new(&mut writer);
+        parser.set_network(Network::Regtest);
+        let spent_in = |by_src: &[InputOutputPair], tx: TxHash| -> Vec<Option<BlockHash>> {
+            by_src
+                .iter()
+                .filter(|p| p.source.src_tx == tx)
+                .map(|p| p.dest.map(|d| d.dest_block))
+                .collect()
+        };
+        fork.follow(&mut parser, |step, _| {
+            if step < 2 {
+                return;
+            }
+            sort_and_write_data_in(dir.path(), 1).unwrap();
+            let (txs, _, _, blocks, by_src, _, _, _) = load_worker(dir.path(), 0);
+            assert!(txs.iter().map(|t| (t.id, t.block)).all_unique());
+            assert!(by_src
+                .iter()
+                .map(|p| (p.source.src_tx, p.source.src_index, p.source.src_block))
+                .all_unique());
+            match step {
+                2 => {
+                    assert_eq!(blocks.len(), 6);
+                    assert_eq!(spent_in(&by_src, fork.cb1.id), [Some(fork.c[0].id)]);
+                    assert_eq!(spent_in(&by_src, fork.cb2.id), [Some(fork.c[1].id)]);
+                }
+                _ => {
+                    assert_eq!(blocks.len(), 7);
+                    // The coinbase of each block, and `spend_a`.
+                    assert_eq!(txs.len(), 8);
+                    // The coinbase source and coinbase output of each block, and the output of
+                    // `spend_a`.
+                    assert_eq!(by_src.len(), 15);
+                    assert_eq!(spent_in(&by_src, fork.cb1.id), [None]);
+                    assert_eq!(spent_in(&by_src, fork.cb2.id), [Some(fork.a[0].id)]);
+                    assert_eq!(spent_in(&by_src, fork.spend_c.id), []);
+                }
+            }
+        });
+        drop(parser);
+        drop(writer);
+
+        // Blocks 3 and 4 of `a` were written twice and retracted once, so they are kept.
+        let (network, _, blocks, _) = read_custom_formats(
+            &dir.path().join(TRANSACTIONS_DBFILE_UNSORTED),
+            &dir.path().join(BLOCKS_DBFILE_UNSORTED),
+            &dir.path().join(IOPAIRS_DBFILE_UNSORTED),
+        )
+        .unwrap();
+        let retracted = read_retracted_blocks(
+            &dir.path().join(RETRACTED_BLOCKS_DBFILE_UNSORTED),
+            network,
+            &blocks,
+        )
+        .unwrap();
+        assert_eq!(retracted, fork.c.iter().map(|b| b.id).collect());
+    }
 }
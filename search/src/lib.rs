@@ -1,6 +1,18 @@
+pub mod block_reader;
+pub mod block_source;
+pub mod chain;
+pub mod checkpoint;
 pub mod custom_format;
+pub mod network;
+pub mod node_rpc;
 pub mod output_writer;
+pub mod parquet_format;
 pub mod parser;
 pub mod rpc_service;
+pub mod script;
 pub mod sqlite;
+#[cfg(test)]
+mod test_chain;
+pub mod text_format;
 pub mod transaction;
+pub mod utxo_store;
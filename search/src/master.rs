@@ -1,12 +1,13 @@
+use anyhow::bail;
 use clap::Parser;
 use hdrhistogram::Histogram;
 use search::custom_format::load_tx_ids_sorted;
+use search::network::Network;
 use search::rpc_service::{SearchClient, DEFAULT_PORT};
 use search::transaction::{InputOutputPair, TxHash};
 use rand::seq::SliceRandom;
 use std::net::{IpAddr, Ipv4Addr};
 use tarpc::{client, context, tokio_serde::formats::Bincode};
-use tokio;
 use tokio::time::Instant;
 
 #[derive(Parser, Debug)]
@@ -25,10 +26,10 @@ const LATENCY_NUM_ITERS: u64 = 100_000;
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
-    if args.client.len() < 1 {
+    if args.client.is_empty() {
         panic!("Need at least one client!")
     }
-    if args.port.len() != 0 && args.client.len() != args.port.len() {
+    if !args.port.is_empty() && args.client.len() != args.port.len() {
         panic!(
             "Passed in {} client IP addresses and {} port numbers. They need to match (or you can pass in no ports at all, and the default of {} is used for all clients).",
             args.client.len(),
@@ -37,14 +38,14 @@ async fn main() -> anyhow::Result<()> {
         );
     }
     let ports: Vec<u16> = match args.port.len() {
-        0 => vec![DEFAULT_PORT; args.client.len().try_into().unwrap()],
+        0 => vec![DEFAULT_PORT; args.client.len()],
         _ => args.port,
     };
 
     // In the master, we load some data so that we can make real queries.
     println!("loading data...");
-    let txs = load_tx_ids_sorted();
-    println!("data loaded... ({} tx hashes)", txs.len());
+    let (network, txs) = load_tx_ids_sorted();
+    println!("data loaded... ({} {} tx hashes)", txs.len(), network);
 
     let mut clients: Vec<SearchClient> = Vec::new();
 
@@ -58,6 +59,8 @@ async fn main() -> anyhow::Result<()> {
             tarpc::serde_transport::tcp::connect((IpAddr::V4(*c), ports[i]), Bincode::default);
 
         let client = SearchClient::new(client::Config::default(), transport.await?).spawn();
+        let client_network = client.network(context::current()).await?;
+        check_client_network(i, network, client_network)?;
         clients.push(client);
 
         println!(
@@ -66,7 +69,7 @@ async fn main() -> anyhow::Result<()> {
         );
     }
     println!("Master clients spawned!");
-    println!("");
+    println!();
 
     // Testing setup
     let mut rng = rand::thread_rng();
@@ -88,7 +91,7 @@ async fn main() -> anyhow::Result<()> {
             THROUGHPUT_NUM_ITERS,
             new_now.duration_since(now)
         );
-        println!("");
+        println!();
     }
 
     {
@@ -133,7 +136,7 @@ async fn main() -> anyhow::Result<()> {
             latencies_ns.value_at_quantile(0.99999)
         );
         println!("Max latency: {} ns", latencies_ns.max());
-        println!("");
+        println!();
     }
 
     // Run tests for parents queries
@@ -153,7 +156,7 @@ async fn main() -> anyhow::Result<()> {
             THROUGHPUT_NUM_ITERS,
             new_now.duration_since(now)
         );
-        println!("");
+        println!();
     }
 
     {
@@ -198,13 +201,26 @@ async fn main() -> anyhow::Result<()> {
             latencies_ns.value_at_quantile(0.99999)
         );
         println!("Max latency: {} ns", latencies_ns.max());
-        println!("");
+        println!();
     }
 
     Ok(())
 }
 
-async fn get_children_of_txs(clients: &Vec<SearchClient>, t: &Vec<TxHash>) -> Vec<InputOutputPair> {
+// Checks that the client at position `i` serves data from the same network as the master's.
+fn check_client_network(i: usize, network: Network, client_network: Network) -> anyhow::Result<()> {
+    if client_network != network {
+        bail!(
+            "client {} serves {} data, but the master's data is from {}",
+            i,
+            client_network,
+            network
+        );
+    }
+    Ok(())
+}
+
+async fn get_children_of_txs(clients: &[SearchClient], t: &[TxHash]) -> Vec<InputOutputPair> {
     match clients.len() {
         1 => {
             match async {
@@ -219,52 +235,44 @@ async fn get_children_of_txs(clients: &Vec<SearchClient>, t: &Vec<TxHash>) -> Ve
             }
         }
         2 => {
-            match async {
+            let (x, y) = async {
                 tokio::join! {
                     clients[0].transactions_by_sources(context::current(), t.to_vec()),
                     clients[1].transactions_by_sources(context::current(), t.to_vec()),
                 }
             }
-            .await
-            {
-                (x, y) => {
-                    let (mut x, mut y) = (x.unwrap(), y.unwrap());
-                    let mut result: Vec<InputOutputPair> = Vec::new();
-                    result.append(&mut x);
-                    result.append(&mut y);
-                    result.sort_unstable();
-                    result.dedup();
-                    result
-                }
-            }
+            .await;
+            let (mut x, mut y) = (x.unwrap(), y.unwrap());
+            let mut result: Vec<InputOutputPair> = Vec::new();
+            result.append(&mut x);
+            result.append(&mut y);
+            result.sort_unstable();
+            result.dedup();
+            result
         },
         3 => {
-            match async {
+            let (x, y, z) = async {
                 tokio::join! {
                     clients[0].transactions_by_sources(context::current(), t.to_vec()),
                     clients[1].transactions_by_sources(context::current(), t.to_vec()),
                     clients[2].transactions_by_sources(context::current(), t.to_vec()),
                 }
             }
-            .await
-            {
-                (x, y, z) => {
-                    let (mut x, mut y, mut z) = (x.unwrap(), y.unwrap(), z.unwrap());
-                    let mut result: Vec<InputOutputPair> = Vec::new();
-                    result.append(&mut x);
-                    result.append(&mut y);
-                    result.append(&mut z);
-                    result.sort_unstable();
-                    result.dedup();
-                    result
-                }
-            }
+            .await;
+            let (mut x, mut y, mut z) = (x.unwrap(), y.unwrap(), z.unwrap());
+            let mut result: Vec<InputOutputPair> = Vec::new();
+            result.append(&mut x);
+            result.append(&mut y);
+            result.append(&mut z);
+            result.sort_unstable();
+            result.dedup();
+            result
         },
         _ => panic!("Because of personal issues with the Rust compiler, we currently only support the cases where there are exactly 1, 2, or 3 clients.")
     }
 }
 
-async fn get_parents_of_txs(clients: &Vec<SearchClient>, t: &Vec<TxHash>) -> Vec<InputOutputPair> {
+async fn get_parents_of_txs(clients: &[SearchClient], t: &[TxHash]) -> Vec<InputOutputPair> {
     match clients.len() {
         1 => {
             match async {
@@ -279,52 +287,45 @@ async fn get_parents_of_txs(clients: &Vec<SearchClient>, t: &Vec<TxHash>) -> Vec
             }
         },
         2 => {
-            match async {
+            let (x, y) = async {
                 tokio::join! {
                     clients[0].transactions_by_destinations(context::current(), t.to_vec()),
                     clients[1].transactions_by_destinations(context::current(), t.to_vec()),
                 }
             }
-            .await
-            {
-                (x, y) => {
-                    let (mut x, mut y) = (x.unwrap(), y.unwrap());
-                    let mut result: Vec<InputOutputPair> = Vec::new();
-                    result.append(&mut x);
-                    result.append(&mut y);
-                    result.sort_unstable();
-                    result.dedup();
-                    result
-                }
-            }
+            .await;
+            let (mut x, mut y) = (x.unwrap(), y.unwrap());
+            let mut result: Vec<InputOutputPair> = Vec::new();
+            result.append(&mut x);
+            result.append(&mut y);
+            result.sort_unstable();
+            result.dedup();
+            result
         },
         3 => {
-            match async {
+            let (x, y, z) = async {
                 tokio::join! {
                     clients[0].transactions_by_destinations(context::current(), t.to_vec()),
                     clients[1].transactions_by_destinations(context::current(), t.to_vec()),
                     clients[2].transactions_by_destinations(context::current(), t.to_vec()),
                 }
             }
-            .await
-            {
-                (x, y, z) => {
-                    let (mut x, mut y, mut z) = (x.unwrap(), y.unwrap(), z.unwrap());
-                    let mut result: Vec<InputOutputPair> = Vec::new();
-                    result.append(&mut x);
-                    result.append(&mut y);
-                    result.append(&mut z);
-                    result.sort_unstable();
-                    result.dedup();
-                    result
-                }
-            }
+            .await;
+            let (mut x, mut y, mut z) = (x.unwrap(), y.unwrap(), z.unwrap());
+            let mut result: Vec<InputOutputPair> = Vec::new();
+            result.append(&mut x);
+            result.append(&mut y);
+            result.append(&mut z);
+            result.sort_unstable();
+            result.dedup();
+            result
         },
         _ => panic!("Because of personal issues with the Rust compiler, we currently only support the cases where there are exactly 1, 2, or 3 clients.")
     }
 }
 
-async fn get_grandchildren_of_tx(clients: &Vec<SearchClient>, t: &TxHash) -> Vec<InputOutputPair> {
+#[allow(dead_code)]
+async fn get_grandchildren_of_tx(clients: &[SearchClient], t: &TxHash) -> Vec<InputOutputPair> {
     let v = vec![*t];
     let children = get_children_of_txs(clients, &v);
     let mut children: Vec<TxHash> = children
@@ -334,10 +335,11 @@ async fn get_grandchildren_of_tx(clients: &Vec<SearchClient>, t: &TxHash) -> Vec
         .collect();
     children.sort();
     children.dedup();
-    return get_children_of_txs(clients, &children).await;
+    get_children_of_txs(clients, &children).await
 }
 
-async fn get_grandparents_of_tx(clients: &Vec<SearchClient>, t: &TxHash) -> Vec<InputOutputPair> {
+#[allow(dead_code)]
+async fn get_grandparents_of_tx(clients: &[SearchClient], t: &TxHash) -> Vec<InputOutputPair> {
     let v = vec![*t];
     let parents = get_parents_of_txs(clients, &v);
     let mut parents: Vec<TxHash> = parents
@@ -347,5 +349,22 @@ async fn get_grandparents_of_tx(clients: &Vec<SearchClient>, t: &TxHash) -> Vec<
         .collect();
     parents.sort();
     parents.dedup();
-    return get_parents_of_txs(clients, &parents).await;
+    get_parents_of_txs(clients, &parents).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_clients_on_another_network() {
+        assert!(check_client_network(0, Network::Regtest, Network::Regtest).is_ok());
+        let error = check_client_network(1, Network::Mainnet, Network::Signet)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "client 1 serves signet data, but the master's data is from mainnet"
+        );
+    }
 }
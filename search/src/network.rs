@@ -0,0 +1,99 @@
+use crate::chain::PowParams;
+use crate::transaction::BlockHash;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// The Bitcoin networks that block data can come from. They differ in the magic bytes in front of
+// every block, their genesis block, and their proof-of-work rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Network {
+    Mainnet,
+    // testnet3
+    Testnet,
+    // The default signet. Block signatures are not checked.
+    Signet,
+    Regtest,
+}
+
+impl Network {
+    pub const ALL: [Network; 4] = [
+        Network::Mainnet,
+        Network::Testnet,
+        Network::Signet,
+        Network::Regtest,
+    ];
+
+    // The bytes that precede every block in the network's blk files (`pchMessageStart` in Bitcoin
+    // Core).
+    pub fn magic(self) -> [u8; 4] {
+        match self {
+            Network::Mainnet => [0xf9, 0xbe, 0xb4, 0xd9],
+            Network::Testnet => [0x0b, 0x11, 0x09, 0x07],
+            Network::Signet => [0x0a, 0x03, 0xcf, 0x40],
+            Network::Regtest => [0xfa, 0xbf, 0xb5, 0xda],
+        }
+    }
+
+    // The network whose blocks start with `magic`, if any.
+    pub fn from_magic(magic: &[u8]) -> Option<Network> {
+        Network::ALL.into_iter().find(|n| n.magic() == magic)
+    }
+
+    pub fn genesis_hash(self) -> BlockHash {
+        BlockHash::new_from_str(match self {
+            Network::Mainnet => "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+            Network::Testnet => "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943",
+            Network::Signet => "00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6",
+            Network::Regtest => "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206",
+        })
+    }
+
+    pub fn pow_params(self) -> PowParams {
+        match self {
+            Network::Mainnet => PowParams::MAINNET,
+            Network::Testnet => PowParams::TESTNET,
+            Network::Signet => PowParams::SIGNET,
+            Network::Regtest => PowParams::REGTEST,
+        }
+    }
+
+    // Version byte of base58 P2PKH addresses.
+    pub fn p2pkh_version(self) -> u8 {
+        match self {
+            Network::Mainnet => 0x00,
+            Network::Testnet | Network::Signet | Network::Regtest => 0x6f,
+        }
+    }
+
+    // Version byte of base58 P2SH addresses.
+    pub fn p2sh_version(self) -> u8 {
+        match self {
+            Network::Mainnet => 0x05,
+            Network::Testnet | Network::Signet | Network::Regtest => 0xc4,
+        }
+    }
+
+    // Human-readable part of bech32 and bech32m (segwit) addresses.
+    pub fn bech32_hrp(self) -> bech32::Hrp {
+        match self {
+            Network::Mainnet => bech32::hrp::BC,
+            Network::Testnet | Network::Signet => bech32::hrp::TB,
+            Network::Regtest => bech32::hrp::BCRT,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Signet => "signet",
+            Network::Regtest => "regtest",
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
@@ -0,0 +1,238 @@
+use crate::network::Network;
+use crate::transaction::BlockHash;
+use anyhow::{bail, Context};
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::fmt;
+use std::io::Read;
+use std::path::PathBuf;
+
+// How to authenticate to a node's JSON-RPC interface.
+#[derive(Clone, PartialEq, Eq)]
+pub enum RpcAuth {
+    None,
+    // The cookie file Bitcoin Core writes to its data directory (`.cookie`), which holds
+    // `user:password`. It is read on every connect, since the node changes it when it restarts.
+    Cookie(PathBuf),
+    UserPassword { user: String, password: String },
+}
+
+// Keeps the password out of logs.
+impl fmt::Debug for RpcAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcAuth::None => write!(f, "None"),
+            RpcAuth::Cookie(path) => write!(f, "Cookie({:?})", path),
+            RpcAuth::UserPassword { user, .. } => write!(f, "UserPassword({:?}, <hidden>)", user),
+        }
+    }
+}
+
+// A Bitcoin Core node whose blocks are fetched over JSON-RPC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RpcEndpoint {
+    // For example `http://127.0.0.1:8332`.
+    pub url: String,
+    pub auth: RpcAuth,
+}
+
+pub struct RpcClient {
+    url: String,
+    // Value of the HTTP `Authorization` header.
+    authorization: Option<String>,
+    agent: ureq::Agent,
+    next_id: u64,
+}
+
+impl RpcClient {
+    pub fn connect(endpoint: &RpcEndpoint) -> anyhow::Result<RpcClient> {
+        let credentials = match &endpoint.auth {
+            RpcAuth::None => None,
+            RpcAuth::Cookie(path) => Some(
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read RPC cookie file {}", path.display()))?
+                    .trim()
+                    .to_string(),
+            ),
+            RpcAuth::UserPassword { user, password } => Some(format!("{}:{}", user, password)),
+        };
+        let authorization = credentials.map(|c| {
+            format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(c)
+            )
+        });
+        Ok(RpcClient {
+            url: endpoint.url.clone(),
+            authorization,
+            agent: ureq::AgentBuilder::new().build(),
+            next_id: 0,
+        })
+    }
+
+    // Calls `method` and returns its result. Errors reported by the node are returned as errors.
+    fn call<T: DeserializeOwned>(&mut self, method: &str, params: Value) -> anyhow::Result<T> {
+        self.next_id += 1;
+        let mut request = self.agent.post(&self.url);
+        if let Some(a) = &self.authorization {
+            request = request.set("Authorization", a);
+        }
+        let body = json!({
+            "jsonrpc": "1.0",
+            "id": self.next_id,
+            "method": method,
+            "params": params,
+        });
+
+        // Bitcoin Core reports RPC errors with an HTTP error status, but still sends the usual
+        // response with the error in it.
+        let response = match request.send_json(body) {
+            Ok(r) => r,
+            Err(ureq::Error::Status(401, _)) => {
+                bail!("{} rejected the RPC credentials", self.url)
+            }
+            Err(ureq::Error::Status(_, r)) => r,
+            Err(e) => return Err(e).with_context(|| format!("RPC call {} failed", method)),
+        };
+        let mut response: Value = response
+            .into_json()
+            .with_context(|| format!("invalid response to RPC call {}", method))?;
+
+        if !response["error"].is_null() {
+            bail!(
+                "RPC call {} failed: {}",
+                method,
+                response["error"]["message"]
+                    .as_str()
+                    .map(String::from)
+                    .unwrap_or_else(|| response["error"].to_string())
+            );
+        }
+        serde_json::from_value(response["result"].take())
+            .with_context(|| format!("unexpected result of RPC call {}", method))
+    }
+
+    // Height of the node's best block.
+    pub fn block_count(&mut self) -> anyhow::Result<u32> {
+        self.call("getblockcount", json!([]))
+    }
+
+    // Hash of the block at `height` on the node's best chain, in hex.
+    pub fn block_hash(&mut self, height: u32) -> anyhow::Result<String> {
+        self.call("getblockhash", json!([height]))
+    }
+
+    pub fn raw_block(&mut self, hash: &str) -> anyhow::Result<Vec<u8>> {
+        let hex: String = self.call("getblock", json!([hash, 0]))?;
+        hex::decode(hex.trim()).with_context(|| format!("block {} is not valid hex", hash))
+    }
+
+    pub fn raw_block_header(&mut self, hash: &str) -> anyhow::Result<Vec<u8>> {
+        let hex: String = self.call("getblockheader", json!([hash, false]))?;
+        hex::decode(hex.trim()).with_context(|| format!("block header {} is not valid hex", hash))
+    }
+}
+
+// Which blocks an `RpcBlockStream` serves.
+enum RpcBlocks {
+    // The headers of the node's best chain from the genesis block up to this height, looked up by
+    // height.
+    HeadersUpTo(u32),
+    // These blocks, in full and in this order.
+    Blocks(Vec<BlockHash>),
+}
+
+// Blocks fetched from a node one at a time and served in the format of a blk file, so that they can
+// be parsed like block files. The parser first reads the headers of the node's best chain, which is
+// all it needs to index them, and then the whole blocks by the hashes it found. If the node's best
+// chain changes in between, the blocks are still the ones whose headers were indexed.
+pub struct RpcBlockStream {
+    client: RpcClient,
+    magic: [u8; 4],
+    blocks: RpcBlocks,
+    // Height of the next header, or index of the next block in `RpcBlocks::Blocks`.
+    next: usize,
+    // The current block, with magic bytes and size in front of it, and how much of it has been read.
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl RpcBlockStream {
+    // Serves the headers of the node's best chain up to `tip_height`.
+    pub fn headers(
+        endpoint: &RpcEndpoint,
+        network: Network,
+        tip_height: u32,
+    ) -> anyhow::Result<RpcBlockStream> {
+        RpcBlockStream::open(endpoint, network, RpcBlocks::HeadersUpTo(tip_height))
+    }
+
+    // Serves the blocks with the given hashes.
+    pub fn blocks(
+        endpoint: &RpcEndpoint,
+        network: Network,
+        hashes: Vec<BlockHash>,
+    ) -> anyhow::Result<RpcBlockStream> {
+        RpcBlockStream::open(endpoint, network, RpcBlocks::Blocks(hashes))
+    }
+
+    fn open(
+        endpoint: &RpcEndpoint,
+        network: Network,
+        blocks: RpcBlocks,
+    ) -> anyhow::Result<RpcBlockStream> {
+        Ok(RpcBlockStream {
+            client: RpcClient::connect(endpoint)?,
+            magic: network.magic(),
+            blocks,
+            next: 0,
+            buffer: Vec::new(),
+            position: 0,
+        })
+    }
+
+    fn is_done(&self) -> bool {
+        match &self.blocks {
+            RpcBlocks::HeadersUpTo(tip_height) => self.next > *tip_height as usize,
+            RpcBlocks::Blocks(hashes) => self.next == hashes.len(),
+        }
+    }
+
+    fn fetch_next(&mut self) -> anyhow::Result<()> {
+        let data = match &self.blocks {
+            RpcBlocks::HeadersUpTo(_) => {
+                let hash = self.client.block_hash(u32::try_from(self.next)?)?;
+                self.client.raw_block_header(&hash)?
+            }
+            RpcBlocks::Blocks(hashes) => {
+                let hash = format!("{:?}", hashes[self.next]);
+                self.client.raw_block(&hash)?
+            }
+        };
+        self.buffer.clear();
+        self.buffer.extend_from_slice(&self.magic);
+        self.buffer
+            .extend_from_slice(&u32::try_from(data.len())?.to_le_bytes());
+        self.buffer.extend_from_slice(&data);
+        self.position = 0;
+        self.next += 1;
+        Ok(())
+    }
+}
+
+impl Read for RpcBlockStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position == self.buffer.len() {
+            if self.is_done() {
+                return Ok(0);
+            }
+            self.fetch_next()
+                .map_err(|e| std::io::Error::other(format!("{:#}", e)))?;
+        }
+        let n = buf.len().min(self.buffer.len() - self.position);
+        buf[..n].copy_from_slice(&self.buffer[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
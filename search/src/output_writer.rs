@@ -1,7 +1,43 @@
 use crate::transaction::{Block, InputOutputPair, Transaction};
 
+// Receives the parsed data. Errors stop the parser, so that a full disk or a failed write doesn't
+// go unnoticed.
 pub trait OutputWriter {
-    fn insert_tx(&mut self, tx: Transaction);
-    fn insert_block(&mut self, b: Block);
-    fn insert_iopair(&mut self, iopair: InputOutputPair);
+    fn insert_tx(&mut self, tx: Transaction) -> anyhow::Result<()>;
+    fn insert_block(&mut self, b: Block) -> anyhow::Result<()>;
+    fn insert_iopair(&mut self, iopair: InputOutputPair) -> anyhow::Result<()>;
+
+    // Called before and after the parser writes what it found in block `b`, so that writers can
+    // group everything a block adds. Iopairs and transactions of other blocks may be written in
+    // between, when the block completes them, and some data is written outside of any block.
+    fn begin_block(&mut self, _b: &Block) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn end_block(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    // Called instead of `insert_iopair` when the output in `iopair` was written out as unspent by an
+    // earlier run, and is now found to be spent by `iopair.dest`. Writers that can't update what
+    // they wrote earlier just add the new iopair.
+    fn spend_output(&mut self, iopair: InputOutputPair) -> anyhow::Result<()> {
+        self.insert_iopair(iopair)
+    }
+
+    // Called when a block that was written earlier leaves the main chain in a reorg. The block, its
+    // transactions and the iopairs of the outputs it created have to be removed, and outputs spent
+    // by its inputs become unspent again.
+    fn retract_block(&mut self, b: Block) -> anyhow::Result<()>;
+
+    // Makes everything written so far visible to readers. Called whenever the parser is done with
+    // the data it has, such as between polls in follow mode.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    // Called once the parser has handed over all its data. Nothing is written after this.
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.flush()
+    }
 }
@@ -0,0 +1,454 @@
+use crate::{
+    network::Network,
+    output_writer::OutputWriter,
+    transaction::{Block, Hash256, InputOutputPair, Transaction},
+};
+use anyhow::{bail, Context};
+use arrow_array::{
+    ArrayRef, BinaryArray, BooleanArray, FixedSizeBinaryArray, Float64Array, RecordBatch,
+    StringArray, UInt32Array, UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::metadata::KeyValue;
+use parquet::file::properties::{EnabledStatistics, WriterProperties};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const TRANSACTIONS_PARQUET_FILE: &str = "transactions.parquet";
+pub const BLOCKS_PARQUET_FILE: &str = "blocks.parquet";
+pub const IOPAIRS_PARQUET_FILE: &str = "input_output_pairs.parquet";
+
+// Rows are collected and handed to the Parquet writer in batches of this size.
+const BATCH_SIZE: usize = 64 * 1024;
+// Every row group has min/max statistics for each of its columns, which lets readers skip row
+// groups that can't match a filter.
+const ROW_GROUP_SIZE: usize = 1024 * 1024;
+const HASH_SIZE: i32 = 32;
+
+// Writes transactions, blocks and iopairs to one Parquet file each, with the same columns as the
+// SQLite tables. Hashes are stored in the byte order they are displayed in, unlike elsewhere.
+pub struct ParquetWriter {
+    txs: Table<Transaction>,
+    blocks: Table<Block>,
+    iopairs: Table<InputOutputPair>,
+}
+
+// A Parquet file and the rows that were not handed to its writer yet.
+struct Table<T> {
+    path: PathBuf,
+    schema: SchemaRef,
+    to_columns: fn(&[T]) -> anyhow::Result<Vec<ArrayRef>>,
+    rows: Vec<T>,
+    // `None` once the file is complete.
+    writer: Option<ArrowWriter<File>>,
+}
+
+impl ParquetWriter {
+    pub fn new(network: Network) -> anyhow::Result<ParquetWriter> {
+        ParquetWriter::in_dir(Path::new(""), network)
+    }
+
+    // Writes the files in `dir`.
+    fn in_dir(dir: &Path, network: Network) -> anyhow::Result<ParquetWriter> {
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(ROW_GROUP_SIZE)
+            .set_statistics_enabled(EnabledStatistics::Chunk)
+            .set_key_value_metadata(Some(vec![KeyValue::new(
+                String::from("network"),
+                String::from(network.name()),
+            )]))
+            .build();
+        Ok(ParquetWriter {
+            txs: Table::create(
+                dir.join(TRANSACTIONS_PARQUET_FILE),
+                vec![
+                    hash_field("id", false),
+                    hash_field("wtxid", false),
+                    Field::new("version", DataType::UInt32, false),
+                    hash_field("block", false),
+                    Field::new("block_height", DataType::UInt32, false),
+                    Field::new("size", DataType::UInt32, false),
+                    Field::new("input_count", DataType::UInt32, false),
+                    Field::new("output_count", DataType::UInt32, false),
+                    Field::new("lock_time", DataType::UInt32, false),
+                    Field::new("weight", DataType::UInt32, false),
+                    Field::new("vsize", DataType::UInt32, false),
+                    Field::new("is_coinbase", DataType::Boolean, false),
+                    Field::new("fee", DataType::UInt64, true),
+                    Field::new("feerate", DataType::Float64, true),
+                ],
+                tx_columns,
+                &properties,
+            )?,
+            blocks: Table::create(
+                dir.join(BLOCKS_PARQUET_FILE),
+                vec![
+                    hash_field("block_hash", false),
+                    Field::new("version", DataType::UInt32, false),
+                    hash_field("prev_block_id", false),
+                    hash_field("merkle_root", false),
+                    Field::new("unix_time", DataType::UInt32, false),
+                    Field::new("tx_count", DataType::UInt32, false),
+                    Field::new("height", DataType::UInt32, false),
+                ],
+                block_columns,
+                &properties,
+            )?,
+            iopairs: Table::create(
+                dir.join(IOPAIRS_PARQUET_FILE),
+                vec![
+                    hash_field("src_tx", false),
+                    hash_field("src_block", false),
+                    Field::new("src_index", DataType::UInt32, false),
+                    Field::new("value", DataType::UInt64, false),
+                    hash_field("dest_tx", true),
+                    hash_field("dest_block", true),
+                    Field::new("dest_index", DataType::UInt32, true),
+                    Field::new("dest_sequence", DataType::UInt32, true),
+                    Field::new("script_type", DataType::Utf8, false),
+                    Field::new("address", DataType::Utf8, true),
+                    Field::new("data", DataType::Binary, true),
+                    Field::new("data_protocol", DataType::Utf8, true),
+                ],
+                iopair_columns,
+                &properties,
+            )?,
+        })
+    }
+}
+
+impl OutputWriter for ParquetWriter {
+    fn insert_tx(&mut self, tx: Transaction) -> anyhow::Result<()> {
+        self.txs.push(tx)
+    }
+
+    fn insert_block(&mut self, b: Block) -> anyhow::Result<()> {
+        self.blocks.push(b)
+    }
+
+    fn insert_iopair(&mut self, iopair: InputOutputPair) -> anyhow::Result<()> {
+        self.iopairs.push(iopair)
+    }
+
+    // Only happens in follow mode, which the Parquet writer is not used with.
+    fn retract_block(&mut self, b: Block) -> anyhow::Result<()> {
+        bail!(
+            "block {:?} can't be retracted, since Parquet files can't be changed once written",
+            b.id
+        )
+    }
+
+    // Parquet files can only be read once they are complete, so this only hands the rows over to
+    // the writers.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.txs.write_rows()?;
+        self.blocks.write_rows()?;
+        self.iopairs.write_rows()
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.txs.close()?;
+        self.blocks.close()?;
+        self.iopairs.close()
+    }
+}
+
+impl<T> Table<T> {
+    fn create(
+        path: PathBuf,
+        fields: Vec<Field>,
+        to_columns: fn(&[T]) -> anyhow::Result<Vec<ArrayRef>>,
+        properties: &WriterProperties,
+    ) -> anyhow::Result<Table<T>> {
+        let schema = Arc::new(Schema::new(fields));
+        let file =
+            File::create(&path).with_context(|| format!("failed to create {}", path.display()))?;
+        let writer = ArrowWriter::try_new(file, schema.clone(), Some(properties.clone()))?;
+        Ok(Table {
+            path,
+            schema,
+            to_columns,
+            rows: Vec::with_capacity(BATCH_SIZE),
+            writer: Some(writer),
+        })
+    }
+
+    fn push(&mut self, row: T) -> anyhow::Result<()> {
+        self.rows.push(row);
+        match self.rows.len() >= BATCH_SIZE {
+            true => self.write_rows(),
+            false => Ok(()),
+        }
+    }
+
+    fn write_rows(&mut self) -> anyhow::Result<()> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+        let writer = match &mut self.writer {
+            Some(w) => w,
+            None => bail!("{} was already completed", self.path.display()),
+        };
+        let batch = RecordBatch::try_new(self.schema.clone(), (self.to_columns)(&self.rows)?)?;
+        writer
+            .write(&batch)
+            .with_context(|| format!("failed to write {}", self.path.display()))?;
+        self.rows.clear();
+        Ok(())
+    }
+
+    // Writes out the remaining rows and the file's footer.
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.write_rows()?;
+        if let Some(w) = self.writer.take() {
+            w.close()
+                .with_context(|| format!("failed to write {}", self.path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+fn hash_field(name: &str, nullable: bool) -> Field {
+    Field::new(name, DataType::FixedSizeBinary(HASH_SIZE), nullable)
+}
+
+fn hashes(hashes: impl Iterator<Item = Hash256>) -> anyhow::Result<ArrayRef> {
+    Ok(Arc::new(FixedSizeBinaryArray::try_from_iter(hashes)?))
+}
+
+fn optional_hashes(hashes: impl Iterator<Item = Option<Hash256>>) -> anyhow::Result<ArrayRef> {
+    Ok(Arc::new(
+        FixedSizeBinaryArray::try_from_sparse_iter_with_size(hashes, HASH_SIZE)?,
+    ))
+}
+
+fn u32s(values: impl Iterator<Item = u32>) -> ArrayRef {
+    Arc::new(UInt32Array::from_iter_values(values))
+}
+
+fn tx_columns(txs: &[Transaction]) -> anyhow::Result<Vec<ArrayRef>> {
+    Ok(vec![
+        hashes(txs.iter().map(|t| t.id.to_display_bytes()))?,
+        hashes(txs.iter().map(|t| t.wtxid.to_display_bytes()))?,
+        u32s(txs.iter().map(|t| t.version)),
+        hashes(txs.iter().map(|t| t.block.to_display_bytes()))?,
+        u32s(txs.iter().map(|t| t.block_height)),
+        u32s(txs.iter().map(|t| t.size)),
+        u32s(txs.iter().map(|t| t.input_count)),
+        u32s(txs.iter().map(|t| t.output_count)),
+        u32s(txs.iter().map(|t| t.lock_time)),
+        u32s(txs.iter().map(|t| t.weight)),
+        u32s(txs.iter().map(|t| t.vsize)),
+        Arc::new(BooleanArray::from_iter(
+            txs.iter().map(|t| Some(t.is_coinbase)),
+        )),
+        Arc::new(UInt64Array::from_iter(txs.iter().map(|t| t.fee))),
+        Arc::new(Float64Array::from_iter(txs.iter().map(|t| t.feerate))),
+    ])
+}
+
+fn block_columns(blocks: &[Block]) -> anyhow::Result<Vec<ArrayRef>> {
+    Ok(vec![
+        hashes(blocks.iter().map(|b| b.id.to_display_bytes()))?,
+        u32s(blocks.iter().map(|b| b.version)),
+        hashes(blocks.iter().map(|b| b.prev_block_id.to_display_bytes()))?,
+        hashes(blocks.iter().map(|b| b.merkle_root.to_display_bytes()))?,
+        u32s(blocks.iter().map(|b| b.unix_time)),
+        u32s(blocks.iter().map(|b| b.tx_count)),
+        u32s(blocks.iter().map(|b| b.height)),
+    ])
+}
+
+fn iopair_columns(iopairs: &[InputOutputPair]) -> anyhow::Result<Vec<ArrayRef>> {
+    let sources = || iopairs.iter().map(|x| &x.source);
+    let dests = || iopairs.iter().map(|x| x.dest.as_ref());
+    Ok(vec![
+        hashes(sources().map(|s| s.src_tx.to_display_bytes()))?,
+        hashes(sources().map(|s| s.src_block.to_display_bytes()))?,
+        u32s(sources().map(|s| s.src_index)),
+        Arc::new(UInt64Array::from_iter_values(sources().map(|s| s.value))),
+        optional_hashes(dests().map(|d| d.map(|d| d.dest_tx.to_display_bytes())))?,
+        optional_hashes(dests().map(|d| d.map(|d| d.dest_block.to_display_bytes())))?,
+        Arc::new(UInt32Array::from_iter(
+            dests().map(|d| d.map(|d| d.dest_index)),
+        )),
+        Arc::new(UInt32Array::from_iter(
+            dests().map(|d| d.map(|d| d.sequence)),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            sources().map(|s| s.script_type.name()),
+        )),
+        Arc::new(StringArray::from_iter(
+            sources().map(|s| s.address.as_deref()),
+        )),
+        Arc::new(BinaryArray::from_iter(sources().map(|s| s.data.as_deref()))),
+        Arc::new(StringArray::from_iter(
+            sources().map(|s| s.data_protocol.map(|p| p.name())),
+        )),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::{DataProtocol, ScriptType};
+    use crate::transaction::{BlockHash, Input, MerkleRoot, Output, TxHash};
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{UInt32Type, UInt64Type};
+    use arrow_array::Array;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    const BLOCK: &str = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+    const TX: &str = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+
+    // Reads back all rows of a Parquet file, and the `network` key of its metadata.
+    fn read(path: &Path) -> (RecordBatch, Option<String>) {
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap()).unwrap();
+        let network = builder
+            .metadata()
+            .file_metadata()
+            .key_value_metadata()
+            .and_then(|kv| kv.iter().find(|kv| kv.key == "network"))
+            .and_then(|kv| kv.value.clone());
+        let batches: Vec<RecordBatch> = builder.build().unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(batches.len(), 1);
+        (batches.into_iter().next().unwrap(), network)
+    }
+
+    fn column_hex(batch: &RecordBatch, name: &str, row: usize) -> Option<String> {
+        let column = batch.column_by_name(name).unwrap().as_fixed_size_binary();
+        match column.is_null(row) {
+            true => None,
+            false => Some(hex::encode(column.value(row))),
+        }
+    }
+
+    #[test]
+    fn writes_rows_that_read_back_the_same() {
+        let block_id = BlockHash::new_from_str(BLOCK);
+        let tx_id = TxHash::new_from_str(TX);
+        let output = |index: u32, value, script_type, data: Option<Vec<u8>>| Output {
+            src_tx: tx_id,
+            src_block: block_id,
+            src_index: index,
+            value,
+            script_type,
+            address: data.is_none().then(|| String::from("bcrt1qexample")),
+            data_protocol: data.as_ref().map(|_| DataProtocol::Hash32Commitment),
+            data,
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = ParquetWriter::in_dir(dir.path(), Network::Regtest).unwrap();
+        writer
+            .insert_block(Block {
+                id: block_id,
+                version: 1,
+                prev_block_id: BlockHash::new([0; 32]),
+                merkle_root: MerkleRoot::new(*tx_id.as_ref()),
+                unix_time: 1231006505,
+                tx_count: 1,
+                height: 0,
+            })
+            .unwrap();
+        writer
+            .insert_tx(Transaction {
+                id: tx_id,
+                wtxid: tx_id,
+                version: 1,
+                block: block_id,
+                block_height: 0,
+                size: 204,
+                input_count: 1,
+                output_count: 2,
+                lock_time: 0,
+                weight: 816,
+                vsize: 204,
+                is_coinbase: true,
+                fee: None,
+                feerate: None,
+            })
+            .unwrap();
+        writer
+            .insert_iopair(InputOutputPair {
+                source: output(0, 50_0000_0000, ScriptType::P2wpkh, None),
+                dest: Some(Input {
+                    dest_tx: tx_id,
+                    dest_block: block_id,
+                    dest_index: 3,
+                    sequence: 0xfffffffd,
+                }),
+            })
+            .unwrap();
+        writer
+            .insert_iopair(InputOutputPair {
+                source: output(1, 0, ScriptType::OpReturn, Some(vec![7; 32])),
+                dest: None,
+            })
+            .unwrap();
+        writer.finish().unwrap();
+
+        let (blocks, network) = read(&dir.path().join(BLOCKS_PARQUET_FILE));
+        assert_eq!(network.as_deref(), Some("regtest"));
+        assert_eq!(blocks.num_rows(), 1);
+        assert_eq!(column_hex(&blocks, "block_hash", 0).unwrap(), BLOCK);
+        assert_eq!(column_hex(&blocks, "merkle_root", 0).unwrap(), TX);
+
+        let (txs, network) = read(&dir.path().join(TRANSACTIONS_PARQUET_FILE));
+        assert_eq!(network.as_deref(), Some("regtest"));
+        assert_eq!(column_hex(&txs, "id", 0).unwrap(), TX);
+        assert_eq!(column_hex(&txs, "block", 0).unwrap(), BLOCK);
+        let output_count = txs.column_by_name("output_count").unwrap();
+        assert_eq!(output_count.as_primitive::<UInt32Type>().value(0), 2);
+        assert!(txs.column_by_name("fee").unwrap().is_null(0));
+        assert!(txs.column_by_name("feerate").unwrap().is_null(0));
+
+        let (iopairs, network) = read(&dir.path().join(IOPAIRS_PARQUET_FILE));
+        assert_eq!(network.as_deref(), Some("regtest"));
+        assert_eq!(iopairs.num_rows(), 2);
+        assert_eq!(column_hex(&iopairs, "src_tx", 1).unwrap(), TX);
+        let value = iopairs.column_by_name("value").unwrap();
+        assert_eq!(value.as_primitive::<UInt64Type>().value(0), 50_0000_0000);
+
+        // The spent output has all dest columns, and the unspent one none of them.
+        assert_eq!(column_hex(&iopairs, "dest_tx", 0).unwrap(), TX);
+        assert_eq!(column_hex(&iopairs, "dest_block", 0).unwrap(), BLOCK);
+        let dest_index = iopairs.column_by_name("dest_index").unwrap();
+        assert_eq!(dest_index.as_primitive::<UInt32Type>().value(0), 3);
+        let dest_sequence = iopairs.column_by_name("dest_sequence").unwrap();
+        assert_eq!(
+            dest_sequence.as_primitive::<UInt32Type>().value(0),
+            0xfffffffd
+        );
+        assert_eq!(column_hex(&iopairs, "dest_tx", 1), None);
+        assert_eq!(column_hex(&iopairs, "dest_block", 1), None);
+        assert!(dest_index.is_null(1));
+        assert!(dest_sequence.is_null(1));
+
+        let strings = |name: &str| {
+            let column = iopairs.column_by_name(name).unwrap().as_string::<i32>();
+            (0..2)
+                .map(|row| (!column.is_null(row)).then(|| column.value(row).to_string()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            strings("script_type"),
+            [Some("p2wpkh".to_string()), Some("op_return".to_string())]
+        );
+        assert_eq!(
+            strings("address"),
+            [Some("bcrt1qexample".to_string()), None]
+        );
+        assert_eq!(
+            strings("data_protocol"),
+            [None, Some("hash32_commitment".to_string())]
+        );
+        let data = iopairs.column_by_name("data").unwrap().as_binary::<i32>();
+        assert!(data.is_null(0));
+        assert_eq!(data.value(1), [7; 32]);
+    }
+}
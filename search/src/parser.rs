@@ -1,354 +1,2204 @@
-use crate::{
-    output_writer::OutputWriter,
-    transaction::{self, Block, Input, InputOutputPair, Output, Transaction, TxHash},
-};
-use nom::{
-    bytes::complete::{tag, take},
-    combinator::cond,
-    number::complete::{le_u16, le_u32, le_u64},
-    sequence::{preceded, tuple},
-    IResult, ToUsize,
-};
-use sha2::Digest;
-use std::collections::HashMap;
-use std::path::Path;
-
-#[derive(Debug, PartialEq, Eq, Hash)]
-struct OutputHashAndIndex {
-    tx: TxHash,
-    index: u32,
-}
-
-pub struct Parser<'p> {
-    // The key is the expected src transaction hash and index corresponding to the input.
-    unmatched_inputs: HashMap<OutputHashAndIndex, transaction::Input>,
-    // The key is the source tx and index of the output.
-    unmatched_outputs: HashMap<OutputHashAndIndex, transaction::Value>,
-
-    // The drainer's relevant function is called on an item whenever it is successfully and fully
-    // parsed.
-    drainer: &'p mut dyn OutputWriter,
-
-    blocks_parsed: u64,
-}
-
-impl<'p> Parser<'p> {
-    pub fn new(drainer: &'p mut dyn OutputWriter) -> Parser<'p> {
-        Parser {
-            unmatched_inputs: HashMap::new(),
-            unmatched_outputs: HashMap::new(),
-
-            drainer,
-
-            blocks_parsed: 0,
-        }
-    }
-
-    pub fn parse_file(&mut self, path: &Path) {
-        let file = std::fs::read(path).unwrap();
-        let file = file.as_slice();
-
-        let mut input = file;
-        loop {
-            input = match take_raw_block_size(input) {
-                Err(_e) => return,
-                Ok((_i, 0)) => return,
-                Ok((i, _s)) => i,
-            };
-
-            let block: Block;
-            let txs: Vec<Transaction>;
-
-            (input, block) = self.parse_block_header_and_tx_count(input).unwrap();
-            self.drainer.insert_block(block);
-            (input, txs) = self.parse_transactions(input, &block).unwrap();
-            for t in txs.into_iter() {
-                self.drainer.insert_tx(t);
-            }
-
-            self.blocks_parsed += 1;
-            if self.blocks_parsed % 500 == 0 {
-                println!("Blocks parsed: {}", self.blocks_parsed);
-            }
-        }
-    }
-
-    pub fn parse(&mut self, num_files: u32) {
-        let mut files: Vec<String> = vec![];
-
-        for i in 0..num_files {
-            files.push(format!(
-                "/Volumes/SavvyT7Red/BitcoinCore/blocks/blk{:05}.dat",
-                i
-            ));
-        }
-
-        for (i, f) in files.iter().enumerate() {
-            println!("Parsing file {} of {}...: {}", i, files.len(), &f);
-            self.parse_file(Path::new(f));
-        }
-
-        self.finalize();
-    }
-
-    // Note that height is not correct when this function returns.
-    fn parse_block_header_and_tx_count<'a, 'b>(
-        &'a mut self,
-        input: &'b [u8],
-    ) -> IResult<&'b [u8], transaction::Block> {
-        let (input, header) = take(80u8)(input)?;
-
-        // hash entire header to get the block ID
-        let id = hash_twice(header);
-
-        let mut parser = tuple((le_u32, take_32_bytes_as_hash, take_32_bytes_as_hash, le_u32));
-        let (_, (version, prev_id, merkle_root, unix_time)) = parser(header)?;
-        let (input, tx_count) = take_varint_fixed(input)?;
-
-        Ok((
-            input,
-            transaction::Block {
-                id: id.into(),
-                version,
-                prev_block_id: prev_id.into(),
-                merkle_root: merkle_root.into(),
-                unix_time,
-                tx_count: tx_count.try_into().unwrap(),
-                height: u32::MAX,
-            },
-        ))
-    }
-
-    fn parse_transaction<'a, 'b>(
-        &mut self,
-        input: &'a [u8],
-        block: &'b transaction::Block,
-    ) -> IResult<&'a [u8], transaction::Transaction> {
-        // Save original input so we can hash everything later
-        let orig_input = input;
-
-        let (input, version) = le_u32(input)?;
-        let (input, input_count) = take_varint_fixed(input)?;
-
-        // Need to deal with the optional witness flag in newer protocols versions if it's there.
-        let witnesses_enabled = input_count == 0;
-        let (input, input_count) =
-            match cond(witnesses_enabled, tuple((take(1u8), take_varint_fixed)))(input)? {
-                (_i, None) => (input, input_count),
-                (i, Some((_, s))) => (i, s),
-            };
-
-        // Take the raw data from the inputs and outputs
-        let (input, tx_input_sources) =
-            nom::multi::count(take_tx_input, input_count.to_usize())(input)?;
-        let (input, output_count) = take_varint_fixed(input)?;
-        let (input, tx_output_values) =
-            nom::multi::count(|x| take_tx_output_value(x), output_count.to_usize())(input)?;
-
-        // Skip witnesses if we need to
-        let input = match cond(witnesses_enabled, |x| {
-            skip_witnesses(x, input_count.to_usize())
-        })(input)?
-        {
-            (_i, None) => input,
-            (i, Some(_)) => i,
-        };
-
-        // Skip the locktime field
-        let (input, _) = le_u32(input)?;
-
-        // Compute size and hash
-        let size = input.as_ptr() as usize - orig_input.as_ptr() as usize;
-        let id = hash_twice(&orig_input[..size]);
-        let size = size as u32;
-
-        // Compute resulting transaction
-        let result = transaction::Transaction {
-            id: id.into(),
-            version,
-            block: block.id,
-            block_height: block.height,
-            size,
-        };
-
-        // For each output and input, register what we parsed
-        for (i, v) in tx_output_values.into_iter().enumerate() {
-            self.register_output(Output {
-                src_tx: id.into(),
-                src_index: i.try_into().unwrap(),
-                value: v,
-            })
-        }
-
-        for (i, v) in tx_input_sources.into_iter().enumerate() {
-            self.register_input(
-                Input {
-                    dest_tx: id.into(),
-                    dest_index: i.try_into().unwrap(),
-                },
-                v.tx,
-                v.index,
-            )
-        }
-
-        Ok((input, result))
-    }
-
-    fn parse_transactions<'a, 'b>(
-        &mut self,
-        input: &'a [u8],
-        block: &'b transaction::Block,
-    ) -> IResult<&'a [u8], Vec<transaction::Transaction>> {
-        nom::multi::count(
-            |i| self.parse_transaction(i, block),
-            block.tx_count.to_usize(),
-        )(input)
-    }
-
-    fn register_input(&mut self, i: Input, expected_src_tx: TxHash, expected_src_index: u32) {
-        let key = OutputHashAndIndex {
-            tx: expected_src_tx,
-            index: expected_src_index,
-        };
-        match self.unmatched_outputs.get(&key) {
-            None => {
-                self.unmatched_inputs.insert(key, i);
-            }
-            Some(output_val) => {
-                self.drainer.insert_iopair(InputOutputPair {
-                    source: Output {
-                        src_tx: expected_src_tx,
-                        src_index: expected_src_index,
-                        value: *output_val,
-                    },
-                    dest: Some(i),
-                });
-                self.unmatched_outputs.remove(&key);
-            }
-        }
-    }
-
-    fn register_output(&mut self, o: Output) {
-        let key = OutputHashAndIndex {
-            tx: o.src_tx,
-            index: o.src_index,
-        };
-        match self.unmatched_inputs.get(&key) {
-            None => {
-                self.unmatched_outputs.insert(key, o.value);
-            }
-            Some(i) => {
-                self.drainer.insert_iopair(InputOutputPair {
-                    source: o,
-                    dest: Some(*i),
-                });
-                self.unmatched_inputs.remove(&key);
-            }
-        }
-    }
-
-    fn finalize(&mut self) {
-        println!(
-            "Finalizing! Writing {} tx outputs without corresponding inputs into the database",
-            self.unmatched_outputs.len(),
-        );
-
-        for (k, v) in self.unmatched_outputs.iter() {
-            self.drainer.insert_iopair(InputOutputPair {
-                source: Output {
-                    src_tx: k.tx,
-                    src_index: k.index,
-                    value: *v,
-                },
-                dest: None,
-            });
-        }
-    }
-}
-
-fn take_raw_block_size(input: &[u8]) -> IResult<&[u8], u32> {
-    // find magic byte sequence and then pull block size
-    let magic: &[u8] = &[0xf9, 0xbe, 0xb4, 0xd9];
-    let magic = tag(magic);
-    preceded(magic, le_u32)(input)
-}
-
-fn take_32_bytes_as_hash(input: &[u8]) -> IResult<&[u8], [u8; 32]> {
-    let (input, data) = take(32u8)(input)?;
-    let res: [u8; 32] = data.try_into().expect("Wrong length; expected 32");
-    Ok((input, res))
-}
-
-// returns a source transaction and index. The index (and tx hash) of the input itself
-// will be taken care of by the calling function.
-fn take_tx_input(input: &[u8]) -> IResult<&[u8], OutputHashAndIndex> {
-    let (input, (src_tx, src_index)) = tuple((take_32_bytes_as_hash, le_u32))(input)?;
-
-    // Skip script and sequence number
-    let (input, sig_len) = take_varint_fixed(input)?;
-    let amt_to_skip = sig_len + 4;
-
-    let (input, _) = take(amt_to_skip)(input)?;
-
-    Ok((
-        input,
-        OutputHashAndIndex {
-            tx: src_tx.into(),
-            index: src_index,
-        },
-    ))
-}
-
-fn take_tx_output_value(input: &[u8]) -> IResult<&[u8], transaction::Value> {
-    let (input, value) = le_u64(input)?;
-
-    // Skip script
-    let (input, sig_len) = take_varint_fixed(input)?;
-    let (input, _) = take(sig_len)(input)?;
-
-    Ok((input, value))
-}
-
-fn skip_single_witness_stack_item(input: &[u8]) -> IResult<&[u8], ()> {
-    let (input, len) = take_varint_fixed(input)?;
-    let (input, _) = take(len)(input)?;
-    Ok((input, ()))
-}
-
-fn skip_single_witness(input: &[u8]) -> IResult<&[u8], ()> {
-    let (input, stack_count) = take_varint_fixed(input)?;
-    let (input, _) =
-        nom::multi::count(skip_single_witness_stack_item, stack_count.to_usize())(input)?;
-    Ok((input, ()))
-}
-
-fn skip_witnesses(input: &[u8], input_count: usize) -> IResult<&[u8], ()> {
-    let (input, _) = nom::multi::count(skip_single_witness, input_count)(input)?;
-    Ok((input, ()))
-}
-
-fn take_varint_fixed(input: &[u8]) -> IResult<&[u8], u64> {
-    let (input, first_byte) = take(1u8)(input)?;
-    let first_byte = first_byte[0];
-    if first_byte < 0xFD {
-        Ok((input, first_byte.into()))
-    } else if first_byte == 0xFD {
-        let (input, val) = le_u16(input)?;
-        Ok(((input), val.into()))
-    } else if first_byte == 0xFE {
-        let (input, val) = le_u32(input)?;
-        Ok(((input), val.into()))
-    } else {
-        return le_u64(input);
-    }
-}
-
-// Hash helpers
-
-pub fn hash_once(x: &[u8]) -> transaction::Hash256 {
-    sha2::Sha256::digest(x).into()
-}
-
-pub fn hash_twice(x: &[u8]) -> transaction::Hash256 {
-    hash_once(&hash_once(x))
-}
+use crate::{
+    block_reader::{BlockReader, ParseError, RawBlock},
+    block_source::{BlockInput, BlockSource},
+    chain::{HeaderChain, MainChain, PowParams, UNKNOWN_HEIGHT},
+    checkpoint::Checkpoint,
+    network::Network,
+    node_rpc::RpcBlockStream,
+    output_writer::OutputWriter,
+    script::{decode_script, op_return_data, DataProtocol, ScriptType},
+    transaction::{
+        self, BlockHash, Input, InputOutputPair, MerkleRoot, Output, TxHash, COINBASE_SOURCE_INDEX,
+        COINBASE_SOURCE_TX,
+    },
+    utxo_store::{MemoryUtxoStore, SavedOutputs, UtxoStore},
+};
+use anyhow::{bail, Context};
+use nom::{
+    bytes::complete::take,
+    combinator::{cond, map_res},
+    error::{context, VerboseError, VerboseErrorKind},
+    number::complete::{le_u16, le_u32, le_u64},
+    sequence::tuple,
+    IResult, ToUsize,
+};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+
+// Bitcoin Core (since v28) obfuscates blk files by XORing every byte at file offset `i` with
+// `key[i % 8]`. The key is stored in `blocks/xor.dat`.
+pub type XorKey = [u8; 8];
+
+pub const XOR_KEY_FILE: &str = "xor.dat";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct OutputHashAndIndex {
+    pub(crate) tx: TxHash,
+    pub(crate) index: u32,
+}
+
+impl OutputHashAndIndex {
+    // Whether this is the null outpoint spent by coinbase inputs.
+    fn is_null(&self) -> bool {
+        self.tx == COINBASE_SOURCE_TX && self.index == COINBASE_SOURCE_INDEX
+    }
+}
+
+// An output that has not been spent yet, keyed by its `OutputHashAndIndex`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnmatchedOutput {
+    // The block of the transaction that created the output. Needed to tell apart outputs of
+    // transactions with the same txid.
+    pub(crate) block: BlockHash,
+    pub(crate) value: transaction::Value,
+    pub(crate) script_type: ScriptType,
+    pub(crate) address: Option<String>,
+    // Whether the output was already written out as unspent, by an earlier run that this one
+    // resumes from. If it gets spent now, the drainer has to update it.
+    pub(crate) written: bool,
+}
+
+impl UnmatchedOutput {
+    fn new(o: Output, written: bool) -> UnmatchedOutput {
+        UnmatchedOutput {
+            block: o.src_block,
+            value: o.value,
+            script_type: o.script_type,
+            address: o.address,
+            written,
+        }
+    }
+
+    fn to_output(&self, key: &OutputHashAndIndex) -> Output {
+        Output {
+            src_tx: key.tx,
+            src_block: self.block,
+            src_index: key.index,
+            value: self.value,
+            script_type: self.script_type,
+            address: self.address.clone(),
+            // Only OP_RETURN outputs carry data, and those are never unmatched.
+            data: None,
+            data_protocol: None,
+        }
+    }
+}
+
+// Results of the nom parsers below. They keep track of what was being parsed when an error happens,
+// so that decode errors can point at the offending part of the block.
+type DecodeResult<'a, T> = IResult<&'a [u8], T, VerboseError<&'a [u8]>>;
+
+// Why a block could not be decoded, or was rejected after decoding it.
+enum DecodeError {
+    Invalid {
+        block: Option<BlockHash>,
+        context: String,
+    },
+    MerkleMismatch {
+        block: BlockHash,
+        expected: MerkleRoot,
+    },
+}
+
+impl DecodeError {
+    // Adds where the block was found.
+    fn at(self, file: &str, offset: u64) -> ParseError {
+        let file = file.to_string();
+        match self {
+            DecodeError::Invalid { block, context } => ParseError::Decode {
+                file,
+                offset,
+                block,
+                context,
+            },
+            DecodeError::MerkleMismatch { block, expected } => ParseError::MerkleMismatch {
+                file,
+                offset,
+                block,
+                expected,
+            },
+        }
+    }
+}
+
+// A block whose transactions have been decoded, but whose inputs and outputs have not been matched
+// up yet.
+struct DecodedBlock {
+    block: transaction::Block,
+    txs: Vec<DecodedTransaction>,
+    // Whether the block is a stale block that is kept because of `StaleBlockPolicy::Mark`. Only the
+    // block and its transactions are written for it; its inputs and outputs are not matched up.
+    stale: bool,
+}
+
+struct DecodedTransaction {
+    tx: transaction::Transaction,
+    inputs: Vec<DecodedInput>,
+    outputs: Vec<DecodedOutput>,
+}
+
+struct DecodedInput {
+    // The output spent by the input.
+    source: OutputHashAndIndex,
+    sequence: u32,
+}
+
+struct DecodedOutput {
+    value: transaction::Value,
+    script_type: ScriptType,
+    address: Option<String>,
+    data: Option<Vec<u8>>,
+    data_protocol: Option<DataProtocol>,
+}
+
+// A transaction that is held back until the outputs spent by all its inputs are known, so that
+// its fee can be computed.
+struct PendingTx {
+    tx: transaction::Transaction,
+    missing_inputs: usize,
+    input_value: transaction::Value,
+    output_value: transaction::Value,
+}
+
+// Number of blocks handed to the thread pool at once.
+const DECODE_BATCH_SIZE: usize = 256;
+
+// In follow mode, reorgs that replace up to this many blocks below the tip can be handled.
+const REORG_DEPTH: u32 = 100;
+
+// Where the data of a block was found, so that it can be read again.
+#[derive(Debug, Clone)]
+struct BlockLocation {
+    input: BlockInput,
+    // Offset of the block's magic bytes in the input.
+    offset: u64,
+}
+
+// What registering a block changed, so that it can be undone when the block leaves the main chain
+// in a reorg.
+struct BlockUndo {
+    block: transaction::Block,
+    location: BlockLocation,
+    // All outputs the block created.
+    created: Vec<OutputHashAndIndex>,
+    // The outputs that inputs of the block spent, as they were before.
+    spent: Vec<(OutputHashAndIndex, UnmatchedOutput)>,
+    // Inputs of the block whose outputs were not known when the block was registered.
+    waiting: Vec<OutputHashAndIndex>,
+}
+
+pub struct Parser<'p> {
+    // The key is the expected src transaction hash and index corresponding to the input.
+    unmatched_inputs: HashMap<OutputHashAndIndex, transaction::Input>,
+    // The key is the source tx and index of the output.
+    unmatched_outputs: Box<dyn UtxoStore>,
+    // Transactions with inputs in `unmatched_inputs`, by txid and block.
+    pending_txs: HashMap<(TxHash, BlockHash), PendingTx>,
+
+    // The drainer's relevant function is called on an item whenever it is successfully and fully
+    // parsed.
+    drainer: &'p mut dyn OutputWriter,
+
+    // The network the block data is from.
+    network: Network,
+
+    // Key used to de-obfuscate block data. If unset, `parse` looks for `xor.dat` next to the block
+    // files.
+    xor_key: Option<XorKey>,
+
+    // All block headers seen so far, and the main chain as reconstructed from them, used to look up
+    // block heights.
+    header_chain: HeaderChain,
+    main_chain: MainChain,
+    stale_block_policy: StaleBlockPolicy,
+    // Rules to validate block headers against, if they should be validated.
+    header_validation: Option<PowParams>,
+    // Whether to check that each block's merkle root matches the txids of its transactions.
+    verify_merkle_roots: bool,
+
+    // Decodes blocks in parallel.
+    thread_pool: rayon::ThreadPool,
+
+    error_policy: ErrorPolicy,
+    // Errors that were skipped over because of `ErrorPolicy::Skip`, in the order they happened.
+    skipped: Vec<ParseError>,
+
+    // For each block file, the offset up to which it has been parsed. Parsing a file starts from
+    // there, which is what allows resuming from a checkpoint.
+    consumed: HashMap<String, u64>,
+
+    // Set while following the chain tip. Blocks near the tip then keep what is needed to retract
+    // them in a reorg, and a block that is cut off at the end of a file is taken to be still being
+    // written.
+    following: bool,
+    // For the main chain blocks at most `REORG_DEPTH` below the tip, what registering them changed.
+    recent_blocks: HashMap<BlockHash, BlockUndo>,
+    // The undo data being collected for the block that is being registered, if it is kept.
+    undo: Option<BlockUndo>,
+    // Blocks that were dropped or retracted while following, in case they become part of the main
+    // chain later.
+    dropped_blocks: HashMap<BlockHash, BlockLocation>,
+    // Number of headers the main chain was last computed from.
+    main_chain_headers: usize,
+
+    blocks_parsed: u64,
+    stale_blocks_dropped: u64,
+}
+
+// What to do when the block data is corrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    // Stop parsing and return the error.
+    Abort,
+    // Skip the affected block, continue at the next network magic bytes, and report what was
+    // skipped at the end. I/O errors still abort.
+    Skip,
+}
+
+// What to do with blocks that are not on the main chain: stale blocks that lost a race, and blocks
+// whose ancestors are not in the parsed data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StaleBlockPolicy {
+    // Skip such blocks entirely, including their transactions and iopairs.
+    Drop,
+    // Keep such blocks and their transactions, with their height set to `UNKNOWN_HEIGHT`. Their
+    // inputs and outputs are left out, since those mostly spend and create the same outputs as
+    // their main chain copies.
+    Mark,
+}
+
+impl<'p> Parser<'p> {
+    pub fn new(drainer: &'p mut dyn OutputWriter) -> Parser<'p> {
+        Parser {
+            unmatched_inputs: HashMap::new(),
+            unmatched_outputs: Box::new(MemoryUtxoStore::default()),
+            pending_txs: HashMap::new(),
+
+            drainer,
+
+            network: Network::Mainnet,
+
+            xor_key: None,
+
+            header_chain: HeaderChain::new(),
+            main_chain: MainChain::default(),
+            stale_block_policy: StaleBlockPolicy::Drop,
+            header_validation: None,
+            verify_merkle_roots: false,
+
+            thread_pool: rayon::ThreadPoolBuilder::new()
+                .build()
+                .expect("failed to start parser threads"),
+
+            error_policy: ErrorPolicy::Abort,
+            skipped: Vec::new(),
+
+            consumed: HashMap::new(),
+
+            following: false,
+            recent_blocks: HashMap::new(),
+            undo: None,
+            dropped_blocks: HashMap::new(),
+            main_chain_headers: 0,
+
+            blocks_parsed: 0,
+            stale_blocks_dropped: 0,
+        }
+    }
+
+    // Sets where outputs are kept until they are spent. By default, they are kept in memory.
+    pub fn set_utxo_store(&mut self, store: Box<dyn UtxoStore>) {
+        self.unmatched_outputs = store;
+    }
+
+    pub fn set_network(&mut self, network: Network) {
+        self.network = network;
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn set_xor_key(&mut self, key: XorKey) {
+        self.xor_key = Some(key);
+    }
+
+    pub fn set_stale_block_policy(&mut self, policy: StaleBlockPolicy) {
+        self.stale_block_policy = policy;
+    }
+
+    // Enables validating the proof of work and timestamps of all block headers against `pow`.
+    // Blocks with invalid headers, and all blocks built on top of them, are left out of the output.
+    pub fn set_header_validation(&mut self, pow: Option<PowParams>) {
+        self.header_validation = pow;
+    }
+
+    // Enables checking merkle roots. Blocks that fail the check are treated like corrupt blocks, as
+    // set by the error policy.
+    pub fn set_verify_merkle_roots(&mut self, verify: bool) {
+        self.verify_merkle_roots = verify;
+    }
+
+    pub fn set_error_policy(&mut self, policy: ErrorPolicy) {
+        self.error_policy = policy;
+    }
+
+    // The errors that were skipped over so far.
+    pub fn skipped(&self) -> &[ParseError] {
+        &self.skipped
+    }
+
+    // Sets the number of threads used to decode blocks. By default, there is one per CPU core.
+    pub fn set_threads(&mut self, threads: usize) -> anyhow::Result<()> {
+        self.thread_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .context("failed to start parser threads")?;
+        Ok(())
+    }
+
+    // Continues from the state of an earlier run. Blocks that the earlier run parsed are skipped,
+    // and outputs it wrote out as unspent are passed to `OutputWriter::spend_output` once they are
+    // spent. The checkpoint has to be from the same network as the one set on the parser.
+    pub fn resume_from(&mut self, checkpoint: Checkpoint) -> anyhow::Result<()> {
+        if checkpoint.network != self.network {
+            bail!(
+                "the checkpoint is for {}, but the parser is set to {}",
+                checkpoint.network,
+                self.network
+            );
+        }
+        for h in checkpoint.headers.iter() {
+            self.header_chain.insert(h.id, h.prev, h.bits, h.time);
+        }
+        self.consumed = checkpoint.consumed;
+        self.unmatched_inputs = checkpoint.unmatched_inputs;
+        match checkpoint.unmatched_outputs {
+            SavedOutputs::InMemory(outputs) => {
+                for (k, v) in outputs.into_iter() {
+                    self.unmatched_outputs.insert(k, v, false)?;
+                }
+            }
+            // The caller has to open the store at `path` and pass it to `set_utxo_store`.
+            SavedOutputs::OnDisk(_) => {}
+        }
+        Ok(())
+    }
+
+    // The state to resume from in a later run.
+    pub fn into_checkpoint(self) -> anyhow::Result<Checkpoint> {
+        Ok(Checkpoint::new(
+            self.network,
+            self.consumed,
+            self.header_chain.stored_headers(),
+            self.unmatched_inputs,
+            self.unmatched_outputs.into_saved()?,
+        ))
+    }
+
+    // Parses a single blk file. Note that heights can only be determined if the file contains the
+    // genesis block.
+    pub fn parse_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        self.parse(&BlockSource::Files(vec![path.to_path_buf()]))
+    }
+
+    // Opens `input` for reading, right after the part of it that was already parsed. Stdin can only
+    // be read once, so it is read from `stdin_spool` instead, which holds a copy of it. Blocks from a
+    // node are fetched by the hashes in `rpc_blocks`. If it is `None`, only the headers of the node's
+    // best chain are fetched, which is all that is needed to index them.
+    fn open_input(
+        &self,
+        input: &BlockInput,
+        stdin_spool: Option<&Path>,
+        rpc_blocks: Option<&[BlockHash]>,
+    ) -> Result<BlockReader<Box<dyn Read>>, ParseError> {
+        let offset = self.consumed_offset(input);
+        let opened = match (input, stdin_spool) {
+            (BlockInput::Stdin, Some(spool)) => {
+                std::fs::File::open(spool).map(|f| Box::new(f) as Box<dyn Read>)
+            }
+            (
+                BlockInput::Rpc {
+                    endpoint,
+                    tip_height,
+                },
+                _,
+            ) => match rpc_blocks {
+                Some(hashes) => RpcBlockStream::blocks(endpoint, self.network, hashes.to_vec()),
+                None => RpcBlockStream::headers(endpoint, self.network, *tip_height),
+            }
+            .map(|s| Box::new(s) as Box<dyn Read>)
+            .map_err(|e| std::io::Error::other(format!("{:#}", e))),
+            _ => input.open_at(offset),
+        };
+        // Blocks from a node are never obfuscated.
+        let xor_key = match input {
+            BlockInput::Rpc { .. } => None,
+            _ => self.xor_key,
+        };
+        match opened {
+            Ok(r) => Ok(BlockReader::new_at(
+                input.name(),
+                r,
+                self.network,
+                xor_key,
+                offset,
+            )),
+            Err(source) => Err(ParseError::Io {
+                file: input.name(),
+                offset,
+                source,
+            }),
+        }
+    }
+
+    // The offset up to which `input` has been parsed.
+    fn consumed_offset(&self, input: &BlockInput) -> u64 {
+        input
+            .checkpoint_key()
+            .and_then(|k| self.consumed.get(&k).copied())
+            .unwrap_or(0)
+    }
+
+    // Returns the next block from `reader` (or only its header if `header_only` is set). Under
+    // `ErrorPolicy::Skip`, corrupt data is skipped until the next block that can be read. Errors are
+    // only recorded when reading full blocks, since headers are read from the same data first.
+    fn next_raw_block<R: Read>(
+        &mut self,
+        reader: &mut BlockReader<R>,
+        header_only: bool,
+    ) -> Result<Option<RawBlock>, ParseError> {
+        loop {
+            let result = match header_only {
+                true => reader.next_header(),
+                false => reader.next_block(),
+            };
+            match result {
+                // Bitcoin Core may be in the middle of writing the last block.
+                Err(ParseError::Truncated { .. }) if self.following => return Ok(None),
+                Err(e) if self.error_policy == ErrorPolicy::Skip && e.is_recoverable() => {
+                    if !header_only {
+                        self.skip(e);
+                    }
+                    reader.resync()?;
+                }
+                r => return r,
+            }
+        }
+    }
+
+    fn skip(&mut self, e: ParseError) {
+        println!("Skipping corrupt data: {}", e);
+        self.skipped.push(e);
+    }
+
+    // Adds the headers of all blocks in `reader` to `chain` without parsing their transactions. If
+    // `ids` is given, the hashes of the blocks are added to it in the order they were read.
+    fn index_headers<R: Read>(
+        &mut self,
+        reader: &mut BlockReader<R>,
+        chain: &mut HeaderChain,
+        mut ids: Option<&mut Vec<BlockHash>>,
+    ) -> Result<(), ParseError> {
+        while let Some(block) = self.next_raw_block(reader, true)? {
+            let id = insert_header(chain, &block.data[..80]);
+            if let Some(ids) = ids.as_mut() {
+                ids.push(id);
+            }
+        }
+        Ok(())
+    }
+
+    // Parses all blocks in `reader` and hands them to the drainer. Blocks are read and decoded in
+    // parallel in batches, and then registered one by one in file order, so the result is the same
+    // as when parsing sequentially.
+    fn parse_blocks<R: Read>(
+        &mut self,
+        reader: &mut BlockReader<R>,
+        input: &BlockInput,
+    ) -> anyhow::Result<()> {
+        let mut batch: Vec<RawBlock> = Vec::with_capacity(DECODE_BATCH_SIZE);
+        loop {
+            batch.clear();
+            while batch.len() < DECODE_BATCH_SIZE {
+                match self.next_raw_block(reader, false)? {
+                    Some(b) => batch.push(b),
+                    None => break,
+                }
+            }
+            if batch.is_empty() {
+                return Ok(());
+            }
+            self.decode_and_register(&batch, input)?;
+        }
+    }
+
+    // Decodes `batch`, which was read from `input`, in parallel, and registers the blocks in order.
+    fn decode_and_register(
+        &mut self,
+        batch: &[RawBlock],
+        input: &BlockInput,
+    ) -> anyhow::Result<()> {
+        let main_chain = &self.main_chain;
+        let policy = self.stale_block_policy;
+        let verify_merkle_root = self.verify_merkle_roots;
+        let network = self.network;
+        let decoded: Vec<Result<Option<DecodedBlock>, DecodeError>> =
+            self.thread_pool.install(|| {
+                batch
+                    .par_iter()
+                    .map(|b| decode_block(&b.data, main_chain, policy, verify_merkle_root, network))
+                    .collect()
+            });
+        for (d, raw) in decoded.into_iter().zip(batch.iter()) {
+            match d {
+                Ok(d) => self.register_block(d, input, raw)?,
+                Err(e) => {
+                    let e = e.at(&input.name(), raw.offset);
+                    match self.error_policy {
+                        ErrorPolicy::Abort => return Err(e.into()),
+                        ErrorPolicy::Skip => self.skip(e),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Hands a decoded block to the drainer and matches up the inputs and outputs of its
+    // transactions. `None` stands for a block that was dropped while decoding. `raw` is the block's
+    // data as read from `input`.
+    fn register_block(
+        &mut self,
+        decoded: Option<DecodedBlock>,
+        input: &BlockInput,
+        raw: &RawBlock,
+    ) -> anyhow::Result<()> {
+        let location = || BlockLocation {
+            input: input.clone(),
+            offset: raw.offset,
+        };
+        let decoded = match decoded {
+            None => {
+                self.stale_blocks_dropped += 1;
+                if self.following {
+                    // Its header may have been appended after the headers were indexed, so make
+                    // sure it is known when the main chain is computed again.
+                    insert_header(&mut self.header_chain, &raw.data[..80]);
+                    self.dropped_blocks
+                        .insert(hash_twice(&raw.data[..80]).into(), location());
+                }
+                return Ok(());
+            }
+            Some(d) => d,
+        };
+
+        if self.following && self.is_recent(decoded.block.height) {
+            self.undo = Some(BlockUndo {
+                block: decoded.block,
+                location: location(),
+                created: Vec::new(),
+                spent: Vec::new(),
+                waiting: Vec::new(),
+            });
+        }
+
+        let stale = decoded.stale;
+        self.drainer.begin_block(&decoded.block)?;
+        self.drainer.insert_block(decoded.block)?;
+        for t in decoded.txs.into_iter() {
+            match stale {
+                true => self.drainer.insert_tx(t.tx)?,
+                false => self.register_transaction(t)?,
+            }
+        }
+        self.drainer.end_block()?;
+        if let Some(u) = self.undo.take() {
+            self.recent_blocks.insert(u.block.id, u);
+        }
+
+        self.blocks_parsed += 1;
+        if self.blocks_parsed.is_multiple_of(500) {
+            println!("Blocks parsed: {}", self.blocks_parsed);
+        }
+        Ok(())
+    }
+
+    // Matches up the inputs and outputs of a transaction, and hands it to the drainer once the
+    // outputs its inputs spend are all known.
+    fn register_transaction(&mut self, t: DecodedTransaction) -> anyhow::Result<()> {
+        let id = t.tx.id;
+        let block = t.tx.block;
+        let output_value = t.outputs.iter().map(|o| o.value).sum();
+
+        // A coinbase input doesn't spend a real output, so link it to a synthetic source right away.
+        // Its value is what the coinbase pays out, which can be less than the subsidy and fees the
+        // miner was allowed to claim. The source has no script.
+        if t.tx.is_coinbase {
+            self.drainer.insert_iopair(InputOutputPair {
+                source: Output {
+                    src_tx: COINBASE_SOURCE_TX,
+                    src_block: block,
+                    src_index: COINBASE_SOURCE_INDEX,
+                    value: output_value,
+                    script_type: ScriptType::Nonstandard,
+                    address: None,
+                    data: None,
+                    data_protocol: None,
+                },
+                dest: Some(Input {
+                    dest_tx: id,
+                    dest_block: block,
+                    dest_index: 0,
+                    sequence: t.inputs[0].sequence,
+                }),
+            })?;
+        }
+
+        // For each output and input, register what we parsed
+        for (i, o) in t.outputs.into_iter().enumerate() {
+            self.register_output(
+                Output {
+                    src_tx: id,
+                    src_block: block,
+                    src_index: i.try_into().unwrap(),
+                    value: o.value,
+                    script_type: o.script_type,
+                    address: o.address,
+                    data: o.data,
+                    data_protocol: o.data_protocol,
+                },
+                t.tx.is_coinbase,
+            )?;
+        }
+
+        // Coinbase inputs were already taken care of above.
+        let inputs_to_register = match t.tx.is_coinbase {
+            true => vec![],
+            false => t.inputs,
+        };
+        let mut pending = PendingTx {
+            tx: t.tx,
+            missing_inputs: 0,
+            input_value: 0,
+            output_value,
+        };
+        for (i, v) in inputs_to_register.into_iter().enumerate() {
+            let value = self.register_input(
+                Input {
+                    dest_tx: id,
+                    dest_block: block,
+                    dest_index: i.try_into().unwrap(),
+                    sequence: v.sequence,
+                },
+                v.source.tx,
+                v.source.index,
+            )?;
+            match value {
+                Some(v) => pending.input_value += v,
+                None => pending.missing_inputs += 1,
+            }
+        }
+        match pending.missing_inputs {
+            0 => self.write_tx(pending)?,
+            _ => {
+                self.pending_txs.insert((id, block), pending);
+            }
+        }
+        Ok(())
+    }
+
+    // Called when the output spent by an input of a pending transaction turns up.
+    fn input_matched(&mut self, i: &Input, value: transaction::Value) -> anyhow::Result<()> {
+        let key = (i.dest_tx, i.dest_block);
+        if let Some(p) = self.pending_txs.get_mut(&key) {
+            p.input_value += value;
+            p.missing_inputs -= 1;
+            if p.missing_inputs == 0 {
+                let p = self.pending_txs.remove(&key).unwrap();
+                self.write_tx(p)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Fills in the fee of a transaction, if its inputs were all found, and hands it to the drainer.
+    fn write_tx(&mut self, p: PendingTx) -> anyhow::Result<()> {
+        let mut tx = p.tx;
+        if !tx.is_coinbase && p.missing_inputs == 0 {
+            tx.fee = p.input_value.checked_sub(p.output_value);
+            tx.feerate = tx.fee.map(|f| f as f64 / tx.vsize as f64);
+        }
+        self.drainer.insert_tx(tx)
+    }
+
+    // Parses everything in `source`. This makes two passes over the data: the first one collects
+    // all block headers to reconstruct the main chain, and the second one parses the blocks with
+    // their heights known. Blocks are read one at a time, so inputs of any size can be parsed.
+    pub fn parse(&mut self, source: &BlockSource) -> anyhow::Result<()> {
+        if self.xor_key.is_none() {
+            if let Some(dir) = source.blocks_dir()? {
+                self.xor_key = read_xor_key(&dir.join(XOR_KEY_FILE))?;
+            }
+        }
+        if let Some(key) = self.xor_key {
+            println!(
+                "De-obfuscating block data with XOR key {}",
+                hex::encode(key)
+            );
+        }
+
+        let inputs = source.inputs()?;
+
+        // The data is read twice, once to find the main chain and once to parse it, so stdin is
+        // copied to a temporary file first.
+        let stdin_spool = match inputs.contains(&BlockInput::Stdin) {
+            true => Some(spool_stdin()?),
+            false => None,
+        };
+        let stdin_spool = stdin_spool.as_ref().map(|f| f.path());
+
+        // Headers are collected into `self.header_chain`, which may already hold the headers from a
+        // checkpoint. The hashes of the blocks whose headers came from a node are kept, so that the
+        // second pass fetches the same blocks even if the node's best chain changes in between.
+        let mut chain = std::mem::take(&mut self.header_chain);
+        let mut rpc_blocks = Vec::new();
+        for (i, input) in inputs.iter().enumerate() {
+            println!(
+                "Indexing headers in file {} of {}...: {}",
+                i,
+                inputs.len(),
+                input.name()
+            );
+            let mut reader = self.open_input(input, stdin_spool, None)?;
+            let ids = matches!(input, BlockInput::Rpc { .. }).then_some(&mut rpc_blocks);
+            self.index_headers(&mut reader, &mut chain, ids)?;
+        }
+
+        self.main_chain = chain.main_chain(
+            &self.network.genesis_hash(),
+            self.header_validation.as_ref(),
+        );
+        self.main_chain_headers = chain.len();
+        let invalid = self.main_chain.invalid_headers();
+        if !invalid.is_empty() {
+            println!(
+                "Excluding {} blocks whose headers failed validation, and their descendants:",
+                invalid.len()
+            );
+            for h in invalid.iter() {
+                println!("  block {:?} at height {}: {}", h.block, h.height, h.reason);
+            }
+        }
+        match self.main_chain.tip_height() {
+            Some(h) => println!(
+                "Found {} block headers; main chain height is {}, {} blocks are stale or orphaned",
+                chain.len(),
+                h,
+                chain.len() - self.main_chain.len()
+            ),
+            None => println!(
+                "Found {} block headers but no usable {} genesis block; block heights are unknown",
+                chain.len(),
+                self.network
+            ),
+        }
+        self.header_chain = chain;
+
+        for (i, input) in inputs.iter().enumerate() {
+            println!(
+                "Parsing file {} of {}...: {}",
+                i,
+                inputs.len(),
+                input.name()
+            );
+            let mut reader = self.open_input(input, stdin_spool, Some(&rpc_blocks))?;
+            let skipped_before = self.skipped.len();
+            self.parse_blocks(&mut reader, input)?;
+            if let Some(k) = input.checkpoint_key() {
+                self.consumed.insert(k, reader.end_of_last_block());
+            }
+            // Blocks are read ahead of decoding them, so errors may have been recorded out of order.
+            self.skipped[skipped_before..].sort_by_key(|e| e.offset());
+        }
+
+        if self.stale_blocks_dropped > 0 {
+            println!(
+                "Dropped {} blocks that are not on the main chain",
+                self.stale_blocks_dropped
+            );
+        }
+
+        if !self.skipped.is_empty() {
+            println!(
+                "Skipped {} corrupt blocks or regions of block data:",
+                self.skipped.len()
+            );
+            for e in self.skipped.iter() {
+                println!("  {}", e);
+            }
+        }
+
+        self.finalize()?;
+        self.prune_recent_blocks();
+        Ok(())
+    }
+
+    // Parses everything in `source` like `parse`, and then keeps watching its block files for new
+    // blocks, checking every `interval`. Blocks that leave the main chain in a reorg are retracted
+    // from the output, and blocks that join it are added. Only returns if an error happens.
+    pub fn follow(&mut self, source: &BlockSource, interval: Duration) -> anyhow::Result<()> {
+        self.start_following(source)?;
+        println!("Following the block files for new blocks...");
+        loop {
+            std::thread::sleep(interval);
+            self.parse_new_blocks(source)?;
+        }
+    }
+
+    // Parses everything in `source` like `parse`, but keeps what is needed to retract the blocks
+    // near the tip, so that `parse_new_blocks` can be called afterwards.
+    pub(crate) fn start_following(&mut self, source: &BlockSource) -> anyhow::Result<()> {
+        if matches!(source, BlockSource::Stdin | BlockSource::Rpc(_)) {
+            bail!("only block files can be followed");
+        }
+        // A marked stale block may join the main chain later, and would then have to be replaced
+        // by a fully registered copy.
+        if self.stale_block_policy == StaleBlockPolicy::Mark {
+            bail!("stale blocks can't be marked while following, only dropped");
+        }
+        self.following = true;
+        self.parse(source)
+    }
+
+    // Parses the blocks that were appended to the block files of `source` since they were last
+    // looked at, and updates the output to the current main chain.
+    pub(crate) fn parse_new_blocks(&mut self, source: &BlockSource) -> anyhow::Result<()> {
+        // Only files that grew can hold new blocks. Bitcoin Core preallocates the file it is writing
+        // to, so that one is always looked at.
+        let mut new_blocks: Vec<(BlockInput, Vec<RawBlock>)> = Vec::new();
+        for input in source.inputs()?.into_iter() {
+            let size = match &input {
+                BlockInput::File(p) => std::fs::metadata(p).map(|m| m.len()).unwrap_or(0),
+                _ => 0,
+            };
+            if size <= self.consumed_offset(&input) {
+                continue;
+            }
+            let mut reader = self.open_input(&input, None, Some(&[]))?;
+            let mut blocks = Vec::new();
+            while let Some(b) = self.next_raw_block(&mut reader, false)? {
+                blocks.push(b);
+            }
+            if let Some(k) = input.checkpoint_key() {
+                self.consumed.insert(k, reader.end_of_last_block());
+            }
+            if !blocks.is_empty() {
+                new_blocks.push((input, blocks));
+            }
+        }
+        for (_, blocks) in new_blocks.iter() {
+            for b in blocks.iter() {
+                insert_header(&mut self.header_chain, &b.data[..80]);
+            }
+        }
+        if new_blocks.is_empty() && self.header_chain.len() == self.main_chain_headers {
+            return Ok(());
+        }
+
+        let old_tip = self.main_chain.tip();
+        self.main_chain = self.header_chain.main_chain(
+            &self.network.genesis_hash(),
+            self.header_validation.as_ref(),
+        );
+        self.main_chain_headers = self.header_chain.len();
+        if let Some(tip) = old_tip {
+            self.retract_stale_blocks(tip)?;
+        }
+
+        let blocks_before = self.blocks_parsed;
+        for (input, blocks) in new_blocks.iter() {
+            self.decode_and_register(blocks, input)?;
+        }
+
+        // Blocks that were read before, but only joined the main chain now.
+        let mut joined: Vec<(u32, BlockHash)> = self
+            .dropped_blocks
+            .keys()
+            .filter_map(|id| Some((self.main_chain.height(id)?, *id)))
+            .collect();
+        joined.sort_unstable();
+        for (_, id) in joined.into_iter() {
+            let location = self.dropped_blocks.remove(&id).unwrap();
+            let raw = self.read_block_at(&location)?;
+            self.decode_and_register(std::slice::from_ref(&raw), &location.input)?;
+        }
+
+        println!(
+            "Parsed {} new blocks; main chain height is {}",
+            self.blocks_parsed - blocks_before,
+            self.main_chain
+                .tip_height()
+                .map(|h| h.to_string())
+                .unwrap_or_else(|| String::from("unknown"))
+        );
+        self.finalize()?;
+        self.prune_recent_blocks();
+        Ok(())
+    }
+
+    // Reads the block at `location` again.
+    fn read_block_at(&self, location: &BlockLocation) -> anyhow::Result<RawBlock> {
+        let input = &location.input;
+        let reader = input
+            .open_at(location.offset)
+            .map_err(|source| ParseError::Io {
+                file: input.name(),
+                offset: location.offset,
+                source,
+            })?;
+        let mut reader = BlockReader::new_at(
+            input.name(),
+            reader,
+            self.network,
+            self.xor_key,
+            location.offset,
+        );
+        match reader.next_block()? {
+            Some(b) => Ok(b),
+            None => bail!(
+                "block in {} at offset {} has disappeared",
+                input.name(),
+                location.offset
+            ),
+        }
+    }
+
+    // Whether a main chain block at `height` is close enough to the tip to keep its undo data.
+    fn is_recent(&self, height: u32) -> bool {
+        height != UNKNOWN_HEIGHT
+            && self
+                .main_chain
+                .tip_height()
+                .is_some_and(|tip| height + REORG_DEPTH >= tip)
+    }
+
+    fn prune_recent_blocks(&mut self) {
+        let tip = self.main_chain.tip_height().unwrap_or_default();
+        self.recent_blocks
+            .retain(|_, u| u.block.height + REORG_DEPTH >= tip);
+    }
+
+    // Retracts the blocks of the previous main chain, which ended in `old_tip`, that are not part
+    // of the main chain anymore, starting at the tip.
+    fn retract_stale_blocks(&mut self, old_tip: BlockHash) -> anyhow::Result<()> {
+        let mut id = old_tip;
+        let mut retracted = 0;
+        while self.main_chain.height(&id).is_none() {
+            let undo = match self.recent_blocks.remove(&id) {
+                Some(u) => u,
+                None => bail!(
+                    "block {:?} left the main chain, but only reorgs of up to {} blocks can be handled; parse the data again from scratch",
+                    id,
+                    REORG_DEPTH
+                ),
+            };
+            id = undo.block.prev_block_id;
+            self.retract_block(undo)?;
+            retracted += 1;
+        }
+        if retracted > 0 {
+            println!(
+                "Reorg: retracted {} blocks that left the main chain",
+                retracted
+            );
+        }
+        Ok(())
+    }
+
+    // Undoes registering a block. Blocks have to be retracted in reverse chain order, so that
+    // outputs the block spent are only put back after their own blocks were restored.
+    fn retract_block(&mut self, undo: BlockUndo) -> anyhow::Result<()> {
+        let id = undo.block.id;
+        self.drainer.retract_block(undo.block)?;
+
+        // The writer now has these outputs as unspent.
+        for (key, o) in undo.spent.into_iter() {
+            self.unmatched_outputs
+                .insert(key, UnmatchedOutput { written: true, ..o }, false)?;
+        }
+        // This also takes back outputs that were spent within the block itself.
+        for key in undo.created.iter() {
+            if let Some(o) = self.unmatched_outputs.remove(key)? {
+                if o.block != id {
+                    self.unmatched_outputs.insert(*key, o, false)?;
+                }
+            }
+        }
+        self.pending_txs.retain(|(_, block), _| *block != id);
+        for key in undo.waiting.iter() {
+            if self
+                .unmatched_inputs
+                .get(key)
+                .is_some_and(|i| i.dest_block == id)
+            {
+                self.unmatched_inputs.remove(key);
+            }
+        }
+
+        self.blocks_parsed -= 1;
+        self.dropped_blocks.insert(id, undo.location);
+        Ok(())
+    }
+
+    // Returns the value of the spent output, or `None` if it is not known yet.
+    fn register_input(
+        &mut self,
+        i: Input,
+        expected_src_tx: TxHash,
+        expected_src_index: u32,
+    ) -> anyhow::Result<Option<transaction::Value>> {
+        let key = OutputHashAndIndex {
+            tx: expected_src_tx,
+            index: expected_src_index,
+        };
+        match self.unmatched_outputs.remove(&key)? {
+            None => {
+                self.unmatched_inputs.insert(key, i);
+                if let Some(u) = &mut self.undo {
+                    u.waiting.push(key);
+                }
+                Ok(None)
+            }
+            Some(o) => {
+                let iopair = InputOutputPair {
+                    source: o.to_output(&key),
+                    dest: Some(i),
+                };
+                let written = o.written;
+                if let Some(u) = &mut self.undo {
+                    u.spent.push((key, o));
+                }
+                let value = iopair.source.value;
+                match written {
+                    true => self.drainer.spend_output(iopair)?,
+                    false => self.drainer.insert_iopair(iopair)?,
+                }
+                Ok(Some(value))
+            }
+        }
+    }
+
+    // `coinbase` tells whether the output belongs to a coinbase, which is the only kind of transaction
+    // whose txid can be the same as an earlier one's.
+    fn register_output(&mut self, o: Output, coinbase: bool) -> anyhow::Result<()> {
+        // OP_RETURN outputs can never be spent, so like Bitcoin Core, don't keep them with the
+        // unspent outputs.
+        if o.script_type == ScriptType::OpReturn {
+            return self.drainer.insert_iopair(InputOutputPair {
+                source: o,
+                dest: None,
+            });
+        }
+
+        let key = OutputHashAndIndex {
+            tx: o.src_tx,
+            index: o.src_index,
+        };
+        if let Some(u) = &mut self.undo {
+            u.created.push(key);
+        }
+        match self.unmatched_inputs.remove(&key) {
+            None => {
+                let new = UnmatchedOutput::new(o, false);
+                if let Some(existing) = self.unmatched_outputs.insert(key, new.clone(), coinbase)? {
+                    self.resolve_duplicate_output(key, existing, new)?;
+                }
+            }
+            Some(i) => {
+                // The input's block was registered first, so its undo data has to learn about the
+                // output it spends.
+                if let Some(u) = self.recent_blocks.get_mut(&i.dest_block) {
+                    u.spent.push((key, UnmatchedOutput::new(o.clone(), true)));
+                }
+                let value = o.value;
+                self.drainer.insert_iopair(InputOutputPair {
+                    source: o,
+                    dest: Some(i),
+                })?;
+                self.input_matched(&i, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Called when an unspent output is created a second time, which happens when two transactions
+    // share a txid. Bitcoin Core lets the later one overwrite the earlier one (this was made invalid
+    // by BIP 30, but two such pairs of coinbases made it into the main chain before that), so only
+    // the later output can ever be spent. The earlier one is written out as unspent right away.
+    fn resolve_duplicate_output(
+        &mut self,
+        key: OutputHashAndIndex,
+        existing: UnmatchedOutput,
+        new: UnmatchedOutput,
+    ) -> anyhow::Result<()> {
+        // Blocks are not necessarily parsed in chain order, so use their heights to tell which of
+        // the two came first. Without heights, assume parse order.
+        let existing_height = self.main_chain.height(&existing.block);
+        let new_height = self.main_chain.height(&new.block);
+        let overwritten = match (existing_height, new_height) {
+            (Some(e), Some(n)) if n < e => {
+                self.unmatched_outputs.insert(key, existing, true)?;
+                new
+            }
+            _ => existing,
+        };
+
+        println!(
+            "Found duplicate txid {:?}; output {} in block {:?} was overwritten and is unspendable",
+            key.tx, key.index, overwritten.block
+        );
+        if !overwritten.written {
+            self.drainer.insert_iopair(InputOutputPair {
+                source: overwritten.to_output(&key),
+                dest: None,
+            })?;
+        }
+        Ok(())
+    }
+
+    // Writes out all outputs that are still unspent. They stay around as unmatched outputs, so that a
+    // later run resuming from a checkpoint can still match them up. Then has the drainer finish
+    // writing, or just flush if more blocks are coming in follow mode.
+    fn finalize(&mut self) -> anyhow::Result<()> {
+        println!("Finalizing! Writing tx outputs without corresponding inputs into the database");
+
+        let drainer = &mut self.drainer;
+        let mut written = 0;
+        self.unmatched_outputs.write_unwritten(&mut |k, v| {
+            drainer.insert_iopair(InputOutputPair {
+                source: v.to_output(k),
+                dest: None,
+            })?;
+            written += 1;
+            Ok(())
+        })?;
+        println!("Wrote {} unspent tx outputs", written);
+
+        // The outputs these spend were not in the parsed data, so their fee can't be computed.
+        let pending = std::mem::take(&mut self.pending_txs);
+        if !pending.is_empty() {
+            println!(
+                "Wrote {} transactions whose inputs were not all found, without a fee",
+                pending.len()
+            );
+        }
+        for p in pending.into_values() {
+            self.write_tx(p)?;
+        }
+
+        match self.following {
+            true => self.drainer.flush(),
+            false => self.drainer.finish(),
+        }
+    }
+}
+
+// Decodes a block and all its transactions, without registering their inputs and outputs. This
+// only depends on the block itself (and the already known main chain), so blocks can be decoded in
+// parallel. Returns `None` if the block is not on the main chain and `policy` says to drop it, or if
+// it was excluded by header validation.
+fn decode_block(
+    data: &[u8],
+    main_chain: &MainChain,
+    policy: StaleBlockPolicy,
+    verify_merkle_root: bool,
+    network: Network,
+) -> Result<Option<DecodedBlock>, DecodeError> {
+    let (input, mut block) =
+        parse_block_header_and_tx_count(data).map_err(|e| DecodeError::Invalid {
+            block: data.get(..80).map(|h| hash_twice(h).into()),
+            context: describe_decode_error(data, e),
+        })?;
+
+    // Stale blocks mostly contain transactions that also made it into the main chain, so their
+    // inputs and outputs must not be registered. Without a known main chain, no block can be told
+    // to be stale.
+    let stale = match main_chain.height(&block.id) {
+        Some(h) => {
+            block.height = h;
+            false
+        }
+        None if main_chain.is_excluded(&block.id) => return Ok(None),
+        None if main_chain.is_known() => match policy {
+            StaleBlockPolicy::Drop => return Ok(None),
+            StaleBlockPolicy::Mark => true,
+        },
+        None => false,
+    };
+
+    let mut input = input;
+    let mut txs = Vec::with_capacity(block.tx_count.to_usize().min(input.len()));
+    for i in 0..block.tx_count {
+        let (rest, tx) =
+            decode_transaction(input, &block, network).map_err(|e| DecodeError::Invalid {
+                block: Some(block.id),
+                context: format!("transaction {}: {}", i, describe_decode_error(data, e)),
+            })?;
+        input = rest;
+        txs.push(tx);
+    }
+
+    if verify_merkle_root {
+        let computed: MerkleRoot =
+            merkle_root(txs.iter().map(|t| *t.tx.id.as_ref()).collect()).into();
+        if computed != block.merkle_root {
+            return Err(DecodeError::MerkleMismatch {
+                block: block.id,
+                expected: block.merkle_root,
+            });
+        }
+    }
+
+    Ok(Some(DecodedBlock { block, txs, stale }))
+}
+
+// Turns a nom error into a message such as "Eof at byte 1234 in transaction input", where the byte
+// position is relative to the start of `block`.
+fn describe_decode_error(block: &[u8], e: nom::Err<VerboseError<&[u8]>>) -> String {
+    let e = match e {
+        nom::Err::Error(e) | nom::Err::Failure(e) => e,
+        nom::Err::Incomplete(_) => return String::from("unexpected end of data"),
+    };
+
+    let mut result = String::new();
+    for (input, kind) in e.errors.iter() {
+        let position = block.len() - input.len();
+        match kind {
+            VerboseErrorKind::Context(c) if result.is_empty() => {
+                result = format!("invalid {} at byte {}", c, position)
+            }
+            VerboseErrorKind::Context(c) => result = format!("{} in {}", result, c),
+            VerboseErrorKind::Nom(k) if result.is_empty() => {
+                result = format!("{:?} at byte {}", k, position)
+            }
+            VerboseErrorKind::Nom(_) | VerboseErrorKind::Char(_) => {}
+        }
+    }
+    result
+}
+
+// Note that height is not correct when this function returns.
+fn parse_block_header_and_tx_count(input: &[u8]) -> DecodeResult<'_, transaction::Block> {
+    let (input, header) = context("block header", take(80u8))(input)?;
+
+    // hash entire header to get the block ID
+    let id = hash_twice(header);
+
+    let mut parser = tuple((le_u32, take_32_bytes_as_hash, take_32_bytes_as_hash, le_u32));
+    let (_, (version, prev_id, merkle_root, unix_time)) = parser(header)?;
+    let (input, tx_count) = context(
+        "transaction count",
+        map_res(take_varint_fixed, u32::try_from),
+    )(input)?;
+
+    Ok((
+        input,
+        transaction::Block {
+            id: id.into(),
+            version,
+            prev_block_id: prev_id.into(),
+            merkle_root: merkle_root.into(),
+            unix_time,
+            tx_count,
+            height: UNKNOWN_HEIGHT,
+        },
+    ))
+}
+
+fn decode_transaction<'a>(
+    input: &'a [u8],
+    block: &transaction::Block,
+    network: Network,
+) -> DecodeResult<'a, DecodedTransaction> {
+    // Save original input so we can hash everything later
+    let orig_input = input;
+
+    let (input, version) = le_u32(input)?;
+    let after_version = input;
+    let (input, input_count) = context("input count", take_varint_fixed)(input)?;
+
+    // Need to deal with the optional witness flag in newer protocols versions if it's there.
+    let witnesses_enabled = input_count == 0;
+    let (input, input_count) =
+        match cond(witnesses_enabled, tuple((take(1u8), take_varint_fixed)))(input)? {
+            (_i, None) => (input, input_count),
+            (i, Some((_, s))) => (i, s),
+        };
+
+    // The txid only commits to the serialization without the witness marker, flag and witnesses
+    // (BIP 144), so keep track of where the inputs and outputs start and end.
+    let inputs_start = match witnesses_enabled {
+        true => &after_version[2..],
+        false => after_version,
+    };
+
+    // Take the raw data from the inputs and outputs
+    let (input, tx_inputs) = nom::multi::count(
+        context("transaction input", take_tx_input),
+        input_count.to_usize(),
+    )(input)?;
+    let (input, output_count) = context("output count", take_varint_fixed)(input)?;
+    let (input, tx_outputs) = nom::multi::count(
+        context("transaction output", take_tx_output),
+        output_count.to_usize(),
+    )(input)?;
+    let outputs_end = input;
+
+    // Skip witnesses if we need to
+    let input = match cond(
+        witnesses_enabled,
+        context("witness", |x| skip_witnesses(x, input_count.to_usize())),
+    )(input)?
+    {
+        (_i, None) => input,
+        (i, Some(_)) => i,
+    };
+
+    let (input, lock_time) = context("lock time", take(4u8))(input)?;
+
+    // Compute size and hashes. Without witnesses, the txid and wtxid are the same.
+    let size = input.as_ptr() as usize - orig_input.as_ptr() as usize;
+    let wtxid = hash_twice(&orig_input[..size]);
+    let id = match witnesses_enabled {
+        true => hash_twice_parts(&[
+            &orig_input[..4],
+            &inputs_start[..inputs_start.len() - outputs_end.len()],
+            lock_time,
+        ]),
+        false => wtxid,
+    };
+    let base_size = 4 + inputs_start.len() - outputs_end.len() + lock_time.len();
+    let weight = (3 * base_size + size) as u32;
+    let size = size as u32;
+
+    let is_coinbase = tx_inputs.len() == 1 && tx_inputs[0].source.is_null();
+
+    // Compute resulting transaction
+    let result = transaction::Transaction {
+        id: id.into(),
+        wtxid: wtxid.into(),
+        version,
+        block: block.id,
+        block_height: block.height,
+        size,
+        input_count: tx_inputs.len().try_into().unwrap(),
+        output_count: tx_outputs.len().try_into().unwrap(),
+        lock_time: u32::from_le_bytes(lock_time.try_into().unwrap()),
+        weight,
+        vsize: weight.div_ceil(4),
+        is_coinbase,
+        fee: None,
+        feerate: None,
+    };
+
+    Ok((
+        input,
+        DecodedTransaction {
+            tx: result,
+            inputs: tx_inputs,
+            outputs: tx_outputs
+                .into_iter()
+                .map(|(value, script)| {
+                    let (script_type, address) = decode_script(script, network);
+                    let (data, data_protocol) = match script_type {
+                        ScriptType::OpReturn => {
+                            let (data, protocol) = op_return_data(script);
+                            (Some(data), protocol)
+                        }
+                        _ => (None, None),
+                    };
+                    DecodedOutput {
+                        value,
+                        script_type,
+                        address,
+                        data,
+                        data_protocol,
+                    }
+                })
+                .collect(),
+        },
+    ))
+}
+
+// Adds a raw 80-byte block header to `chain` and returns the block's hash.
+fn insert_header(chain: &mut HeaderChain, header: &[u8]) -> BlockHash {
+    let id: BlockHash = hash_twice(header).into();
+    let prev_id = BlockHash::new(header[4..36].try_into().unwrap());
+    let time = u32::from_le_bytes(header[68..72].try_into().unwrap());
+    let bits = u32::from_le_bytes(header[72..76].try_into().unwrap());
+    chain.insert(id, prev_id, bits, time);
+    id
+}
+
+// Copies all of stdin to a temporary file, which is deleted when the returned handle is dropped.
+fn spool_stdin() -> anyhow::Result<tempfile::NamedTempFile> {
+    let mut spool = tempfile::NamedTempFile::new()
+        .context("failed to create a temporary file for the data on stdin")?;
+    std::io::copy(&mut std::io::stdin().lock(), &mut spool)
+        .context("failed to copy the data on stdin to a temporary file")?;
+    Ok(spool)
+}
+
+// Reads a Bitcoin Core XOR key file. Returns `None` if the file does not exist or holds the all-zero
+// key, which means the block files are not obfuscated.
+pub fn read_xor_key(path: &Path) -> anyhow::Result<Option<XorKey>> {
+    let data = match std::fs::read(path) {
+        Ok(d) => d,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e).with_context(|| format!("failed to read XOR key {}", path.display()))
+        }
+    };
+    let key: XorKey = match data.as_slice().try_into() {
+        Ok(k) => k,
+        Err(_) => bail!(
+            "XOR key {} should be 8 bytes long but is {} bytes",
+            path.display(),
+            data.len()
+        ),
+    };
+
+    Ok(match key == XorKey::default() {
+        true => None,
+        false => Some(key),
+    })
+}
+
+fn take_32_bytes_as_hash(input: &[u8]) -> DecodeResult<'_, [u8; 32]> {
+    let (input, data) = take(32u8)(input)?;
+    let res: [u8; 32] = data.try_into().expect("Wrong length; expected 32");
+    Ok((input, res))
+}
+
+// returns a source transaction and index, and the sequence number. The index (and tx hash) of the
+// input itself will be taken care of by the calling function.
+fn take_tx_input(input: &[u8]) -> DecodeResult<'_, DecodedInput> {
+    let (input, (src_tx, src_index)) = tuple((take_32_bytes_as_hash, le_u32))(input)?;
+
+    // Skip script
+    let (input, sig_len) = take_varint_fixed(input)?;
+    let (input, _) = take(sig_len)(input)?;
+
+    let (input, sequence) = le_u32(input)?;
+
+    Ok((
+        input,
+        DecodedInput {
+            source: OutputHashAndIndex {
+                tx: src_tx.into(),
+                index: src_index,
+            },
+            sequence,
+        },
+    ))
+}
+
+// Returns the value and the scriptPubKey of an output.
+fn take_tx_output(input: &[u8]) -> DecodeResult<'_, (transaction::Value, &[u8])> {
+    let (input, value) = le_u64(input)?;
+
+    let (input, script_len) = take_varint_fixed(input)?;
+    let (input, script) = take(script_len)(input)?;
+
+    Ok((input, (value, script)))
+}
+
+fn skip_single_witness_stack_item(input: &[u8]) -> DecodeResult<'_, ()> {
+    let (input, len) = take_varint_fixed(input)?;
+    let (input, _) = take(len)(input)?;
+    Ok((input, ()))
+}
+
+fn skip_single_witness(input: &[u8]) -> DecodeResult<'_, ()> {
+    let (input, stack_count) = take_varint_fixed(input)?;
+    let (input, _) =
+        nom::multi::count(skip_single_witness_stack_item, stack_count.to_usize())(input)?;
+    Ok((input, ()))
+}
+
+fn skip_witnesses(input: &[u8], input_count: usize) -> DecodeResult<'_, ()> {
+    let (input, _) = nom::multi::count(skip_single_witness, input_count)(input)?;
+    Ok((input, ()))
+}
+
+fn take_varint_fixed(input: &[u8]) -> DecodeResult<'_, u64> {
+    let (input, first_byte) = take(1u8)(input)?;
+    let first_byte = first_byte[0];
+    if first_byte < 0xFD {
+        Ok((input, first_byte.into()))
+    } else if first_byte == 0xFD {
+        let (input, val) = le_u16(input)?;
+        Ok(((input), val.into()))
+    } else if first_byte == 0xFE {
+        let (input, val) = le_u32(input)?;
+        Ok(((input), val.into()))
+    } else {
+        le_u64(input)
+    }
+}
+
+// Hash helpers
+
+pub fn hash_once(x: &[u8]) -> transaction::Hash256 {
+    sha2::Sha256::digest(x).into()
+}
+
+pub fn hash_twice(x: &[u8]) -> transaction::Hash256 {
+    hash_once(&hash_once(x))
+}
+
+// Like `hash_twice`, but hashes the concatenation of `parts` without copying them together first.
+pub fn hash_twice_parts(parts: &[&[u8]]) -> transaction::Hash256 {
+    let mut hasher = sha2::Sha256::new();
+    for p in parts.iter() {
+        hasher.update(p);
+    }
+    hash_once(&hasher.finalize())
+}
+
+// Computes the merkle root of a block from the txids of its transactions. Each level of the tree
+// hashes pairs of nodes, and a node without a partner is paired with itself.
+pub fn merkle_root(mut level: Vec<transaction::Hash256>) -> transaction::Hash256 {
+    if level.is_empty() {
+        return [0; 32];
+    }
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| hash_twice_parts(&[&pair[0], pair.get(1).unwrap_or(&pair[0])]))
+            .collect();
+    }
+    level[0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_chain;
+    use std::collections::HashSet;
+
+    // The mainnet genesis block.
+    const GENESIS: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c0101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+
+    // A mainnet transaction spending a P2WPKH output, with a witness.
+    const SEGWIT_TX: &str = "02000000000101595895ea20179de87052b4046dfe6fd515860505d6511a9004cf12a1f93cac7c0100000000ffffffff01deb807000000000017a9140f3444e271620c736808aa7b33e370bd87cb5a078702483045022100fb60dad8df4af2841adc0346638c16d0b8035f5e3f3753b88db122e70c79f9370220756e6633b17fd2710e626347d28d60b0a2d6cbb41de51740644b9fb3ba7751040121028fa937ca8cba2197a37c007176ed8941055d3bcb8627d085e94553e62f057dcc00000000";
+
+    // A mainnet transaction without witnesses.
+    const LEGACY_TX: &str = "01000000010c7196428403d8b0c88fcb3ee8d64f56f55c8973c9ab7dd106bb4f3527f5888d000000006a4730440220503a696f55f2c00eee2ac5e65b17767cd88ed04866b5637d3c1d5d996a70656d02202c9aff698f343abb6d176704beda63fcdec503133ea4f6a5216b7f925fa9910c0121024d89b5a13d6521388969209df27a8469bd565aff10e8d42cef931fad5121bfb8ffffffff02b825b404000000001976a914ef79e7ee9fff98bcfd08473d2b76b02a48f8c69088ac0000000000000000296a2732363030393438363937313732333132373633313032313332353630353838373931323132373000000000";
+
+    // A mainnet block with two transactions.
+    const TWO_TX_BLOCK: &str = "010000004ddccd549d28f385ab457e98d1b11ce80bfea2c5ab93015ade4973e400000000bf4473e53794beae34e64fccc471dace6ae544180816f89591894e0f417a914cd74d6e49ffff001d323b3a7b0201000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0804ffff001d026e04ffffffff0100f2052a0100000043410446ef0102d1ec5240f0d061a4246c1bdef63fc3dbab7733052fbbf0ecd8f41fc26bf049ebb4f9527f374280259e7cfa99c48b0e3f39c51347a19a5819651503a5ac00000000010000000321f75f3139a013f50f315b23b0c9a2b6eac31e2bec98e5891c924664889942260000000049483045022100cb2c6b346a978ab8c61b18b5e9397755cbd17d6eb2fe0083ef32e067fa6c785a02206ce44e613f31d9a6b0517e46f3db1576e9812cc98d159bfdaf759a5014081b5c01ffffffff79cda0945903627c3da1f85fc95d0b8ee3e76ae0cfdc9a65d09744b1f8fc85430000000049483045022047957cdd957cfd0becd642f6b84d82f49b6cb4c51a91f49246908af7c3cfdf4a022100e96b46621f1bffcf5ea5982f88cef651e9354f5791602369bf5a82a6cd61a62501fffffffffe09f5fe3ffbf5ee97a54eb5e5069e9da6b4856ee86fc52938c2f979b0f38e82000000004847304402204165be9a4cbab8049e1af9723b96199bfd3e85f44c6b4c0177e3962686b26073022028f638da23fc003760861ad481ead4099312c60030d4cb57820ce4d33812a5ce01ffffffff01009d966b01000000434104ea1feff861b51fe3f5f8a3b12d0f4712db80e919548a80839fc47c6a21e66d957e9c5d8cd108c7a2d2324bad71f9904ac0ae7336507d785b17a2c115e427a32fac00000000";
+
+    fn genesis_block() -> transaction::Block {
+        let genesis = hex::decode(GENESIS).unwrap();
+        parse_block_header_and_tx_count(&genesis).unwrap().1
+    }
+
+    fn decode(tx: &str) -> DecodedTransaction {
+        let data = hex::decode(tx).unwrap();
+        let (rest, tx) = decode_transaction(&data, &genesis_block(), Network::Mainnet).unwrap();
+        assert!(rest.is_empty());
+        tx
+    }
+
+    #[test]
+    fn segwit_txid_leaves_out_the_witness() {
+        let decoded = decode(SEGWIT_TX);
+        let tx = decoded.tx;
+        assert_eq!(
+            tx.id,
+            TxHash::new_from_str(
+                "f5864806e3565c34d1b41e716f72609d00b55ea5eac5b924c9719a842ef42206"
+            )
+        );
+        assert_eq!(
+            tx.wtxid,
+            TxHash::new_from_str(
+                "80b7d8a82d5d5bf92905b06f2014dd699e03837ca172e3a59d51426ebbe3e7f5"
+            )
+        );
+        assert_eq!(tx.size, 193);
+        assert_eq!(tx.weight, 442);
+        assert_eq!(tx.vsize, 111);
+        assert_eq!(tx.version, 2);
+        assert_eq!(tx.input_count, 1);
+        assert_eq!(tx.output_count, 1);
+        assert_eq!(tx.lock_time, 0);
+        assert!(!tx.is_coinbase);
+
+        assert_eq!(decoded.inputs.len(), 1);
+        assert_eq!(
+            decoded.inputs[0].source,
+            OutputHashAndIndex {
+                tx: TxHash::new_from_str(
+                    "7cac3cf9a112cf04901a51d605058615d56ffe6d04b45270e89d1720ea955859"
+                ),
+                index: 1,
+            }
+        );
+        assert_eq!(decoded.inputs[0].sequence, 0xffffffff);
+        assert_eq!(decoded.outputs.len(), 1);
+        assert_eq!(decoded.outputs[0].value, 506_078);
+    }
+
+    #[test]
+    fn legacy_txid_and_wtxid_are_the_same() {
+        let decoded = decode(LEGACY_TX);
+        let tx = decoded.tx;
+        let id = TxHash::new_from_str(
+            "971ed48a62c143bbd9c87f4bafa2ef213cfa106c6e140f111931d0be307468dd",
+        );
+        assert_eq!(tx.id, id);
+        assert_eq!(tx.wtxid, id);
+        assert_eq!(tx.size, 241);
+        assert_eq!(tx.weight, 4 * tx.size);
+        assert_eq!(tx.vsize, tx.size);
+        assert_eq!(tx.version, 1);
+        assert_eq!(tx.input_count, 1);
+        assert_eq!(tx.output_count, 2);
+        assert_eq!(tx.lock_time, 0);
+
+        assert_eq!(
+            decoded.inputs[0].source,
+            OutputHashAndIndex {
+                tx: TxHash::new_from_str(
+                    "8d88f527354fbb06d17dabc973895cf5564fd6e83ecb8fc8b0d803844296710c"
+                ),
+                index: 0,
+            }
+        );
+        assert_eq!(decoded.inputs[0].sequence, 0xffffffff);
+        assert_eq!(decoded.outputs[0].value, 78_915_000);
+        assert_eq!(decoded.outputs[1].value, 0);
+    }
+
+    fn txids(ids: &[&str]) -> Vec<transaction::Hash256> {
+        ids.iter()
+            .map(|id| *TxHash::new_from_str(id).as_ref())
+            .collect()
+    }
+
+    #[test]
+    fn merkle_root_of_block_100000() {
+        let ids = txids(&[
+            "8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87",
+            "fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4",
+            "6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4",
+            "e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d",
+        ]);
+        assert_eq!(
+            MerkleRoot::from(merkle_root(ids)),
+            MerkleRoot::new_from_str(
+                "f3e94742aca4b5ef85488dc37c06c3282295ffec960994b2c0d5ac2a25a95766"
+            )
+        );
+    }
+
+    #[test]
+    fn merkle_root_pairs_the_last_node_with_itself() {
+        let ids = txids(&[
+            "8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87",
+            "fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4",
+            "6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4",
+        ]);
+        let mut padded = ids.clone();
+        padded.push(ids[2]);
+        assert_eq!(merkle_root(ids), merkle_root(padded));
+    }
+
+    #[test]
+    fn verifies_the_merkle_root_of_a_decoded_block() {
+        let mut data = hex::decode(TWO_TX_BLOCK).unwrap();
+        let decode = |data: &[u8]| {
+            decode_block(
+                data,
+                &MainChain::default(),
+                StaleBlockPolicy::Mark,
+                true,
+                Network::Mainnet,
+            )
+        };
+        let decoded = decode(&data).ok().unwrap().unwrap();
+        assert_eq!(decoded.txs.len(), 2);
+
+        // Changing the lock time of the last transaction changes its txid.
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        assert!(matches!(
+            decode(&data),
+            Err(DecodeError::MerkleMismatch { .. })
+        ));
+    }
+
+    // Keeps everything the parser writes.
+    #[derive(Default)]
+    struct Collected {
+        txs: Vec<transaction::Transaction>,
+        blocks: Vec<transaction::Block>,
+        iopairs: Vec<InputOutputPair>,
+        // Outputs that were written out as unspent before they were spent.
+        spends: Vec<InputOutputPair>,
+        retracted: Vec<BlockHash>,
+    }
+
+    impl OutputWriter for Collected {
+        fn insert_tx(&mut self, tx: transaction::Transaction) -> anyhow::Result<()> {
+            self.txs.push(tx);
+            Ok(())
+        }
+
+        fn insert_block(&mut self, b: transaction::Block) -> anyhow::Result<()> {
+            self.blocks.push(b);
+            Ok(())
+        }
+
+        fn insert_iopair(&mut self, iopair: InputOutputPair) -> anyhow::Result<()> {
+            self.iopairs.push(iopair);
+            Ok(())
+        }
+
+        fn spend_output(&mut self, iopair: InputOutputPair) -> anyhow::Result<()> {
+            self.spends.push(iopair);
+            Ok(())
+        }
+
+        fn retract_block(&mut self, b: transaction::Block) -> anyhow::Result<()> {
+            self.retracted.push(b.id);
+            Ok(())
+        }
+    }
+
+    // Writes `blocks` to a mainnet blk file in a new directory.
+    fn blk_file(blocks: &[Vec<u8>]) -> (tempfile::TempDir, std::path::PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blk00000.dat");
+        let mut data = Vec::new();
+        for b in blocks {
+            data.extend_from_slice(&Network::Mainnet.magic());
+            data.extend_from_slice(&(b.len() as u32).to_le_bytes());
+            data.extend_from_slice(b);
+        }
+        std::fs::write(&path, data).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn skips_junk_behind_magic_bytes_instead_of_indexing_it() {
+        let genesis = hex::decode(GENESIS).unwrap();
+        let junk: Vec<u8> = (0..100).map(|i| (i * 37 + 11) as u8).collect();
+        let (_dir, path) = blk_file(&[genesis, junk]);
+
+        let mut out = Collected::default();
+        let mut parser = Parser::new(&mut out);
+        parser.set_error_policy(ErrorPolicy::Skip);
+        parser.parse_file(&path).unwrap();
+
+        assert_eq!(parser.header_chain.len(), 1);
+        assert_eq!(parser.main_chain.len(), 1);
+        assert!(matches!(
+            parser.skipped(),
+            [ParseError::BadProofOfWork { offset: 293, .. }]
+        ));
+        drop(parser);
+        assert_eq!(out.blocks.len(), 1);
+    }
+
+    // Parses `blocks`, in this order, as a regtest blk file.
+    fn parse_regtest(blocks: &[&test_chain::Block], policy: StaleBlockPolicy) -> Collected {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blk00000.dat");
+        std::fs::write(&path, test_chain::blk_data(blocks)).unwrap();
+
+        let mut out = Collected::default();
+        let mut parser = Parser::new(&mut out);
+        parser.set_network(Network::Regtest);
+        parser.set_stale_block_policy(policy);
+        parser.parse_file(&path).unwrap();
+        drop(parser);
+        out.iopairs.sort();
+        out
+    }
+
+    #[test]
+    fn finds_blocks_only_behind_the_magic_of_the_selected_network() {
+        let genesis = test_chain::genesis();
+        let b1 = test_chain::mine(&genesis, &[test_chain::coinbase(1, &[])]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blk00000.dat");
+        std::fs::write(&path, test_chain::blk_data(&[&genesis, &b1])).unwrap();
+
+        for network in Network::ALL {
+            let mut out = Collected::default();
+            let mut parser = Parser::new(&mut out);
+            parser.set_network(network);
+            let result = parser.parse_file(&path);
+            drop(parser);
+            match network {
+                Network::Regtest => {
+                    result.unwrap();
+                    assert_eq!(out.blocks.len(), 2);
+                }
+                _ => {
+                    let error = result.unwrap_err().to_string();
+                    assert!(error.contains("magic bytes of regtest"), "{}", error);
+                    assert!(out.blocks.is_empty());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stale_blocks_do_not_take_part_in_matching() {
+        use test_chain::{coinbase, mine, p2pkh, spend};
+
+        // Both sides of a fork at height 2 spend the output of block 1's coinbase in the same
+        // transaction. The stale side comes first in the file, so it would take the output away from
+        // the main chain copy if it were matched.
+        let genesis = test_chain::genesis();
+        let coinbase1 = coinbase(1, &[(50_0000_0000, p2pkh(1))]);
+        let b1 = mine(&genesis, std::slice::from_ref(&coinbase1));
+        let s = spend(&[(coinbase1.id, 0)], &[(49_0000_0000, p2pkh(3))], false);
+        let b2 = mine(&b1, &[coinbase(2, &[(50_0000_0000, p2pkh(2))]), s.clone()]);
+        let stale = mine(&b1, &[coinbase(2, &[(50_0000_0000, p2pkh(9))]), s.clone()]);
+        let b3 = mine(&b2, &[coinbase(3, &[(50_0000_0000, p2pkh(4))])]);
+        let file = [&genesis, &b1, &stale, &b2, &b3];
+
+        let dropped = parse_regtest(&file, StaleBlockPolicy::Drop);
+        let heights: Vec<_> = dropped.blocks.iter().map(|b| (b.id, b.height)).collect();
+        assert_eq!(
+            heights,
+            [(genesis.id, 0), (b1.id, 1), (b2.id, 2), (b3.id, 3)]
+        );
+        assert!(dropped.txs.iter().all(|t| t.block != stale.id));
+
+        let marked = parse_regtest(&file, StaleBlockPolicy::Mark);
+        let heights: Vec<_> = marked.blocks.iter().map(|b| (b.id, b.height)).collect();
+        assert_eq!(
+            heights,
+            [
+                (genesis.id, 0),
+                (b1.id, 1),
+                (stale.id, UNKNOWN_HEIGHT),
+                (b2.id, 2),
+                (b3.id, 3)
+            ]
+        );
+        let stale_txs: Vec<_> = marked.txs.iter().filter(|t| t.block == stale.id).collect();
+        assert_eq!(stale_txs.len(), 2);
+        assert!(stale_txs
+            .iter()
+            .all(|t| t.block_height == UNKNOWN_HEIGHT && t.fee.is_none()));
+
+        // The stale block adds no iopairs, and the main chain is matched as if it wasn't there.
+        assert_eq!(marked.iopairs, dropped.iopairs);
+        for out in [&dropped, &marked] {
+            let spent: Vec<_> = out
+                .iopairs
+                .iter()
+                .filter(|p| p.source.src_tx == coinbase1.id)
+                .collect();
+            assert_eq!(spent.len(), 1);
+            let dest = spent[0].dest.unwrap();
+            assert_eq!((dest.dest_tx, dest.dest_block), (s.id, b2.id));
+
+            let main_copy = out.txs.iter().find(|t| t.block == b2.id && t.id == s.id);
+            assert_eq!(main_copy.unwrap().fee, Some(1_0000_0000));
+        }
+    }
+
+    #[test]
+    fn coinbase_input_spends_what_the_coinbase_pays_out() {
+        use test_chain::{coinbase, mine, p2pkh, spend};
+
+        // The miner of block 2 claims neither the whole subsidy nor the fee of its other
+        // transaction.
+        let genesis = test_chain::genesis();
+        let coinbase1 = coinbase(1, &[(50_0000_0000, p2pkh(1))]);
+        let b1 = mine(&genesis, std::slice::from_ref(&coinbase1));
+        let coinbase2 = coinbase(2, &[(30_0000_0000, p2pkh(2)), (5_0000_0000, p2pkh(3))]);
+        let s = spend(&[(coinbase1.id, 0)], &[(49_0000_0000, p2pkh(4))], false);
+        let b2 = mine(&b1, &[coinbase2.clone(), s]);
+
+        let out = parse_regtest(&[&genesis, &b1, &b2], StaleBlockPolicy::Drop);
+        let sources: Vec<_> = out
+            .iopairs
+            .iter()
+            .filter(|p| p.source.src_tx == COINBASE_SOURCE_TX && p.source.src_block == b2.id)
+            .collect();
+        assert_eq!(sources.len(), 1);
+        let source = &sources[0].source;
+        assert_eq!(source.src_index, COINBASE_SOURCE_INDEX);
+        assert_eq!(source.value, 35_0000_0000);
+        assert_eq!(source.script_type, ScriptType::Nonstandard);
+        assert_eq!(source.address, None);
+        assert_eq!(
+            sources[0].dest,
+            Some(Input {
+                dest_tx: coinbase2.id,
+                dest_block: b2.id,
+                dest_index: 0,
+                sequence: u32::MAX,
+            })
+        );
+
+        // Every block has one such source.
+        let count = out
+            .iopairs
+            .iter()
+            .filter(|p| p.source.src_tx == COINBASE_SOURCE_TX)
+            .count();
+        assert_eq!(count, 3);
+        let tx = out.txs.iter().find(|t| t.id == coinbase2.id).unwrap();
+        assert!(tx.is_coinbase);
+        assert_eq!(tx.fee, None);
+    }
+
+    #[test]
+    fn fee_is_computed_once_inputs_from_other_blocks_are_found() {
+        use test_chain::{coinbase, mine, p2pkh, spend};
+
+        // `s` spends outputs of blocks 1 and 2, and block 3 comes before block 2 in the file, so one
+        // of its inputs is only found after it was parsed.
+        let genesis = test_chain::genesis();
+        let coinbase1 = coinbase(1, &[(50_0000_0000, p2pkh(1))]);
+        let b1 = mine(&genesis, std::slice::from_ref(&coinbase1));
+        let coinbase2 = coinbase(2, &[(20_0000_0000, p2pkh(2)), (30_0000_0000, p2pkh(3))]);
+        let b2 = mine(&b1, std::slice::from_ref(&coinbase2));
+        let s = spend(
+            &[(coinbase1.id, 0), (coinbase2.id, 1)],
+            &[(79_9999_0000, p2pkh(4))],
+            true,
+        );
+        let b3 = mine(&b2, &[coinbase(3, &[(50_0000_0000, p2pkh(5))]), s.clone()]);
+
+        let out = parse_regtest(&[&genesis, &b1, &b3, &b2], StaleBlockPolicy::Drop);
+        let tx = out.txs.iter().find(|t| t.id == s.id).unwrap();
+        assert_eq!(tx.size as usize, s.data.len());
+        assert_eq!(tx.vsize, s.vsize);
+        assert!(tx.vsize < tx.size);
+        assert_eq!(tx.fee, Some(1_0000));
+        assert_eq!(tx.feerate, Some(1_0000.0 / s.vsize as f64));
+    }
+
+    #[test]
+    fn fee_is_unknown_if_an_input_is_never_found() {
+        use test_chain::{coinbase, mine, p2pkh, spend};
+
+        let genesis = test_chain::genesis();
+        let coinbase1 = coinbase(1, &[(50_0000_0000, p2pkh(1))]);
+        let b1 = mine(&genesis, std::slice::from_ref(&coinbase1));
+        let missing = TxHash::new([7; 32]);
+        let s = spend(
+            &[(coinbase1.id, 0), (missing, 0)],
+            &[(49_0000_0000, p2pkh(2))],
+            false,
+        );
+        let b2 = mine(&b1, &[coinbase(2, &[(50_0000_0000, p2pkh(3))]), s.clone()]);
+
+        let out = parse_regtest(&[&genesis, &b1, &b2], StaleBlockPolicy::Drop);
+        let tx = out.txs.iter().find(|t| t.id == s.id).unwrap();
+        assert_eq!(tx.fee, None);
+        assert_eq!(tx.feerate, None);
+        // The input that was found is still written.
+        assert!(out
+            .iopairs
+            .iter()
+            .any(|p| p.source.src_tx == coinbase1.id && p.dest.is_some_and(|d| d.dest_tx == s.id)));
+    }
+
+    #[test]
+    fn only_the_later_of_two_outputs_with_the_same_txid_can_be_spent() {
+        use test_chain::{coinbase, mine, p2pkh, spend};
+
+        // Blocks 1 and 3 have the same coinbase, and block 4 spends its output.
+        let genesis = test_chain::genesis();
+        let duplicate = coinbase(1, &[(50_0000_0000, p2pkh(1))]);
+        let b1 = mine(&genesis, std::slice::from_ref(&duplicate));
+        let b2 = mine(&b1, &[coinbase(2, &[(50_0000_0000, p2pkh(2))])]);
+        let b3 = mine(&b2, std::slice::from_ref(&duplicate));
+        let s = spend(&[(duplicate.id, 0)], &[(49_0000_0000, p2pkh(4))], false);
+        let b4 = mine(&b3, &[coinbase(4, &[(50_0000_0000, p2pkh(3))]), s.clone()]);
+
+        // The outcome doesn't depend on which of the two is parsed first.
+        for file in [
+            [&genesis, &b1, &b2, &b3, &b4],
+            [&genesis, &b3, &b4, &b2, &b1],
+        ] {
+            let out = parse_regtest(&file, StaleBlockPolicy::Drop);
+            let mut outputs: Vec<_> = out
+                .iopairs
+                .iter()
+                .filter(|p| p.source.src_tx == duplicate.id)
+                .map(|p| {
+                    (
+                        p.source.src_block,
+                        p.dest.map(|d| (d.dest_tx, d.dest_block)),
+                    )
+                })
+                .collect();
+            outputs.sort_by_key(|(_, dest)| dest.is_some());
+            assert_eq!(outputs, [(b1.id, None), (b3.id, Some((s.id, b4.id)))]);
+
+            let mut copies: Vec<_> = out
+                .txs
+                .iter()
+                .filter(|t| t.id == duplicate.id)
+                .map(|t| (t.block, t.block_height))
+                .collect();
+            copies.sort_by_key(|c| c.1);
+            assert_eq!(copies, [(b1.id, 1), (b3.id, 3)]);
+            let spender = out.txs.iter().find(|t| t.id == s.id).unwrap();
+            assert_eq!(spender.fee, Some(1_0000_0000));
+        }
+    }
+
+    #[test]
+    fn parses_the_same_with_any_number_of_threads() {
+        use test_chain::{coinbase, mine, p2pkh, spend};
+
+        // Enough blocks for several decode batches. Each block spends the coinbase output of the one
+        // before it, and pairs of blocks are swapped in the file, so that some outputs only turn up
+        // after the inputs spending them.
+        let mut chain = vec![test_chain::genesis()];
+        let mut previous_coinbase = None;
+        for height in 1..=(2 * DECODE_BATCH_SIZE as u32 + 10) {
+            let mut txs = vec![coinbase(height, &[(50_0000_0000, p2pkh(height as u8))])];
+            if let Some(id) = previous_coinbase {
+                txs.push(spend(
+                    &[(id, 0)],
+                    &[(20_0000_0000, p2pkh(1)), (29_0000_0000, p2pkh(2))],
+                    height % 3 == 0,
+                ));
+            }
+            previous_coinbase = Some(txs[0].id);
+            chain.push(mine(chain.last().unwrap(), &txs));
+        }
+        let mut file: Vec<_> = chain.iter().collect();
+        for pair in file[1..].chunks_mut(2) {
+            pair.reverse();
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blk00000.dat");
+        std::fs::write(&path, test_chain::blk_data(&file)).unwrap();
+
+        let parse = |threads| {
+            let mut out = Collected::default();
+            let mut parser = Parser::new(&mut out);
+            parser.set_network(Network::Regtest);
+            parser.set_threads(threads).unwrap();
+            parser.parse_file(&path).unwrap();
+            drop(parser);
+            // Unspent outputs are written out in no particular order at the end.
+            out.iopairs.sort();
+            out
+        };
+        let single = parse(1);
+        assert_eq!(single.blocks.len(), chain.len());
+        assert!(single.txs.iter().all(|t| t.is_coinbase || t.fee.is_some()));
+        for threads in [2, 8] {
+            let multi = parse(threads);
+            assert_eq!(
+                format!("{:?}", multi.blocks),
+                format!("{:?}", single.blocks)
+            );
+            assert_eq!(format!("{:?}", multi.txs), format!("{:?}", single.txs));
+            assert_eq!(multi.iopairs, single.iopairs);
+        }
+    }
+
+    #[test]
+    fn retracts_blocks_that_leave_the_main_chain_and_restores_what_they_spent() {
+        let fork = test_chain::Fork::new();
+        let key = |tx: &test_chain::Tx| OutputHashAndIndex {
+            tx: tx.id,
+            index: 0,
+        };
+        let ids = |blocks: &[test_chain::Block]| -> HashSet<BlockHash> {
+            blocks.iter().map(|b| b.id).collect()
+        };
+
+        let mut out = Collected::default();
+        let mut parser = Parser::new(&mut out);
+        parser.set_network(Network::Regtest);
+        fork.follow(&mut parser, |step, parser| {
+            let recent: HashSet<BlockHash> = parser.recent_blocks.keys().copied().collect();
+            let dropped: HashSet<BlockHash> = parser.dropped_blocks.keys().copied().collect();
+            match step {
+                1 => {
+                    assert!(recent.is_superset(&ids(&fork.a[..2])));
+                    assert!(dropped.is_empty());
+                    let undo = &parser.recent_blocks[&fork.a[0].id];
+                    let spent: Vec<_> = undo.spent.iter().map(|(k, _)| *k).collect();
+                    assert_eq!(spent, [key(&fork.cb2)]);
+                    assert!(undo.created.contains(&key(&fork.spend_a)));
+                }
+                2 => {
+                    assert!(recent.is_superset(&ids(&fork.c)));
+                    assert!(recent.is_disjoint(&ids(&fork.a)));
+                    assert_eq!(dropped, ids(&fork.a[..2]));
+                    assert_eq!(parser.main_chain.tip(), Some(fork.c[2].id));
+                }
+                3 => {
+                    assert!(recent.is_superset(&ids(&fork.a)));
+                    assert!(recent.is_disjoint(&ids(&fork.c)));
+                    assert_eq!(dropped, ids(&fork.c));
+                    assert_eq!(parser.main_chain.tip(), Some(fork.a[3].id));
+                }
+                _ => {}
+            }
+            assert!(parser.unmatched_inputs.is_empty());
+        });
+        drop(parser);
+
+        let retracted: Vec<_> = [&fork.a[1], &fork.a[0], &fork.c[2], &fork.c[1], &fork.c[0]]
+            .iter()
+            .map(|b| b.id)
+            .collect();
+        assert_eq!(out.retracted, retracted);
+        // Block 3 of `a` was registered again when it came back to the main chain.
+        assert_eq!(
+            out.blocks.iter().filter(|b| b.id == fork.a[0].id).count(),
+            2
+        );
+
+        // The output spent in `a[0]` was spent within the same step, so it was written along with
+        // its spend. Once `a[0]` was retracted, the writer had it as unspent, so the spends after
+        // that had to update it.
+        let spends: Vec<_> = out
+            .spends
+            .iter()
+            .map(|p| (p.source.src_tx, p.dest.unwrap().dest_block))
+            .collect();
+        assert_eq!(
+            spends,
+            [
+                (fork.cb1.id, fork.c[0].id),
+                (fork.cb2.id, fork.c[1].id),
+                (fork.cb2.id, fork.a[0].id),
+            ]
+        );
+        let written_spent: Vec<_> = out
+            .iopairs
+            .iter()
+            .filter(|p| p.source.src_tx == fork.cb2.id)
+            .map(|p| p.dest.map(|d| d.dest_block))
+            .collect();
+        assert_eq!(written_spent, [Some(fork.a[0].id)]);
+    }
+}
@@ -1,57 +1,443 @@
-use clap::{ArgEnum, Parser};
-use search::custom_format::{sort_and_write_data, CustomWriter};
-
-#[derive(Parser, Debug)]
-#[clap(version)]
-struct Args {
-    #[clap(arg_enum, short, long, default_value = "dump-distributed-custom-dbs")]
-    operation: Operation,
-
-    #[clap(short, long, default_value = "0")]
-    for_num_workers: usize,
-
-    #[clap(short, long, default_value = "1")]
-    dat_files_to_parse: u32,
-}
-
-#[derive(Clone, ArgEnum, Debug)]
-enum Operation {
-    DumpSqlite,
-    DumpUnsortedCustomDB,
-    DumpDistributedCustomDbs,
-}
-
-fn main() {
-    println!("Hello, world!");
-    let args = Args::parse();
-    println!("Parsing with configuration {:?}", args);
-
-    match args.operation {
-        Operation::DumpSqlite => {
-            if args.for_num_workers != 0 {
-                panic!("for_num_workers specified but has no effect unless Operation chosen in DumpDistributedCustomDBs!")
-            }
-            let sqlite_connection = rusqlite::Connection::open("btc-test.db").unwrap();
-            let mut sqlite_drainer = search::sqlite::SQLiteDriver::new(&sqlite_connection);
-            let mut p = search::parser::Parser::new(&mut sqlite_drainer);
-            p.parse(args.dat_files_to_parse);
-        }
-        Operation::DumpUnsortedCustomDB => {
-            if args.for_num_workers != 0 {
-                panic!("for_num_workers specified but has no effect unless Operation chosen in DumpDistributedCustomDBs!")
-            }
-            let mut custom_drainer = CustomWriter::new();
-            let mut p = search::parser::Parser::new(&mut custom_drainer);
-            p.parse(args.dat_files_to_parse);
-        }
-        Operation::DumpDistributedCustomDbs => {
-            if args.for_num_workers < 1 {
-                panic!("for_num_workers less than 1 with DumpDistributedCustomDbs operation doesn't make much sense (note that default value is 0)!")
-            }
-            let mut custom_drainer = CustomWriter::new();
-            let mut p = search::parser::Parser::new(&mut custom_drainer);
-            p.parse(args.dat_files_to_parse);
-            sort_and_write_data(args.for_num_workers);
-        }
-    }
-}
+use anyhow::{bail, Context};
+use clap::{ArgEnum, Parser};
+use search::block_source::BlockSource;
+use search::checkpoint::Checkpoint;
+use search::custom_format::{sort_and_write_data, CustomWriter};
+use search::network;
+use search::node_rpc::{RpcAuth, RpcEndpoint};
+use search::output_writer::OutputWriter;
+use search::parquet_format::ParquetWriter;
+use search::parser::{ErrorPolicy, StaleBlockPolicy, XorKey};
+use search::sqlite::SQLiteDriver;
+use search::text_format::{TextFormat, TextOptions, TextWriter};
+use search::utxo_store::SQLiteUtxoStore;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Parser, Debug)]
+#[clap(version)]
+struct Args {
+    #[clap(arg_enum, short, long, default_value = "dump-distributed-custom-dbs")]
+    operation: Operation,
+
+    #[clap(short, long, default_value = "0")]
+    for_num_workers: usize,
+
+    /// SQLite database to write to with dump-sqlite. If it exists, the parsed data is added to it
+    #[clap(long, default_value = "btc-test.db")]
+    sqlite_db: PathBuf,
+
+    /// Number of blocks written per SQLite transaction with dump-sqlite
+    #[clap(long, default_value = "1000")]
+    sqlite_blocks_per_transaction: usize,
+
+    /// With dump-csv and dump-json-lines, add a column with the value in BTC next to every value in
+    /// satoshis
+    #[clap(long)]
+    btc_columns: bool,
+
+    /// With dump-csv and dump-json-lines, compress the files with gzip
+    #[clap(long)]
+    gzip: bool,
+
+    /// With dump-csv and dump-json-lines, start a new file for a table once its current one holds
+    /// this many MiB (before compression)
+    #[clap(long)]
+    split_mb: Option<u64>,
+
+    /// With dump-sqlite, index transactions.id, blocks.block_hash, and input_output_pairs.src_tx
+    /// and dest_tx once the data is loaded (or before following new blocks, with --follow)
+    #[clap(long)]
+    sqlite_indexes: bool,
+
+    // Block source options. Exactly one of --blocks-dir, --file, --glob and --stdin must be given.
+    /// Bitcoin Core blocks directory; all blk*.dat files in it are parsed in order
+    #[clap(short, long)]
+    blocks_dir: Option<PathBuf>,
+
+    /// Index of the first blk file to parse (with --blocks-dir)
+    #[clap(long, default_value = "0")]
+    start_file: u32,
+
+    /// Index one past the last blk file to parse (with --blocks-dir)
+    #[clap(long)]
+    end_file: Option<u32>,
+
+    /// Number of blk files to parse, starting at --start-file (with --blocks-dir)
+    #[clap(short, long)]
+    dat_files_to_parse: Option<u32>,
+
+    /// Block file to parse; may be repeated, files are parsed in the given order
+    #[clap(long, multiple_occurrences(true))]
+    file: Vec<PathBuf>,
+
+    /// Glob pattern matching the block files to parse
+    #[clap(long)]
+    glob: Option<String>,
+
+    /// Read raw block data from stdin
+    #[clap(long)]
+    stdin: bool,
+
+    /// Fetch the blocks of a Bitcoin Core node's best chain over JSON-RPC, e.g.
+    /// http://127.0.0.1:8332
+    #[clap(long)]
+    rpc_url: Option<String>,
+
+    /// Cookie file to authenticate to the node with (with --rpc-url)
+    #[clap(long)]
+    rpc_cookie: Option<PathBuf>,
+
+    /// User name to authenticate to the node with (with --rpc-url). The password is read from the
+    /// BITCOIN_RPC_PASSWORD environment variable
+    #[clap(long)]
+    rpc_user: Option<String>,
+
+    /// Network the block data is from, which determines its magic bytes and genesis block
+    #[clap(arg_enum, long, default_value = "mainnet")]
+    network: Network,
+
+    /// XOR key (16 hex characters) to de-obfuscate the block data with. By default it is read from
+    /// xor.dat in the blocks directory, if present
+    #[clap(long)]
+    xor_key: Option<String>,
+
+    /// What to do with blocks that are not on the main chain
+    #[clap(arg_enum, long, default_value = "drop")]
+    stale_blocks: StaleBlocks,
+
+    /// What to do with corrupt block data: stop, or skip it and report it at the end
+    #[clap(arg_enum, long, default_value = "abort")]
+    on_error: OnError,
+
+    /// Validate the proof of work and timestamps of all block headers against the rules of the
+    /// network, and leave out blocks that fail, along with their descendants
+    #[clap(long)]
+    validate_headers: bool,
+
+    /// Check each block's merkle root against its transactions; failing blocks count as corrupt
+    #[clap(long)]
+    verify_merkle_roots: bool,
+
+    /// Checkpoint file to resume parsing from, if it exists, and to save the parser state to
+    /// afterwards. A resumed run only writes out blocks that were not parsed before
+    #[clap(long)]
+    checkpoint: Option<PathBuf>,
+
+    /// Keep unspent outputs in an SQLite database at this path instead of in memory, which bounds
+    /// the memory needed to parse the full chain
+    #[clap(long)]
+    utxo_db: Option<PathBuf>,
+
+    /// Memory in MiB for caching unspent outputs in front of --utxo-db
+    #[clap(long, default_value = "1024")]
+    utxo_cache_mb: usize,
+
+    /// Number of threads used to decode blocks; 0 uses one per CPU core
+    #[clap(long, default_value = "0")]
+    threads: usize,
+
+    /// After parsing, keep watching the block files for new blocks and add them to the output,
+    /// retracting blocks that leave the main chain in a reorg. Runs until interrupted
+    #[clap(long)]
+    follow: bool,
+
+    /// How often to check the block files for new blocks with --follow, in milliseconds
+    #[clap(long, default_value = "1000")]
+    poll_interval_ms: u64,
+}
+
+#[derive(Clone, ArgEnum, Debug)]
+enum Operation {
+    DumpSqlite,
+    DumpUnsortedCustomDB,
+    DumpDistributedCustomDbs,
+    DumpParquet,
+    DumpCsv,
+    DumpJsonLines,
+}
+
+#[derive(Clone, ArgEnum, Debug)]
+enum StaleBlocks {
+    Drop,
+    Mark,
+}
+
+#[derive(Clone, ArgEnum, Debug)]
+enum Network {
+    Mainnet,
+    Testnet,
+    Signet,
+    Regtest,
+}
+
+fn network(args: &Args) -> network::Network {
+    match args.network {
+        Network::Mainnet => network::Network::Mainnet,
+        Network::Testnet => network::Network::Testnet,
+        Network::Signet => network::Network::Signet,
+        Network::Regtest => network::Network::Regtest,
+    }
+}
+
+#[derive(Clone, ArgEnum, Debug)]
+enum OnError {
+    Abort,
+    Skip,
+}
+
+fn block_source(args: &Args) -> anyhow::Result<BlockSource> {
+    let sources_given = [
+        args.blocks_dir.is_some(),
+        !args.file.is_empty(),
+        args.glob.is_some(),
+        args.stdin,
+        args.rpc_url.is_some(),
+    ]
+    .iter()
+    .filter(|x| **x)
+    .count();
+    if sources_given != 1 {
+        bail!("exactly one of --blocks-dir, --file, --glob, --stdin and --rpc-url must be given");
+    }
+    if args.rpc_url.is_none() && (args.rpc_cookie.is_some() || args.rpc_user.is_some()) {
+        bail!("--rpc-cookie and --rpc-user only apply to --rpc-url");
+    }
+
+    let end = match (args.end_file, args.dat_files_to_parse) {
+        (Some(_), Some(_)) => bail!("--end-file and --dat-files-to-parse are mutually exclusive"),
+        (Some(e), None) => Some(e),
+        (None, Some(n)) => Some(args.start_file + n),
+        (None, None) => None,
+    };
+    if args.blocks_dir.is_none() && (args.start_file != 0 || end.is_some()) {
+        bail!("--start-file, --end-file and --dat-files-to-parse only apply to --blocks-dir");
+    }
+
+    Ok(match (&args.blocks_dir, &args.glob) {
+        (Some(dir), _) => BlockSource::Directory {
+            path: dir.clone(),
+            start: args.start_file,
+            end,
+        },
+        (None, Some(pattern)) => BlockSource::Glob(pattern.clone()),
+        (None, None) if args.stdin => BlockSource::Stdin,
+        (None, None) if args.rpc_url.is_some() => BlockSource::Rpc(rpc_endpoint(args)?),
+        (None, None) => BlockSource::Files(args.file.clone()),
+    })
+}
+
+fn rpc_endpoint(args: &Args) -> anyhow::Result<RpcEndpoint> {
+    let auth = match (&args.rpc_cookie, &args.rpc_user) {
+        (Some(_), Some(_)) => bail!("--rpc-cookie and --rpc-user are mutually exclusive"),
+        (Some(cookie), None) => RpcAuth::Cookie(cookie.clone()),
+        (None, Some(user)) => RpcAuth::UserPassword {
+            user: user.clone(),
+            password: std::env::var("BITCOIN_RPC_PASSWORD")
+                .context("--rpc-user needs the password in BITCOIN_RPC_PASSWORD")?,
+        },
+        (None, None) => RpcAuth::None,
+    };
+    Ok(RpcEndpoint {
+        url: args.rpc_url.clone().unwrap(),
+        auth,
+    })
+}
+
+fn xor_key(args: &Args) -> anyhow::Result<Option<XorKey>> {
+    let key = match &args.xor_key {
+        None => return Ok(None),
+        Some(k) => k,
+    };
+    let mut result = XorKey::default();
+    hex::decode_to_slice(key.trim(), &mut result)
+        .with_context(|| format!("--xor-key {} is not 8 hex-encoded bytes", key))?;
+    Ok(Some(result))
+}
+
+// Runs the parser over `source` with all the parser options given on the command line, resuming
+// from `checkpoint` if there is one.
+fn parse(
+    args: &Args,
+    source: &BlockSource,
+    checkpoint: Option<Checkpoint>,
+    drainer: &mut dyn OutputWriter,
+) -> anyhow::Result<()> {
+    let mut p = search::parser::Parser::new(drainer);
+    let cache_size = args.utxo_cache_mb << 20;
+    match (checkpoint.as_ref().and_then(|c| c.utxo_db()), &args.utxo_db) {
+        (Some(saved), Some(given)) if saved != given => bail!(
+            "the checkpoint keeps unspent outputs in {}, not in --utxo-db {}",
+            saved.display(),
+            given.display()
+        ),
+        (Some(path), _) => p.set_utxo_store(Box::new(SQLiteUtxoStore::open(path, cache_size)?)),
+        (None, Some(path)) => {
+            p.set_utxo_store(Box::new(SQLiteUtxoStore::create(path, cache_size)?))
+        }
+        (None, None) => {}
+    }
+    p.set_network(network(args));
+    if let Some(c) = checkpoint {
+        p.resume_from(c)?;
+    }
+    if let Some(k) = xor_key(args)? {
+        p.set_xor_key(k);
+    }
+    p.set_stale_block_policy(match args.stale_blocks {
+        StaleBlocks::Drop => StaleBlockPolicy::Drop,
+        StaleBlocks::Mark => StaleBlockPolicy::Mark,
+    });
+    p.set_error_policy(match args.on_error {
+        OnError::Abort => ErrorPolicy::Abort,
+        OnError::Skip => ErrorPolicy::Skip,
+    });
+    if args.validate_headers {
+        p.set_header_validation(Some(network(args).pow_params()));
+    }
+    p.set_verify_merkle_roots(args.verify_merkle_roots);
+    p.set_threads(args.threads)?;
+    match args.follow {
+        true => p.follow(source, Duration::from_millis(args.poll_interval_ms))?,
+        false => p.parse(source)?,
+    }
+
+    if let Some(path) = &args.checkpoint {
+        p.into_checkpoint()?.save(path)?;
+        println!("Saved checkpoint to {}", path.display());
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    println!("Hello, world!");
+    let args = Args::parse();
+    println!("Parsing with configuration {:?}", args);
+    let source = block_source(&args)?;
+
+    let checkpoint = match &args.checkpoint {
+        Some(_) if args.stdin => bail!("--checkpoint can't be used with --stdin"),
+        Some(_) if args.rpc_url.is_some() => bail!("--checkpoint can't be used with --rpc-url"),
+        Some(path) => Checkpoint::load(path)?,
+        None => None,
+    };
+    if args.follow {
+        if args.stdin || args.rpc_url.is_some() {
+            bail!("--follow needs block files to watch, not --stdin or --rpc-url");
+        }
+        if args.checkpoint.is_some() {
+            bail!("--follow can't be used with --checkpoint");
+        }
+        if let StaleBlocks::Mark = args.stale_blocks {
+            bail!("--follow can't be used with --stale-blocks mark, since a stale block can join the main chain later");
+        }
+        if let Operation::DumpDistributedCustomDbs = args.operation {
+            bail!("--follow can't be used with dump-distributed-custom-dbs, which sorts the data once parsing is done");
+        }
+        if let Operation::DumpParquet = args.operation {
+            bail!("--follow can't be used with dump-parquet, since Parquet files can't be changed once written");
+        }
+        if let Operation::DumpCsv | Operation::DumpJsonLines = args.operation {
+            bail!("--follow can't be used with dump-csv and dump-json-lines, which only append to their files");
+        }
+    }
+    if let (Some(path), Some(c)) = (&args.checkpoint, &checkpoint) {
+        if c.network() != network(&args) {
+            bail!(
+                "checkpoint {} is for {}, not {}",
+                path.display(),
+                c.network(),
+                network(&args)
+            );
+        }
+        println!(
+            "Resuming from checkpoint {} with {} known block headers",
+            path.display(),
+            c.header_count()
+        );
+    }
+    if let (Some(_), Operation::DumpParquet) = (&args.checkpoint, &args.operation) {
+        bail!("--checkpoint can't be used with dump-parquet, since Parquet files can't be appended to");
+    }
+    if let (Some(_), Operation::DumpCsv | Operation::DumpJsonLines) =
+        (&args.checkpoint, &args.operation)
+    {
+        bail!("--checkpoint can't be used with dump-csv and dump-json-lines, which start new files on every run");
+    }
+    let resuming = checkpoint.is_some();
+
+    match args.operation {
+        Operation::DumpSqlite => {
+            if args.for_num_workers != 0 {
+                bail!("for_num_workers specified but has no effect unless Operation chosen in DumpDistributedCustomDBs!")
+            }
+            let sqlite_connection = rusqlite::Connection::open(&args.sqlite_db)
+                .with_context(|| format!("failed to open {}", args.sqlite_db.display()))?;
+            let mut sqlite_drainer = match resuming {
+                true => SQLiteDriver::resume(&sqlite_connection, network(&args))?,
+                false => SQLiteDriver::new(&sqlite_connection, network(&args))?,
+            };
+            sqlite_drainer.set_blocks_per_transaction(args.sqlite_blocks_per_transaction);
+            if resuming || args.follow {
+                SQLiteDriver::create_update_indexes(&sqlite_connection)?;
+            }
+            // In follow mode, the parser never finishes loading.
+            if args.sqlite_indexes && args.follow {
+                SQLiteDriver::create_indexes(&sqlite_connection)?;
+            }
+            parse(&args, &source, checkpoint, &mut sqlite_drainer)?;
+            if args.sqlite_indexes {
+                println!("Creating indexes...");
+                SQLiteDriver::create_indexes(&sqlite_connection)?;
+            }
+        }
+        Operation::DumpUnsortedCustomDB => {
+            if args.for_num_workers != 0 {
+                bail!("for_num_workers specified but has no effect unless Operation chosen in DumpDistributedCustomDBs!")
+            }
+            let mut custom_drainer = match resuming {
+                true => CustomWriter::resume(network(&args)),
+                false => CustomWriter::new(network(&args)),
+            }?;
+            parse(&args, &source, checkpoint, &mut custom_drainer)?;
+        }
+        Operation::DumpDistributedCustomDbs => {
+            if args.for_num_workers < 1 {
+                bail!("for_num_workers less than 1 with DumpDistributedCustomDbs operation doesn't make much sense (note that default value is 0)!")
+            }
+            let mut custom_drainer = match resuming {
+                true => CustomWriter::resume(network(&args)),
+                false => CustomWriter::new(network(&args)),
+            }?;
+            parse(&args, &source, checkpoint, &mut custom_drainer)?;
+            // Flushes the unsorted files, so that they can be read back.
+            drop(custom_drainer);
+            sort_and_write_data(args.for_num_workers)?;
+        }
+        Operation::DumpParquet => {
+            if args.for_num_workers != 0 {
+                bail!("for_num_workers specified but has no effect unless Operation chosen in DumpDistributedCustomDBs!")
+            }
+            let mut parquet_drainer = ParquetWriter::new(network(&args))?;
+            parse(&args, &source, checkpoint, &mut parquet_drainer)?;
+        }
+        Operation::DumpCsv | Operation::DumpJsonLines => {
+            if args.for_num_workers != 0 {
+                bail!("for_num_workers specified but has no effect unless Operation chosen in DumpDistributedCustomDBs!")
+            }
+            let format = match args.operation {
+                Operation::DumpCsv => TextFormat::Csv,
+                _ => TextFormat::JsonLines,
+            };
+            let options = TextOptions {
+                btc_columns: args.btc_columns,
+                gzip: args.gzip,
+                max_file_size: args.split_mb.map(|mb| mb << 20),
+            };
+            let mut text_drainer = TextWriter::new(format, options)?;
+            parse(&args, &source, checkpoint, &mut text_drainer)?;
+        }
+    }
+
+    Ok(())
+}
@@ -1,11 +1,27 @@
-use crate::transaction::{Block, BlockHash, InputOutputPair, Transaction, TxHash};
+use crate::network::Network;
+use crate::transaction::{Block, BlockHash, InputOutputPair, Transaction, TxHash, Value};
 
 pub const DEFAULT_PORT: u16 = 6969;
 
 #[tarpc::service]
 pub trait Search {
+    // The network the worker's data is from, so that the master doesn't mix up data from different
+    // networks.
+    async fn network() -> Network;
     async fn transactions_by_sources(targets: Vec<TxHash>) -> Vec<InputOutputPair>;
     async fn transactions_by_destinations(targets: Vec<TxHash>) -> Vec<InputOutputPair>;
     async fn get_transactions(targets: Vec<TxHash>) -> Vec<Transaction>;
     async fn get_blocks(targets: Vec<BlockHash>) -> Vec<Block>;
+    // Transactions with a fee between `min` and `max` satoshis, or a feerate between `min` and `max`
+    // satoshis per virtual byte. Both bounds are inclusive.
+    async fn transactions_by_fee(min: Value, max: Value) -> Vec<Transaction>;
+    async fn transactions_by_feerate(min: f64, max: f64) -> Vec<Transaction>;
+    // The iopairs of all outputs paid to one of the addresses, spent or not.
+    async fn outputs_by_address(targets: Vec<String>) -> Vec<InputOutputPair>;
+    // The iopairs of the outputs paid to one of the addresses that have been spent.
+    async fn spends_by_address(targets: Vec<String>) -> Vec<InputOutputPair>;
+    // The iopairs of OP_RETURN outputs whose data starts with `prefix`, or contains `needle`. An
+    // empty needle matches nothing.
+    async fn op_returns_by_prefix(prefix: Vec<u8>) -> Vec<InputOutputPair>;
+    async fn op_returns_containing(needle: Vec<u8>) -> Vec<InputOutputPair>;
 }
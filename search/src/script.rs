@@ -0,0 +1,426 @@
+use crate::network::Network;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::fmt;
+
+const OP_0: u8 = 0x00;
+const OP_PUSHDATA1: u8 = 0x4c;
+const OP_PUSHDATA2: u8 = 0x4d;
+const OP_PUSHDATA4: u8 = 0x4e;
+const OP_1: u8 = 0x51;
+const OP_13: u8 = 0x5d;
+const OP_16: u8 = 0x60;
+const OP_RETURN: u8 = 0x6a;
+const OP_DUP: u8 = 0x76;
+const OP_EQUAL: u8 = 0x87;
+const OP_EQUALVERIFY: u8 = 0x88;
+const OP_HASH160: u8 = 0xa9;
+const OP_CHECKSIG: u8 = 0xac;
+const OP_CHECKMULTISIG: u8 = 0xae;
+
+// The kinds of output scripts (scriptPubKeys) that Bitcoin Core treats as standard, and
+// `Nonstandard` for everything else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum ScriptType {
+    P2pk,
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    // Bare multisig, i.e. `OP_CHECKMULTISIG` right in the output script.
+    Multisig,
+    // Outputs whose script starts with `OP_RETURN`, which can never be spent. Most of them carry
+    // data.
+    OpReturn,
+    Nonstandard,
+}
+
+impl ScriptType {
+    pub const ALL: [ScriptType; 9] = [
+        ScriptType::P2pk,
+        ScriptType::P2pkh,
+        ScriptType::P2sh,
+        ScriptType::P2wpkh,
+        ScriptType::P2wsh,
+        ScriptType::P2tr,
+        ScriptType::Multisig,
+        ScriptType::OpReturn,
+        ScriptType::Nonstandard,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ScriptType::P2pk => "p2pk",
+            ScriptType::P2pkh => "p2pkh",
+            ScriptType::P2sh => "p2sh",
+            ScriptType::P2wpkh => "p2wpkh",
+            ScriptType::P2wsh => "p2wsh",
+            ScriptType::P2tr => "p2tr",
+            ScriptType::Multisig => "multisig",
+            ScriptType::OpReturn => "op_return",
+            ScriptType::Nonstandard => "nonstandard",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ScriptType> {
+        ScriptType::ALL.into_iter().find(|t| t.name() == name)
+    }
+}
+
+impl fmt::Display for ScriptType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl ToSql for ScriptType {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.name()))
+    }
+}
+
+impl FromSql for ScriptType {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<ScriptType> {
+        let name = value.as_str()?;
+        ScriptType::from_name(name).ok_or_else(|| FromSqlError::Other(name.into()))
+    }
+}
+
+// Protocols that are recognized by the data they put in OP_RETURN outputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum DataProtocol {
+    // Omni Layer, whose payloads start with "omni".
+    Omni,
+    // A single 32-byte push: a bare hash commitment, such as the ones OpenTimestamps calendars
+    // make. The hash itself doesn't tell which protocol made it.
+    Hash32Commitment,
+    // Runestones, which start with `OP_RETURN OP_13`.
+    Runes,
+}
+
+impl DataProtocol {
+    pub const ALL: [DataProtocol; 3] = [
+        DataProtocol::Omni,
+        DataProtocol::Hash32Commitment,
+        DataProtocol::Runes,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DataProtocol::Omni => "omni",
+            DataProtocol::Hash32Commitment => "hash32_commitment",
+            DataProtocol::Runes => "runes",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<DataProtocol> {
+        DataProtocol::ALL.into_iter().find(|p| p.name() == name)
+    }
+}
+
+impl fmt::Display for DataProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl ToSql for DataProtocol {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.name()))
+    }
+}
+
+impl FromSql for DataProtocol {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<DataProtocol> {
+        let name = value.as_str()?;
+        DataProtocol::from_name(name).ok_or_else(|| FromSqlError::Other(name.into()))
+    }
+}
+
+// Classifies `script` and decodes it to an address, if it has one.
+pub fn decode_script(script: &[u8], network: Network) -> (ScriptType, Option<String>) {
+    let script_type = classify(script);
+    (script_type, address(script, script_type, network))
+}
+
+// Classifies an output script by its template, following `Solver` in Bitcoin Core. Unlike there,
+// every script that starts with `OP_RETURN` is `ScriptType::OpReturn`, even if it doesn't consist
+// of pushes only, since Bitcoin Core never adds such outputs to the UTXO set either.
+pub fn classify(script: &[u8]) -> ScriptType {
+    match script {
+        [OP_RETURN, ..] => ScriptType::OpReturn,
+        [OP_DUP, OP_HASH160, 20, hash @ .., OP_EQUALVERIFY, OP_CHECKSIG] if hash.len() == 20 => {
+            ScriptType::P2pkh
+        }
+        [OP_HASH160, 20, hash @ .., OP_EQUAL] if hash.len() == 20 => ScriptType::P2sh,
+        [OP_0, 20, program @ ..] if program.len() == 20 => ScriptType::P2wpkh,
+        [OP_0, 32, program @ ..] if program.len() == 32 => ScriptType::P2wsh,
+        [OP_1, 32, program @ ..] if program.len() == 32 => ScriptType::P2tr,
+        [len, key @ .., OP_CHECKSIG] if *len as usize == key.len() && is_pubkey(key) => {
+            ScriptType::P2pk
+        }
+        _ if is_multisig(script) => ScriptType::Multisig,
+        _ => ScriptType::Nonstandard,
+    }
+}
+
+// The address that pays to `script`. P2PK outputs get the P2PKH address of their key, as block
+// explorers show them. Bare multisig, OP_RETURN and nonstandard outputs have no address.
+pub fn address(script: &[u8], script_type: ScriptType, network: Network) -> Option<String> {
+    match script_type {
+        ScriptType::P2pk => Some(base58_address(
+            network.p2pkh_version(),
+            &hash160(&script[1..script.len() - 1]),
+        )),
+        ScriptType::P2pkh => Some(base58_address(network.p2pkh_version(), &script[3..23])),
+        ScriptType::P2sh => Some(base58_address(network.p2sh_version(), &script[2..22])),
+        ScriptType::P2wpkh | ScriptType::P2wsh => {
+            bech32::segwit::encode_v0(network.bech32_hrp(), &script[2..]).ok()
+        }
+        ScriptType::P2tr => bech32::segwit::encode_v1(network.bech32_hrp(), &script[2..]).ok(),
+        ScriptType::Multisig | ScriptType::OpReturn | ScriptType::Nonstandard => None,
+    }
+}
+
+// The payload of an OP_RETURN output, and the protocol it belongs to, if it is recognized. If the
+// rest of the script consists of pushes only, the payload is all the data it pushes joined
+// together. Otherwise, it is the rest of the script as is. `script` has to be of type
+// `ScriptType::OpReturn`.
+pub fn op_return_data(script: &[u8]) -> (Vec<u8>, Option<DataProtocol>) {
+    let rest = &script[1..];
+    let data = match is_push_only(rest) {
+        true => {
+            let mut data = Vec::new();
+            let mut rest = rest;
+            while let Some((_, pushed, after)) = next_op(rest) {
+                data.extend_from_slice(pushed);
+                rest = after;
+            }
+            data
+        }
+        false => rest.to_vec(),
+    };
+
+    let protocol = match script {
+        [OP_RETURN, OP_13, ..] => Some(DataProtocol::Runes),
+        _ if data.starts_with(b"omni") => Some(DataProtocol::Omni),
+        [OP_RETURN, 32, hash @ ..] if hash.len() == 32 => Some(DataProtocol::Hash32Commitment),
+        _ => None,
+    };
+    (data, protocol)
+}
+
+fn base58_address(version: u8, hash: &[u8]) -> String {
+    bs58::encode(hash).with_check_version(version).into_string()
+}
+
+fn hash160(data: &[u8]) -> [u8; 20] {
+    ripemd::Ripemd160::digest(sha2::Sha256::digest(data)).into()
+}
+
+// Whether `data` has the size of a public key with the given prefix byte (`CPubKey::ValidSize` in
+// Bitcoin Core). The key itself is not checked.
+fn is_pubkey(data: &[u8]) -> bool {
+    match data.first() {
+        Some(2 | 3) => data.len() == 33,
+        Some(4 | 6 | 7) => data.len() == 65,
+        _ => false,
+    }
+}
+
+// Splits the next operation off `script`. Returns the opcode, the data it pushes (empty for
+// opcodes that push nothing), and the rest of the script, or `None` if a push runs past the end.
+fn next_op(script: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&opcode, rest) = script.split_first()?;
+    let (len, rest) = match opcode {
+        1..=0x4b => (opcode as usize, rest),
+        OP_PUSHDATA1 => (*rest.first()? as usize, &rest[1..]),
+        OP_PUSHDATA2 => (
+            u16::from_le_bytes(rest.get(..2)?.try_into().unwrap()) as usize,
+            &rest[2..],
+        ),
+        OP_PUSHDATA4 => (
+            u32::from_le_bytes(rest.get(..4)?.try_into().unwrap()) as usize,
+            &rest[4..],
+        ),
+        _ => (0, rest),
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((opcode, &rest[..len], &rest[len..]))
+}
+
+// Whether `script` consists of pushes only, counting `OP_1` to `OP_16` as pushes.
+fn is_push_only(mut script: &[u8]) -> bool {
+    while !script.is_empty() {
+        match next_op(script) {
+            Some((opcode, _, rest)) if opcode <= OP_16 => script = rest,
+            _ => return false,
+        }
+    }
+    true
+}
+
+// Whether `script` is `OP_m <pubkey>... OP_n OP_CHECKMULTISIG` with n keys and 1 <= m <= n <= 16.
+fn is_multisig(script: &[u8]) -> bool {
+    let (required, mut rest) = match script.split_first() {
+        Some((&op, rest)) if (OP_1..=OP_16).contains(&op) => (op - OP_1 + 1, rest),
+        _ => return false,
+    };
+    let mut keys = 0u8;
+    while let Some((_, _, after)) = next_op(rest).filter(|(_, key, _)| is_pubkey(key)) {
+        keys = keys.saturating_add(1);
+        rest = after;
+    }
+    match rest {
+        [op, OP_CHECKMULTISIG] if (OP_1..=OP_16).contains(op) => {
+            let total = op - OP_1 + 1;
+            keys == total && required <= total
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(script: &str, network: Network) -> (ScriptType, Option<String>) {
+        decode_script(&hex::decode(script).unwrap(), network)
+    }
+
+    // The output of the mainnet genesis block.
+    const GENESIS_P2PK: &str = "4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac";
+
+    // The example scripts and addresses of BIP 173 and BIP 350.
+    const P2WPKH: &str = "0014751e76e8199196d454941c45d1b3a323f1433bd6";
+    const P2WSH: &str = "00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262";
+    const P2TR: &str = "512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+    #[test]
+    fn mainnet_addresses() {
+        let cases = [
+            (
+                GENESIS_P2PK,
+                ScriptType::P2pk,
+                "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa",
+            ),
+            (
+                "76a914162c5ea71c0b23f5b9022ef047c4a86470a5b07088ac",
+                ScriptType::P2pkh,
+                "132F25rTsvBdp9JzLLBHP5mvGY66i1xdiM",
+            ),
+            (
+                "a914162c5ea71c0b23f5b9022ef047c4a86470a5b07087",
+                ScriptType::P2sh,
+                "33iFwdLuRpW1uK1RTRqsoi8rR4NpDzk66k",
+            ),
+            (
+                P2WPKH,
+                ScriptType::P2wpkh,
+                "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4",
+            ),
+            (
+                P2WSH,
+                ScriptType::P2wsh,
+                "bc1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qccfmv3",
+            ),
+            (
+                P2TR,
+                ScriptType::P2tr,
+                "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0",
+            ),
+        ];
+        for (script, script_type, address) in cases {
+            assert_eq!(
+                decode(script, Network::Mainnet),
+                (script_type, Some(address.to_string())),
+                "{}",
+                script
+            );
+        }
+    }
+
+    #[test]
+    fn testnet_addresses() {
+        let cases = [
+            (
+                GENESIS_P2PK,
+                ScriptType::P2pk,
+                "mpXwg4jMtRhuSpVq4xS3HFHmCmWp9NyGKt",
+            ),
+            (
+                "76a914162c5ea71c0b23f5b9022ef047c4a86470a5b07088ac",
+                ScriptType::P2pkh,
+                "mhYCK8wSgwctbFnc3u9fCzzF8XgodR9KHX",
+            ),
+            (
+                "a914162c5ea71c0b23f5b9022ef047c4a86470a5b07087",
+                ScriptType::P2sh,
+                "2MuGU1NGw3H1N76dy8ZTkRf87dQaz5oKCau",
+            ),
+            (
+                P2WPKH,
+                ScriptType::P2wpkh,
+                "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx",
+            ),
+            (
+                P2WSH,
+                ScriptType::P2wsh,
+                "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7",
+            ),
+            (
+                "5120000000c4a5cad46221b2a187905e5266362b99d5e91c6ce24d165dab93e86433",
+                ScriptType::P2tr,
+                "tb1pqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesf3hn0c",
+            ),
+        ];
+        for (script, script_type, address) in cases {
+            assert_eq!(
+                decode(script, Network::Testnet),
+                (script_type, Some(address.to_string())),
+                "{}",
+                script
+            );
+        }
+    }
+
+    #[test]
+    fn op_return_outputs() {
+        let omni = format!(
+            "6a14{}",
+            hex::encode(b"omni\0\0\0\0\0\0\0\x1f\0\0\0\0\x05\xf5\xe1\0")
+        );
+        let hash = "6a20".to_string() + &"ab".repeat(32);
+        let cases = [
+            // A bare OP_RETURN.
+            ("6a", "", None),
+            ("6a0568656c6c6f", "68656c6c6f", None),
+            // Pushes of all kinds, joined together.
+            ("6a0102004c03aabbcc51", "02aabbcc", None),
+            // Opcodes other than pushes, and a push that runs past the end of the script, leave
+            // the data as is.
+            ("6a0102ac", "0102ac", None),
+            ("6a05aabb", "05aabb", None),
+            ("6a5d0400c0a233", "00c0a233", Some(DataProtocol::Runes)),
+            (&omni, &omni[4..], Some(DataProtocol::Omni)),
+            (&hash, &hash[4..], Some(DataProtocol::Hash32Commitment)),
+        ];
+        for (script, data, protocol) in cases {
+            let script = hex::decode(script).unwrap();
+            assert_eq!(
+                decode_script(&script, Network::Mainnet),
+                (ScriptType::OpReturn, None)
+            );
+            assert_eq!(
+                op_return_data(&script),
+                (hex::decode(data).unwrap(), protocol),
+                "{}",
+                hex::encode(&script)
+            );
+        }
+    }
+}
@@ -1,119 +1,600 @@
-use crate::output_writer::OutputWriter;
-use crate::transaction::{Block, InputOutputPair, Transaction};
-use rusqlite::params;
-
-pub struct SQLiteDriver<'a> {
-    tx_inserter: rusqlite::Statement<'a>,
-    block_inserter: rusqlite::Statement<'a>,
-    iopair_inserter: rusqlite::Statement<'a>,
-}
-
-impl<'a, 'b: 'a> SQLiteDriver<'a> {
-    pub fn new(conn: &'b rusqlite::Connection) -> SQLiteDriver<'a> {
-        conn.pragma_update(None, "journal_mode", "memory").unwrap();
-
-        conn.pragma_update(None, "synchronous", "off").unwrap();
-
-        conn.execute(
-            "
-        CREATE TABLE transactions (
-            id                  BLOB NOT NULL,
-            version             UNSIGNED INT4 NOT NULL,
-            block               BLOB NOT NULL,
-            block_height        UNSIGNED INT4 NOT NULL,
-            size                UNSIGNED INT4 NOT NULL
-        );",
-            [],
-        )
-        .unwrap();
-
-        conn.execute(
-            "
-        CREATE TABLE blocks (
-            block_hash          BLOB NOT NULL,
-            version             UNSIGNED INT4 NOT NULL,
-            prev_block_id       BLOB NOT NULL,
-            merkle_root         BLOB NOT NULL,
-            unix_time           UNSIGNED INT4 NOT NULL,
-            tx_count            UNSIGNED INT4 NOT NULL,
-            height              UNSIGNED INT4 NOT NULL
-        );",
-            [],
-        )
-        .unwrap();
-
-        conn.execute(
-            "
-        CREATE TABLE input_output_pairs (
-            src_tx              BLOB NOT NULL,
-            src_index           UNSIGNED INT4 NOT NULL,
-            value               UNSIGNED INT8 NOT NULL,
-            dest_tx             BLOB,
-            dest_index          INT4
-        );",
-            [],
-        )
-        .unwrap();
-
-        SQLiteDriver {
-            tx_inserter: conn
-                .prepare("INSERT INTO transactions VALUES (?1, ?2, ?3, ?4, ?5);")
-                .unwrap(),
-            block_inserter: conn
-                .prepare("INSERT INTO blocks VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7);")
-                .unwrap(),
-            iopair_inserter: conn
-                .prepare("INSERT INTO input_output_pairs VALUES (?1, ?2, ?3, ?4, ?5);")
-                .unwrap(),
-        }
-    }
-}
-
-impl<'a> OutputWriter for SQLiteDriver<'a> {
-    fn insert_tx(&mut self, tx: Transaction) {
-        self.tx_inserter
-            .execute(params![
-                tx.id,
-                tx.version,
-                tx.block,
-                tx.block_height,
-                tx.size
-            ])
-            .unwrap();
-    }
-
-    fn insert_block(&mut self, b: Block) {
-        self.block_inserter
-            .execute(params![
-                b.id,
-                b.version,
-                b.prev_block_id,
-                b.merkle_root,
-                b.unix_time,
-                b.tx_count,
-                b.height
-            ])
-            .unwrap();
-    }
-
-    fn insert_iopair(&mut self, iopair: InputOutputPair) {
-        let dest_tx = match iopair.dest {
-            None => None,
-            Some(d) => Some(d.dest_tx),
-        };
-        let dest_index = match iopair.dest {
-            None => None,
-            Some(d) => Some(d.dest_index),
-        };
-
-        self.iopair_inserter
-            .execute(params![
-                iopair.source.src_tx,
-                iopair.source.src_index,
-                iopair.source.value,
-                dest_tx,
-                dest_index,
-            ])
-            .unwrap();
-    }
-}
+use crate::network::Network;
+use crate::output_writer::OutputWriter;
+use crate::transaction::{Block, InputOutputPair, Transaction};
+use anyhow::{bail, Context};
+use rusqlite::{params, OptionalExtension};
+
+// Number of blocks whose data is written in one SQLite transaction by default.
+pub const DEFAULT_BLOCKS_PER_TRANSACTION: usize = 1000;
+
+// Version of the layout of the tables, kept in the `user_version` of the database. It has to change
+// whenever a column is added, removed or moved, since rows are inserted by position.
+const SCHEMA_VERSION: i32 = 1;
+
+pub struct SQLiteDriver<'a> {
+    conn: &'a rusqlite::Connection,
+    // Writes are grouped into explicit transactions, which is much faster than committing every
+    // statement on its own. A transaction is committed after this many blocks, and on flush.
+    blocks_per_transaction: usize,
+    blocks_in_transaction: usize,
+    in_transaction: bool,
+    tx_inserter: rusqlite::Statement<'a>,
+    block_inserter: rusqlite::Statement<'a>,
+    iopair_inserter: rusqlite::Statement<'a>,
+    iopair_spender: rusqlite::Statement<'a>,
+    // Used to retract blocks that left the main chain.
+    block_remover: rusqlite::Statement<'a>,
+    tx_remover: rusqlite::Statement<'a>,
+    iopair_remover: rusqlite::Statement<'a>,
+    iopair_unspender: rusqlite::Statement<'a>,
+}
+
+impl<'a, 'b: 'a> SQLiteDriver<'a> {
+    // Writes to the database behind `conn`, creating its tables if they don't exist yet. Data in an
+    // existing database is kept and added to, as long as it is from the same network.
+    pub fn new(
+        conn: &'b rusqlite::Connection,
+        network: Network,
+    ) -> anyhow::Result<SQLiteDriver<'a>> {
+        SQLiteDriver::configure(conn)?;
+        SQLiteDriver::check_schema_version(conn)?;
+
+        SQLiteDriver::create_tables(conn).context("failed to create the tables")?;
+        match SQLiteDriver::recorded_network(conn)? {
+            Some(name) if name != network.name() => {
+                bail!("the database holds {} data, not {}", name, network)
+            }
+            Some(_) => println!("Appending to the existing database"),
+            None => {
+                conn.execute(
+                    "INSERT INTO metadata VALUES ('network', ?1);",
+                    params![network.name()],
+                )
+                .context("failed to write the network of the database")?;
+            }
+        }
+        SQLiteDriver::prepare(conn)
+    }
+
+    // Continues writing to a database filled by an earlier run, which the parser resumes from. The
+    // earlier run has to have parsed data from the same network.
+    pub fn resume(
+        conn: &'b rusqlite::Connection,
+        network: Network,
+    ) -> anyhow::Result<SQLiteDriver<'a>> {
+        SQLiteDriver::configure(conn)?;
+        SQLiteDriver::check_schema_version(conn)?;
+
+        match SQLiteDriver::recorded_network(conn)? {
+            Some(name) if name == network.name() => {}
+            Some(name) => bail!("the database holds {} data, not {}", name, network),
+            None => bail!("the database has no recorded network, so it can't be resumed"),
+        }
+        SQLiteDriver::prepare(conn)
+    }
+
+    // The name of the network the data in the database is from, if any was written yet.
+    fn recorded_network(conn: &rusqlite::Connection) -> anyhow::Result<Option<String>> {
+        conn.query_row(
+            "SELECT value FROM metadata WHERE key = 'network';",
+            [],
+            |r| r.get(0),
+        )
+        .optional()
+        .context("failed to read the network of the database")
+    }
+
+    // Trades durability for speed: a crash while parsing can corrupt the database, but the parser
+    // can be run again.
+    fn configure(conn: &rusqlite::Connection) -> anyhow::Result<()> {
+        conn.pragma_update(None, "journal_mode", "memory")
+            .context("failed to set the journal mode of the database")?;
+
+        conn.pragma_update(None, "synchronous", "off")
+            .context("failed to turn off synchronous writes to the database")
+    }
+
+    // Databases whose tables have a different layout can't be added to. A new database has no
+    // tables yet, and its version is 0 until they are created.
+    fn check_schema_version(conn: &rusqlite::Connection) -> anyhow::Result<()> {
+        let version: i32 = conn
+            .pragma_query_value(None, "user_version", |r| r.get(0))
+            .context("failed to read the schema version of the database")?;
+        let tables: u32 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table';",
+                [],
+                |r| r.get(0),
+            )
+            .context("failed to read the tables of the database")?;
+        match (version, tables) {
+            (SCHEMA_VERSION, _) | (0, 0) => Ok(()),
+            _ => bail!(
+                "schema version mismatch: the database has schema version {}, but this parser writes version {}",
+                version,
+                SCHEMA_VERSION
+            ),
+        }
+    }
+
+    fn create_tables(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+        conn.execute(
+            "
+        CREATE TABLE IF NOT EXISTS transactions (
+            id                  BLOB NOT NULL,
+            wtxid               BLOB NOT NULL,
+            version             UNSIGNED INT4 NOT NULL,
+            block               BLOB NOT NULL,
+            block_height        UNSIGNED INT4 NOT NULL,
+            size                UNSIGNED INT4 NOT NULL,
+            input_count         UNSIGNED INT4 NOT NULL,
+            output_count        UNSIGNED INT4 NOT NULL,
+            lock_time           UNSIGNED INT4 NOT NULL,
+            weight              UNSIGNED INT4 NOT NULL,
+            vsize               UNSIGNED INT4 NOT NULL,
+            is_coinbase         BOOLEAN NOT NULL,
+            fee                 UNSIGNED INT8,
+            feerate             REAL
+        );",
+            [],
+        )?;
+
+        conn.execute(
+            "
+        CREATE TABLE IF NOT EXISTS blocks (
+            block_hash          BLOB NOT NULL,
+            version             UNSIGNED INT4 NOT NULL,
+            prev_block_id       BLOB NOT NULL,
+            merkle_root         BLOB NOT NULL,
+            unix_time           UNSIGNED INT4 NOT NULL,
+            tx_count            UNSIGNED INT4 NOT NULL,
+            height              UNSIGNED INT4 NOT NULL
+        );",
+            [],
+        )?;
+
+        conn.execute(
+            "
+        CREATE TABLE IF NOT EXISTS input_output_pairs (
+            src_tx              BLOB NOT NULL,
+            src_block           BLOB NOT NULL,
+            src_index           UNSIGNED INT4 NOT NULL,
+            value               UNSIGNED INT8 NOT NULL,
+            dest_tx             BLOB,
+            dest_block          BLOB,
+            dest_index          INT4,
+            dest_sequence       UNSIGNED INT4,
+            script_type         TEXT NOT NULL,
+            address             TEXT,
+            data                BLOB,
+            data_protocol       TEXT
+        );",
+            [],
+        )?;
+
+        conn.execute(
+            "
+        CREATE TABLE IF NOT EXISTS metadata (
+            key                 TEXT NOT NULL,
+            value               TEXT NOT NULL
+        );",
+            [],
+        )?;
+
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION)
+    }
+
+    fn prepare(conn: &'b rusqlite::Connection) -> anyhow::Result<SQLiteDriver<'a>> {
+        let prepare = |sql: &str| {
+            conn.prepare(sql)
+                .with_context(|| format!("failed to prepare statement {}", sql.trim()))
+        };
+        Ok(SQLiteDriver {
+            conn,
+            blocks_per_transaction: DEFAULT_BLOCKS_PER_TRANSACTION,
+            blocks_in_transaction: 0,
+            in_transaction: false,
+            tx_inserter: prepare("INSERT INTO transactions VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14);")?,
+            block_inserter: prepare("INSERT INTO blocks VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7);")?,
+            iopair_inserter: prepare("INSERT INTO input_output_pairs VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12);")?,
+            iopair_spender: prepare(
+                "UPDATE input_output_pairs SET dest_tx = ?1, dest_index = ?2, dest_block = ?3,
+                dest_sequence = ?4
+                WHERE src_tx = ?5 AND src_index = ?6 AND src_block = ?7 AND dest_tx IS NULL;",
+            )?,
+            block_remover: prepare("DELETE FROM blocks WHERE block_hash = ?1;")?,
+            tx_remover: prepare("DELETE FROM transactions WHERE block = ?1;")?,
+            iopair_remover: prepare("DELETE FROM input_output_pairs WHERE src_block = ?1;")?,
+            iopair_unspender: prepare(
+                "UPDATE input_output_pairs SET dest_tx = NULL, dest_index = NULL, dest_block = NULL,
+                dest_sequence = NULL
+                WHERE dest_block = ?1;",
+            )?,
+        })
+    }
+
+    pub fn set_blocks_per_transaction(&mut self, blocks: usize) {
+        self.blocks_per_transaction = blocks.max(1);
+    }
+
+    // Indexes the columns that transactions, blocks and iopairs are looked up by. This is much
+    // faster once the data is loaded than keeping the indexes up to date while inserting.
+    pub fn create_indexes(conn: &rusqlite::Connection) -> anyhow::Result<()> {
+        conn.execute_batch(
+            "
+        CREATE INDEX IF NOT EXISTS transactions_id ON transactions (id);
+        CREATE INDEX IF NOT EXISTS blocks_block_hash ON blocks (block_hash);
+        CREATE INDEX IF NOT EXISTS input_output_pairs_src_tx ON input_output_pairs (src_tx);
+        CREATE INDEX IF NOT EXISTS input_output_pairs_dest_tx ON input_output_pairs (dest_tx);",
+        )
+        .context("failed to create indexes")
+    }
+
+    // Indexes the columns that outputs are spent by and that retracted blocks are removed by. Runs
+    // that resume or follow new blocks update rows written earlier, and without these indexes every
+    // spend and retraction scans a whole table.
+    pub fn create_update_indexes(conn: &rusqlite::Connection) -> anyhow::Result<()> {
+        conn.execute_batch(
+            "
+        CREATE INDEX IF NOT EXISTS blocks_block_hash ON blocks (block_hash);
+        CREATE INDEX IF NOT EXISTS transactions_block ON transactions (block);
+        CREATE INDEX IF NOT EXISTS input_output_pairs_src ON input_output_pairs (src_tx, src_index);
+        CREATE INDEX IF NOT EXISTS input_output_pairs_src_block ON input_output_pairs (src_block);
+        CREATE INDEX IF NOT EXISTS input_output_pairs_dest_block ON input_output_pairs (dest_block);",
+        )
+        .context("failed to create indexes for updating the data")
+    }
+
+    // Starts a transaction for the following writes, unless one is open already.
+    fn begin(&mut self) -> anyhow::Result<()> {
+        if !self.in_transaction {
+            self.conn
+                .execute_batch("BEGIN;")
+                .context("failed to begin a transaction")?;
+            self.in_transaction = true;
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self) -> anyhow::Result<()> {
+        if self.in_transaction {
+            self.conn
+                .execute_batch("COMMIT;")
+                .context("failed to commit a transaction")?;
+            self.in_transaction = false;
+        }
+        self.blocks_in_transaction = 0;
+        Ok(())
+    }
+}
+
+impl<'a> OutputWriter for SQLiteDriver<'a> {
+    fn insert_tx(&mut self, tx: Transaction) -> anyhow::Result<()> {
+        self.begin()?;
+        self.tx_inserter
+            .execute(params![
+                tx.id,
+                tx.wtxid,
+                tx.version,
+                tx.block,
+                tx.block_height,
+                tx.size,
+                tx.input_count,
+                tx.output_count,
+                tx.lock_time,
+                tx.weight,
+                tx.vsize,
+                tx.is_coinbase,
+                tx.fee,
+                tx.feerate
+            ])
+            .context("failed to write transaction")?;
+        Ok(())
+    }
+
+    fn insert_block(&mut self, b: Block) -> anyhow::Result<()> {
+        self.begin()?;
+        self.block_inserter
+            .execute(params![
+                b.id,
+                b.version,
+                b.prev_block_id,
+                b.merkle_root,
+                b.unix_time,
+                b.tx_count,
+                b.height
+            ])
+            .context("failed to write block")?;
+        Ok(())
+    }
+
+    fn insert_iopair(&mut self, iopair: InputOutputPair) -> anyhow::Result<()> {
+        self.begin()?;
+        let dest_tx = iopair.dest.map(|d| d.dest_tx);
+        let dest_index = iopair.dest.map(|d| d.dest_index);
+        let dest_block = iopair.dest.map(|d| d.dest_block);
+        let dest_sequence = iopair.dest.map(|d| d.sequence);
+
+        self.iopair_inserter
+            .execute(params![
+                iopair.source.src_tx,
+                iopair.source.src_block,
+                iopair.source.src_index,
+                iopair.source.value,
+                dest_tx,
+                dest_block,
+                dest_index,
+                dest_sequence,
+                iopair.source.script_type,
+                iopair.source.address,
+                iopair.source.data,
+                iopair.source.data_protocol,
+            ])
+            .context("failed to write iopair")?;
+        Ok(())
+    }
+
+    fn spend_output(&mut self, iopair: InputOutputPair) -> anyhow::Result<()> {
+        self.begin()?;
+        let dest = iopair.dest.unwrap();
+        self.iopair_spender
+            .execute(params![
+                dest.dest_tx,
+                dest.dest_index,
+                dest.dest_block,
+                dest.sequence,
+                iopair.source.src_tx,
+                iopair.source.src_index,
+                iopair.source.src_block,
+            ])
+            .context("failed to mark output as spent")?;
+        Ok(())
+    }
+
+    fn retract_block(&mut self, b: Block) -> anyhow::Result<()> {
+        self.begin()?;
+        self.block_remover
+            .execute(params![b.id])
+            .and_then(|_| self.tx_remover.execute(params![b.id]))
+            .and_then(|_| self.iopair_remover.execute(params![b.id]))
+            .and_then(|_| self.iopair_unspender.execute(params![b.id]))
+            .context("failed to retract block")?;
+        Ok(())
+    }
+
+    fn end_block(&mut self) -> anyhow::Result<()> {
+        self.blocks_in_transaction += 1;
+        match self.blocks_in_transaction >= self.blocks_per_transaction {
+            true => self.commit(),
+            false => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_source::BlockSource;
+    use crate::checkpoint::Checkpoint;
+    use crate::parser::Parser;
+    use crate::test_chain::{self, coinbase, mine, p2pkh, spend};
+    use crate::transaction::{BlockHash, MerkleRoot, TxHash};
+    use std::path::Path;
+
+    // Parses the blk files in `blocks_dir` into the database behind `conn`, continuing from
+    // `checkpoint` if there is one, and returns the checkpoint to continue from next time.
+    fn parse_run(
+        conn: &rusqlite::Connection,
+        blocks_dir: &Path,
+        checkpoint: Option<Checkpoint>,
+    ) -> Checkpoint {
+        let mut driver = match checkpoint.is_some() {
+            true => SQLiteDriver::resume(conn, Network::Regtest).unwrap(),
+            false => SQLiteDriver::new(conn, Network::Regtest).unwrap(),
+        };
+        let mut parser = Parser::new(&mut driver);
+        parser.set_network(Network::Regtest);
+        if let Some(c) = checkpoint {
+            parser.resume_from(c).unwrap();
+        }
+        let source = BlockSource::Directory {
+            path: blocks_dir.to_path_buf(),
+            start: 0,
+            end: None,
+        };
+        parser.parse(&source).unwrap();
+        parser.into_checkpoint().unwrap()
+    }
+
+    fn count(conn: &rusqlite::Connection, sql: &str) -> i64 {
+        conn.query_row(sql, [], |r| r.get(0)).unwrap()
+    }
+
+    #[test]
+    fn resuming_spends_outputs_written_by_the_earlier_run() {
+        // The first run writes the coinbase output of block 1 as unspent, and the second run parses
+        // block 3, which spends it.
+        let genesis = test_chain::genesis();
+        let cb1 = coinbase(1, &[(50_0000_0000, p2pkh(1))]);
+        let b1 = mine(&genesis, std::slice::from_ref(&cb1));
+        let b2 = mine(&b1, &[coinbase(2, &[(50_0000_0000, p2pkh(2))])]);
+        let s = spend(&[(cb1.id, 0)], &[(49_0000_0000, p2pkh(3))], false);
+        let b3 = mine(&b2, &[coinbase(3, &[(50_0000_0000, p2pkh(3))]), s.clone()]);
+
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        test_chain::append_blk_file(blocks_dir.path(), 0, &[&genesis, &b1, &b2]);
+        let checkpoint = parse_run(&conn, blocks_dir.path(), None);
+        test_chain::append_blk_file(blocks_dir.path(), 0, &[&b3]);
+        parse_run(&conn, blocks_dir.path(), Some(checkpoint));
+
+        let rows: Vec<(Option<TxHash>, Option<u32>)> = conn
+            .prepare("SELECT dest_tx, dest_index FROM input_output_pairs WHERE src_tx = ?1;")
+            .unwrap()
+            .query_map(params![cb1.id], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(rows, [(Some(s.id), Some(0))]);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM blocks;"), 4);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM transactions;"), 5);
+        // The coinbase source and coinbase output of each block, and the output of `s`.
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM input_output_pairs;"), 9);
+    }
+
+    #[test]
+    fn refuses_to_resume_a_database_from_another_network() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        SQLiteDriver::new(&conn, Network::Regtest).unwrap();
+
+        let error = SQLiteDriver::resume(&conn, Network::Mainnet)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("holds regtest data, not mainnet"));
+        assert!(SQLiteDriver::resume(&conn, Network::Regtest).is_ok());
+    }
+
+    #[test]
+    fn refuses_to_resume_a_database_without_a_network() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        SQLiteDriver::create_tables(&conn).unwrap();
+
+        let error = SQLiteDriver::resume(&conn, Network::Regtest).err().unwrap();
+        assert!(error.to_string().contains("no recorded network"));
+    }
+
+    // The block each output of `src_tx` is spent in, or `None` for unspent ones.
+    fn spent_in(conn: &rusqlite::Connection, src_tx: TxHash) -> Vec<Option<BlockHash>> {
+        conn.prepare("SELECT dest_block FROM input_output_pairs WHERE src_tx = ?1;")
+            .unwrap()
+            .query_map(params![src_tx], |r| r.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn follows_reorgs() {
+        let fork = test_chain::Fork::new();
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let mut driver = SQLiteDriver::new(&conn, Network::Regtest).unwrap();
+        SQLiteDriver::create_update_indexes(&conn).unwrap();
+        let mut parser = Parser::new(&mut driver);
+        parser.set_network(Network::Regtest);
+        fork.follow(&mut parser, |step, _| match step {
+            2 => {
+                assert_eq!(count(&conn, "SELECT COUNT(*) FROM blocks;"), 6);
+                assert_eq!(spent_in(&conn, fork.cb1.id), [Some(fork.c[0].id)]);
+                assert_eq!(spent_in(&conn, fork.cb2.id), [Some(fork.c[1].id)]);
+                assert_eq!(spent_in(&conn, fork.spend_a.id), [None]);
+            }
+            3 => {
+                assert_eq!(count(&conn, "SELECT COUNT(*) FROM blocks;"), 7);
+                assert_eq!(spent_in(&conn, fork.cb1.id), [None]);
+                assert_eq!(spent_in(&conn, fork.cb2.id), [Some(fork.a[0].id)]);
+                assert_eq!(spent_in(&conn, fork.spend_c.id), []);
+            }
+            _ => {}
+        });
+
+        // Nothing of `c` is left.
+        let c3 = fork.c[0].id;
+        let rows = conn
+            .query_row(
+                "SELECT COUNT(*) FROM transactions WHERE block = ?1;",
+                params![c3],
+                |r| r.get::<_, i64>(0),
+            )
+            .unwrap();
+        assert_eq!(rows, 0);
+        // The coinbase of each block, and `spend_a`.
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM transactions;"), 8);
+        // The coinbase source and coinbase output of each block, and the output of `spend_a`.
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM input_output_pairs;"), 15);
+    }
+
+    // A block with `n` as every byte of its hash.
+    fn block(n: u8) -> Block {
+        Block {
+            id: BlockHash::new([n; 32]),
+            version: 1,
+            prev_block_id: BlockHash::new([n - 1; 32]),
+            merkle_root: MerkleRoot::new([0; 32]),
+            unix_time: 1_700_000_000,
+            tx_count: 0,
+            height: n as u32,
+        }
+    }
+
+    #[test]
+    fn commits_after_every_batch_of_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        let reader = rusqlite::Connection::open(&path).unwrap();
+        let mut driver = SQLiteDriver::new(&conn, Network::Regtest).unwrap();
+        driver.set_blocks_per_transaction(2);
+
+        // What another connection sees of the blocks, and whether a transaction is still open.
+        let mut write = |n: u8| {
+            driver.begin_block(&block(n)).unwrap();
+            driver.insert_block(block(n)).unwrap();
+            driver.end_block().unwrap();
+            (
+                count(&reader, "SELECT COUNT(*) FROM blocks;"),
+                conn.is_autocommit(),
+            )
+        };
+        assert_eq!(write(1), (0, false));
+        assert_eq!(write(2), (2, true));
+        assert_eq!(write(3), (2, false));
+        driver.flush().unwrap();
+        assert_eq!(count(&reader, "SELECT COUNT(*) FROM blocks;"), 3);
+        assert!(conn.is_autocommit());
+    }
+
+    #[test]
+    fn refuses_databases_with_another_schema_version() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        SQLiteDriver::new(&conn, Network::Regtest).unwrap();
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        let error = SQLiteDriver::new(&conn, Network::Regtest)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("schema version mismatch"), "{}", error);
+        assert!(SQLiteDriver::resume(&conn, Network::Regtest).is_err());
+
+        // Tables without a version were not made by this parser.
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute("CREATE TABLE blocks (id BLOB);", []).unwrap();
+        assert!(SQLiteDriver::new(&conn, Network::Regtest).is_err());
+    }
+
+    #[test]
+    fn appends_to_an_existing_database_from_the_same_network() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        for n in 1..=2 {
+            let conn = rusqlite::Connection::open(&path).unwrap();
+            let mut driver = SQLiteDriver::new(&conn, Network::Regtest).unwrap();
+            driver.insert_block(block(n)).unwrap();
+            driver.flush().unwrap();
+        }
+
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM blocks;"), 2);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM metadata;"), 1);
+        let error = SQLiteDriver::new(&conn, Network::Mainnet)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("holds regtest data, not mainnet"));
+    }
+}
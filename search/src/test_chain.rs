@@ -0,0 +1,265 @@
+// Builds small regtest chains for tests. The blocks are mined against the regtest target, so they
+// pass the same checks as blocks written by a node.
+use crate::block_source::BlockSource;
+use crate::chain::{check_proof_of_work, PowParams};
+use crate::network::Network;
+use crate::parser::{hash_twice, merkle_root, Parser};
+use crate::transaction::{BlockHash, TxHash, Value};
+use std::io::Write;
+use std::path::Path;
+
+// The coinbase of the genesis block, which is the same on all networks.
+const GENESIS_COINBASE: &str = "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+
+const REGTEST_BITS: u32 = 0x207fffff;
+
+// A serialized transaction.
+#[derive(Debug, Clone)]
+pub(crate) struct Tx {
+    pub data: Vec<u8>,
+    pub id: TxHash,
+    // The virtual size (BIP 141): the weight divided by four, rounded up.
+    pub vsize: u32,
+}
+
+// A serialized block.
+#[derive(Debug, Clone)]
+pub(crate) struct Block {
+    pub data: Vec<u8>,
+    pub id: BlockHash,
+}
+
+impl Block {
+    fn time(&self) -> u32 {
+        u32::from_le_bytes(self.data[68..72].try_into().unwrap())
+    }
+}
+
+// A P2PKH output script, with `n` as every byte of the key hash.
+pub(crate) fn p2pkh(n: u8) -> Vec<u8> {
+    let mut script = vec![0x76, 0xa9, 0x14];
+    script.extend_from_slice(&[n; 20]);
+    script.extend_from_slice(&[0x88, 0xac]);
+    script
+}
+
+fn push_varint(data: &mut Vec<u8>, n: usize) {
+    match n {
+        0..0xfd => data.push(n as u8),
+        _ => {
+            data.push(0xfd);
+            data.extend_from_slice(&(n as u16).to_le_bytes());
+        }
+    }
+}
+
+// Serializes a version 2 transaction. `inputs` are the spent outputs with the input's script, and
+// segwit transactions get a 72-byte witness item for each input.
+fn tx(inputs: &[(TxHash, u32, Vec<u8>, u32)], outputs: &[(Value, Vec<u8>)], segwit: bool) -> Tx {
+    let mut body = Vec::new();
+    push_varint(&mut body, inputs.len());
+    for (src_tx, src_index, script, sequence) in inputs {
+        body.extend_from_slice(src_tx.as_ref());
+        body.extend_from_slice(&src_index.to_le_bytes());
+        push_varint(&mut body, script.len());
+        body.extend_from_slice(script);
+        body.extend_from_slice(&sequence.to_le_bytes());
+    }
+    push_varint(&mut body, outputs.len());
+    for (value, script) in outputs {
+        body.extend_from_slice(&value.to_le_bytes());
+        push_varint(&mut body, script.len());
+        body.extend_from_slice(script);
+    }
+
+    let version = 2u32.to_le_bytes();
+    let lock_time = 0u32.to_le_bytes();
+    let stripped = [&version[..], &body, &lock_time].concat();
+    let id = hash_twice(&stripped).into();
+    let mut data = version.to_vec();
+    if segwit {
+        data.extend_from_slice(&[0, 1]);
+    }
+    data.extend_from_slice(&body);
+    if segwit {
+        for _ in inputs {
+            data.extend_from_slice(&[1, 72]);
+            data.extend_from_slice(&[0x30; 72]);
+        }
+    }
+    data.extend_from_slice(&lock_time);
+    let weight = 3 * stripped.len() + data.len();
+    let vsize = weight.div_ceil(4) as u32;
+    Tx { data, id, vsize }
+}
+
+// A coinbase transaction for a block at `height`. Coinbases for the same height with the same
+// outputs have the same txid.
+pub(crate) fn coinbase(height: u32, outputs: &[(Value, Vec<u8>)]) -> Tx {
+    let mut script = vec![4];
+    script.extend_from_slice(&height.to_le_bytes());
+    tx(
+        &[(TxHash::new([0; 32]), u32::MAX, script, u32::MAX)],
+        outputs,
+        false,
+    )
+}
+
+// A transaction spending `inputs`, given as txid and output index.
+pub(crate) fn spend(inputs: &[(TxHash, u32)], outputs: &[(Value, Vec<u8>)], segwit: bool) -> Tx {
+    let inputs: Vec<_> = inputs
+        .iter()
+        .map(|(id, index)| (*id, *index, Vec::new(), 0xfffffffd))
+        .collect();
+    tx(&inputs, outputs, segwit)
+}
+
+fn block(prev: BlockHash, time: u32, nonce: u32, txs: &[Tx]) -> Block {
+    let root = merkle_root(txs.iter().map(|t| *t.id.as_ref()).collect());
+    let mut data = Vec::new();
+    data.extend_from_slice(&1u32.to_le_bytes());
+    data.extend_from_slice(prev.as_ref());
+    data.extend_from_slice(&root);
+    data.extend_from_slice(&time.to_le_bytes());
+    data.extend_from_slice(&REGTEST_BITS.to_le_bytes());
+    data.extend_from_slice(&nonce.to_le_bytes());
+    push_varint(&mut data, txs.len());
+    for t in txs {
+        data.extend_from_slice(&t.data);
+    }
+    let id = hash_twice(&data[..80]).into();
+    Block { data, id }
+}
+
+// The regtest genesis block.
+pub(crate) fn genesis() -> Block {
+    let coinbase = hex::decode(GENESIS_COINBASE).unwrap();
+    let coinbase = Tx {
+        id: hash_twice(&coinbase).into(),
+        vsize: coinbase.len() as u32,
+        data: coinbase,
+    };
+    let genesis = block(BlockHash::new([0; 32]), 1296688602, 2, &[coinbase]);
+    assert_eq!(genesis.id, Network::Regtest.genesis_hash());
+    genesis
+}
+
+// Mines a block with `txs` on top of `prev`.
+pub(crate) fn mine(prev: &Block, txs: &[Tx]) -> Block {
+    (0..)
+        .map(|nonce| block(prev.id, prev.time() + 600, nonce, txs))
+        .find(|b| check_proof_of_work(&b.id, REGTEST_BITS, &PowParams::REGTEST).is_ok())
+        .unwrap()
+}
+
+// The contents of a regtest blk file holding `blocks`.
+pub(crate) fn blk_data(blocks: &[&Block]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for b in blocks {
+        data.extend_from_slice(&Network::Regtest.magic());
+        data.extend_from_slice(&(b.data.len() as u32).to_le_bytes());
+        data.extend_from_slice(&b.data);
+    }
+    data
+}
+
+// Appends `blocks` to the blk file with the given index in `dir`, creating it if needed.
+pub(crate) fn append_blk_file(dir: &Path, index: u32, blocks: &[&Block]) {
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(dir.join(format!("blk{:05}.dat", index)))
+        .unwrap();
+    file.write_all(&blk_data(blocks)).unwrap();
+}
+
+// A chain that forks after block 2, into branch `a` with blocks 3 to 6 and branch `c` with blocks 3
+// to 5. `spend_a` spends the coinbase output of block 2, in `a[0]` and again in `c[1]`. `spend_c`
+// spends the coinbase output of block 1 in `c[0]`.
+pub(crate) struct Fork {
+    pub genesis: Block,
+    pub b1: Block,
+    pub b2: Block,
+    pub a: Vec<Block>,
+    pub c: Vec<Block>,
+    pub cb1: Tx,
+    pub cb2: Tx,
+    pub spend_a: Tx,
+    pub spend_c: Tx,
+}
+
+impl Fork {
+    pub(crate) fn new() -> Fork {
+        let genesis = genesis();
+        let cb1 = coinbase(1, &[(50_0000_0000, p2pkh(1))]);
+        let b1 = mine(&genesis, std::slice::from_ref(&cb1));
+        let cb2 = coinbase(2, &[(50_0000_0000, p2pkh(2))]);
+        let b2 = mine(&b1, std::slice::from_ref(&cb2));
+        let spend_a = spend(&[(cb2.id, 0)], &[(49_0000_0000, p2pkh(20))], false);
+        let spend_c = spend(&[(cb1.id, 0)], &[(49_0000_0000, p2pkh(30))], false);
+
+        let mut a: Vec<Block> = Vec::new();
+        for height in 3..=6 {
+            let mut txs = vec![coinbase(height, &[(50_0000_0000, p2pkh(height as u8))])];
+            if height == 3 {
+                txs.push(spend_a.clone());
+            }
+            a.push(mine(a.last().unwrap_or(&b2), &txs));
+        }
+        let mut c: Vec<Block> = Vec::new();
+        for height in 3..=5 {
+            let mut txs = vec![coinbase(
+                height,
+                &[(50_0000_0000, p2pkh(10 + height as u8))],
+            )];
+            match height {
+                3 => txs.push(spend_c.clone()),
+                4 => txs.push(spend_a.clone()),
+                _ => {}
+            }
+            c.push(mine(c.last().unwrap_or(&b2), &txs));
+        }
+        Fork {
+            genesis,
+            b1,
+            b2,
+            a,
+            c,
+            cb1,
+            cb2,
+            spend_a,
+            spend_c,
+        }
+    }
+
+    // The blocks appended to the blk file in each step of following the chain: first the genesis
+    // block and block 1, then block 2 with blocks 3 and 4 of `a`, then all of `c`, which takes over
+    // the main chain, and last blocks 5 and 6 of `a`, which take it back.
+    fn steps(&self) -> [Vec<&Block>; 4] {
+        [
+            vec![&self.genesis, &self.b1],
+            vec![&self.b2, &self.a[0], &self.a[1]],
+            self.c.iter().collect(),
+            vec![&self.a[2], &self.a[3]],
+        ]
+    }
+
+    // Has `parser` follow the blk file as the steps are appended to it, and calls `check` with the
+    // number of each step after parsing it.
+    pub(crate) fn follow(&self, parser: &mut Parser, mut check: impl FnMut(usize, &Parser)) {
+        let dir = tempfile::tempdir().unwrap();
+        let source = BlockSource::Directory {
+            path: dir.path().to_path_buf(),
+            start: 0,
+            end: None,
+        };
+        for (i, blocks) in self.steps().iter().enumerate() {
+            append_blk_file(dir.path(), 0, blocks);
+            match i {
+                0 => parser.start_following(&source).unwrap(),
+                _ => parser.parse_new_blocks(&source).unwrap(),
+            }
+            check(i, parser);
+        }
+    }
+}
@@ -0,0 +1,550 @@
+use crate::{
+    output_writer::OutputWriter,
+    transaction::{Block, InputOutputPair, Transaction, Value},
+};
+use anyhow::{bail, Context};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const SATOSHIS_PER_BTC: Value = 100_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextFormat {
+    Csv,
+    // One JSON object per line.
+    JsonLines,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TextOptions {
+    // Adds a column with the value in BTC next to every value in satoshis.
+    pub btc_columns: bool,
+    pub gzip: bool,
+    // Starts a new file for a table once its current file holds this many bytes (before
+    // compression). Each file has its own CSV header.
+    pub max_file_size: Option<u64>,
+}
+
+// Writes transactions, blocks and iopairs to CSV or JSON-lines files, one table per file, with the
+// same columns as the SQLite tables. Hashes are hex-encoded as they are displayed, and values are
+// in satoshis.
+pub struct TextWriter {
+    txs: TextTable,
+    blocks: TextTable,
+    iopairs: TextTable,
+}
+
+// The files of a table, of which only the last one is open.
+struct TextTable {
+    dir: PathBuf,
+    name: &'static str,
+    format: TextFormat,
+    options: TextOptions,
+    columns: Vec<&'static str>,
+    // Index of the current file, if the table is split into several.
+    part: u32,
+    out: Option<Sink>,
+    // Bytes written to the current file, before compression, and the rows among them.
+    written: u64,
+    rows: u64,
+}
+
+enum Sink {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+enum Field {
+    Text(String),
+    // A number, already formatted.
+    Number(String),
+    Bool(bool),
+    Null,
+}
+
+impl TextWriter {
+    pub fn new(format: TextFormat, options: TextOptions) -> anyhow::Result<TextWriter> {
+        TextWriter::in_dir(Path::new(""), format, options)
+    }
+
+    // Writes the files in `dir`.
+    fn in_dir(dir: &Path, format: TextFormat, options: TextOptions) -> anyhow::Result<TextWriter> {
+        let btc = options.btc_columns;
+        Ok(TextWriter {
+            txs: TextTable::create(
+                dir,
+                "transactions",
+                format,
+                options,
+                with_btc(TX_COLUMNS, "fee", "fee_btc", btc),
+            )?,
+            blocks: TextTable::create(dir, "blocks", format, options, BLOCK_COLUMNS.to_vec())?,
+            iopairs: TextTable::create(
+                dir,
+                "input_output_pairs",
+                format,
+                options,
+                with_btc(IOPAIR_COLUMNS, "value", "value_btc", btc),
+            )?,
+        })
+    }
+}
+
+impl OutputWriter for TextWriter {
+    fn insert_tx(&mut self, tx: Transaction) -> anyhow::Result<()> {
+        let btc = self.txs.options.btc_columns;
+        let mut fields = vec![
+            Field::hash(tx.id),
+            Field::hash(tx.wtxid),
+            Field::number(tx.version),
+            Field::hash(tx.block),
+            Field::number(tx.block_height),
+            Field::number(tx.size),
+            Field::number(tx.input_count),
+            Field::number(tx.output_count),
+            Field::number(tx.lock_time),
+            Field::number(tx.weight),
+            Field::number(tx.vsize),
+            Field::Bool(tx.is_coinbase),
+            Field::optional(tx.fee, Field::number),
+        ];
+        if btc {
+            fields.push(Field::optional(tx.fee, Field::btc));
+        }
+        fields.push(Field::optional(tx.feerate, Field::number));
+        self.txs.write_row(fields)
+    }
+
+    fn insert_block(&mut self, b: Block) -> anyhow::Result<()> {
+        self.blocks.write_row(vec![
+            Field::hash(b.id),
+            Field::number(b.version),
+            Field::hash(b.prev_block_id),
+            Field::hash(b.merkle_root),
+            Field::number(b.unix_time),
+            Field::number(b.tx_count),
+            Field::number(b.height),
+        ])
+    }
+
+    fn insert_iopair(&mut self, iopair: InputOutputPair) -> anyhow::Result<()> {
+        let btc = self.iopairs.options.btc_columns;
+        let source = iopair.source;
+        let dest = iopair.dest;
+        let mut fields = vec![
+            Field::hash(source.src_tx),
+            Field::hash(source.src_block),
+            Field::number(source.src_index),
+            Field::number(source.value),
+        ];
+        if btc {
+            fields.push(Field::btc(source.value));
+        }
+        fields.extend([
+            Field::optional(dest.map(|d| d.dest_tx), Field::hash),
+            Field::optional(dest.map(|d| d.dest_block), Field::hash),
+            Field::optional(dest.map(|d| d.dest_index), Field::number),
+            Field::optional(dest.map(|d| d.sequence), Field::number),
+            Field::Text(source.script_type.name().to_string()),
+            Field::optional(source.address, Field::Text),
+            Field::optional(source.data, |d| Field::Text(hex::encode(d))),
+            Field::optional(source.data_protocol, |p| Field::Text(p.name().to_string())),
+        ]);
+        self.iopairs.write_row(fields)
+    }
+
+    // Only happens in follow mode, which the text writers are not used with.
+    fn retract_block(&mut self, b: Block) -> anyhow::Result<()> {
+        bail!(
+            "block {:?} can't be retracted from files that were already written",
+            b.id
+        )
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.txs.flush()?;
+        self.blocks.flush()?;
+        self.iopairs.flush()
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.txs.close()?;
+        self.blocks.close()?;
+        self.iopairs.close()
+    }
+}
+
+const TX_COLUMNS: &[&str] = &[
+    "id",
+    "wtxid",
+    "version",
+    "block",
+    "block_height",
+    "size",
+    "input_count",
+    "output_count",
+    "lock_time",
+    "weight",
+    "vsize",
+    "is_coinbase",
+    "fee",
+    "feerate",
+];
+
+const BLOCK_COLUMNS: &[&str] = &[
+    "block_hash",
+    "version",
+    "prev_block_id",
+    "merkle_root",
+    "unix_time",
+    "tx_count",
+    "height",
+];
+
+const IOPAIR_COLUMNS: &[&str] = &[
+    "src_tx",
+    "src_block",
+    "src_index",
+    "value",
+    "dest_tx",
+    "dest_block",
+    "dest_index",
+    "dest_sequence",
+    "script_type",
+    "address",
+    "data",
+    "data_protocol",
+];
+
+// `columns`, with `btc_column` inserted after `value_column` if `btc` is set.
+fn with_btc(
+    columns: &[&'static str],
+    value_column: &str,
+    btc_column: &'static str,
+    btc: bool,
+) -> Vec<&'static str> {
+    let mut result = columns.to_vec();
+    if btc {
+        let i = columns.iter().position(|c| *c == value_column).unwrap();
+        result.insert(i + 1, btc_column);
+    }
+    result
+}
+
+impl Field {
+    fn hash(h: impl std::fmt::Debug) -> Field {
+        Field::Text(format!("{:?}", h))
+    }
+
+    fn number(n: impl ToString) -> Field {
+        Field::Number(n.to_string())
+    }
+
+    // Exact, with all 8 decimals.
+    fn btc(v: Value) -> Field {
+        Field::Number(format!(
+            "{}.{:08}",
+            v / SATOSHIS_PER_BTC,
+            v % SATOSHIS_PER_BTC
+        ))
+    }
+
+    fn optional<T>(x: Option<T>, f: impl FnOnce(T) -> Field) -> Field {
+        x.map(f).unwrap_or(Field::Null)
+    }
+}
+
+impl TextTable {
+    fn create(
+        dir: &Path,
+        name: &'static str,
+        format: TextFormat,
+        options: TextOptions,
+        columns: Vec<&'static str>,
+    ) -> anyhow::Result<TextTable> {
+        let mut table = TextTable {
+            dir: dir.to_path_buf(),
+            name,
+            format,
+            options,
+            columns,
+            part: 0,
+            out: None,
+            written: 0,
+            rows: 0,
+        };
+        table.open()?;
+        Ok(table)
+    }
+
+    fn path(&self) -> PathBuf {
+        let extension = match self.format {
+            TextFormat::Csv => "csv",
+            TextFormat::JsonLines => "jsonl",
+        };
+        let gz = match self.options.gzip {
+            true => ".gz",
+            false => "",
+        };
+        let file = match self.options.max_file_size {
+            Some(_) => format!("{}-{:05}.{}{}", self.name, self.part, extension, gz),
+            None => format!("{}.{}{}", self.name, extension, gz),
+        };
+        self.dir.join(file)
+    }
+
+    // Starts the next file of the table.
+    fn open(&mut self) -> anyhow::Result<()> {
+        let path = self.path();
+        let file =
+            File::create(&path).with_context(|| format!("failed to create {}", path.display()))?;
+        let file = BufWriter::new(file);
+        self.out = Some(match self.options.gzip {
+            true => Sink::Gzip(GzEncoder::new(file, Compression::default())),
+            false => Sink::Plain(file),
+        });
+        self.written = 0;
+        self.rows = 0;
+        if self.format == TextFormat::Csv {
+            let header = self.columns.join(",") + "\n";
+            self.write(header.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn write_row(&mut self, fields: Vec<Field>) -> anyhow::Result<()> {
+        let line = match self.format {
+            TextFormat::Csv => csv_line(&fields),
+            TextFormat::JsonLines => json_line(&self.columns, &fields),
+        };
+        // Every file gets at least one row, even if that row alone is too large.
+        if let Some(max) = self.options.max_file_size {
+            if self.rows > 0 && self.written + line.len() as u64 > max {
+                self.close()?;
+                self.part += 1;
+                self.open()?;
+            }
+        }
+        self.write(line.as_bytes())?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let result = match &mut self.out {
+            Some(Sink::Plain(w)) => w.write_all(data),
+            Some(Sink::Gzip(w)) => w.write_all(data),
+            None => bail!("{} was already completed", self.path().display()),
+        };
+        result.with_context(|| format!("failed to write {}", self.path().display()))?;
+        self.written += data.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        let result = match &mut self.out {
+            Some(Sink::Plain(w)) => w.flush(),
+            Some(Sink::Gzip(w)) => w.flush(),
+            None => Ok(()),
+        };
+        result.with_context(|| format!("failed to write {}", self.path().display()))
+    }
+
+    // Completes the current file, including the gzip trailer.
+    fn close(&mut self) -> anyhow::Result<()> {
+        let result = match self.out.take() {
+            Some(Sink::Plain(mut w)) => w.flush(),
+            Some(Sink::Gzip(w)) => w.finish().and_then(|mut w| w.flush()),
+            None => Ok(()),
+        };
+        result.with_context(|| format!("failed to write {}", self.path().display()))
+    }
+}
+
+fn csv_line(fields: &[Field]) -> String {
+    let mut line = fields
+        .iter()
+        .map(|f| match f {
+            Field::Text(s) if s.contains([',', '"', '\n', '\r']) => {
+                format!("\"{}\"", s.replace('"', "\"\""))
+            }
+            Field::Text(s) | Field::Number(s) => s.clone(),
+            Field::Bool(b) => b.to_string(),
+            Field::Null => String::new(),
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    line.push('\n');
+    line
+}
+
+fn json_line(columns: &[&str], fields: &[Field]) -> String {
+    let mut line = String::from("{");
+    for (i, (c, f)) in columns.iter().zip(fields.iter()).enumerate() {
+        if i > 0 {
+            line.push(',');
+        }
+        line.push_str(&serde_json::Value::from(*c).to_string());
+        line.push(':');
+        match f {
+            Field::Text(s) => line.push_str(&serde_json::Value::from(s.as_str()).to_string()),
+            Field::Number(n) => line.push_str(n),
+            Field::Bool(b) => line.push_str(&b.to_string()),
+            Field::Null => line.push_str("null"),
+        }
+    }
+    line.push_str("}\n");
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{BlockHash, MerkleRoot};
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    // A block with `n` as every byte of its hash.
+    fn block(n: u8) -> Block {
+        Block {
+            id: BlockHash::new([n; 32]),
+            version: 1,
+            prev_block_id: BlockHash::new([n - 1; 32]),
+            merkle_root: MerkleRoot::new([0; 32]),
+            unix_time: 1_700_000_000,
+            tx_count: 1,
+            height: n as u32,
+        }
+    }
+
+    // Writes blocks 1 to `n` to the text files in `dir`.
+    fn write_blocks(dir: &Path, format: TextFormat, options: TextOptions, n: u8) {
+        let mut writer = TextWriter::in_dir(dir, format, options).unwrap();
+        for i in 1..=n {
+            writer.insert_block(block(i)).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    fn number(f: Field) -> String {
+        match f {
+            Field::Number(n) => n,
+            _ => panic!("not a number"),
+        }
+    }
+
+    #[test]
+    fn quotes_csv_fields_that_need_it() {
+        let fields = [
+            Field::Text(String::from("plain")),
+            Field::Text(String::from("a,b")),
+            Field::Text(String::from("say \"hi\"")),
+            Field::Text(String::from("two\nlines")),
+            Field::Number(String::from("1.5")),
+            Field::Bool(true),
+            Field::Null,
+        ];
+        assert_eq!(
+            csv_line(&fields),
+            "plain,\"a,b\",\"say \"\"hi\"\"\",\"two\nlines\",1.5,true,\n"
+        );
+    }
+
+    #[test]
+    fn encodes_json_lines() {
+        let columns = ["text", "number", "bool", "null"];
+        let fields = [
+            Field::Text(String::from("say \"hi\"\n")),
+            Field::Number(String::from("0.5")),
+            Field::Bool(false),
+            Field::Null,
+        ];
+        let line = json_line(&columns, &fields);
+        assert_eq!(
+            line,
+            "{\"text\":\"say \\\"hi\\\"\\n\",\"number\":0.5,\"bool\":false,\"null\":null}\n"
+        );
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["text"], "say \"hi\"\n");
+        assert_eq!(value["number"], 0.5);
+    }
+
+    #[test]
+    fn formats_btc_with_all_decimals() {
+        assert_eq!(number(Field::btc(0)), "0.00000000");
+        assert_eq!(number(Field::btc(1)), "0.00000001");
+        assert_eq!(number(Field::btc(123_456_789)), "1.23456789");
+        assert_eq!(number(Field::btc(50 * SATOSHIS_PER_BTC)), "50.00000000");
+        assert_eq!(
+            number(Field::btc(21_000_000 * SATOSHIS_PER_BTC)),
+            "21000000.00000000"
+        );
+    }
+
+    #[test]
+    fn starts_a_new_file_once_the_current_one_is_full() {
+        // Three hashes, "1", "1700000000", "1", a one-digit height, six commas and a newline.
+        let row_len = 3 * 64 + 13 + 7;
+        let header_len = BLOCK_COLUMNS.join(",").len() as u64 + 1;
+        // (max_file_size, rows in each file)
+        let cases = [
+            (header_len + 2 * row_len + 10, vec![2, 2, 1]),
+            (header_len + row_len, vec![1, 1, 1, 1, 1]),
+            // A row larger than a whole file still gets a file of its own.
+            (10, vec![1, 1, 1, 1, 1]),
+        ];
+        for (max_file_size, expected) in cases {
+            let dir = tempfile::tempdir().unwrap();
+            let options = TextOptions {
+                max_file_size: Some(max_file_size),
+                ..TextOptions::default()
+            };
+            write_blocks(dir.path(), TextFormat::Csv, options, 5);
+
+            let mut rows = Vec::new();
+            for part in 0..expected.len() {
+                let path = dir.path().join(format!("blocks-{:05}.csv", part));
+                let data = std::fs::read_to_string(path).unwrap();
+                let mut lines = data.lines();
+                assert_eq!(lines.next().unwrap(), BLOCK_COLUMNS.join(","));
+                rows.push(lines.count());
+            }
+            assert_eq!(rows, expected, "max_file_size {}", max_file_size);
+            let next = dir.path().join(format!("blocks-{:05}.csv", expected.len()));
+            assert!(!next.exists());
+        }
+    }
+
+    #[test]
+    fn completes_gzip_files_on_close() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = TextOptions {
+            gzip: true,
+            ..TextOptions::default()
+        };
+        write_blocks(dir.path(), TextFormat::JsonLines, options, 2);
+
+        let compressed = std::fs::read(dir.path().join("blocks.jsonl.gz")).unwrap();
+        let mut data = String::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_string(&mut data)
+            .unwrap();
+        let lines: Vec<serde_json::Value> = data
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["block_hash"], hex::encode([2; 32]));
+        assert_eq!(lines[1]["height"], 2);
+
+        // The trailer ends in the size of the uncompressed data.
+        let trailer = &compressed[compressed.len() - 4..];
+        assert_eq!(
+            u32::from_le_bytes(trailer.try_into().unwrap()),
+            data.len() as u32
+        );
+    }
+}
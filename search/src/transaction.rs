@@ -1,123 +1,168 @@
-use duplicate::duplicate;
-use itertools::Itertools;
-use rusqlite::types::{FromSqlResult, ToSqlOutput, ValueRef};
-use serde::{Deserialize, Serialize};
-use std::cmp::{Ord, PartialOrd};
-use std::fmt;
-use std::hash::Hash;
-use std::string::String;
-
-// Note that Hash256 types are stored internally in little-endian! (reverse byte order). This is
-// due to an accident in the original Bitcoin protocol, and we keep it this way for efficiency
-// reasons.
-pub type Hash256 = [u8; 32];
-
-// Define distinct hash types to avoid mixing them up accidentally. But keep the same
-// implementations of all three.
-duplicate! {
-    [ T; [TxHash]; [BlockHash]; [MerkleRoot] ]
-#[derive(Clone, Copy, PartialEq, Hash, Eq, Serialize, Deserialize, PartialOrd, Ord)]
-pub struct T(Hash256);
-
-impl T {
-    pub fn new_from_str(s: &str) -> T {
-        let s = s.trim();
-        assert_eq!(s.len(), 64);
-        let mut result_bytes: [u8; 32] = [0; 32];
-        hex::decode_to_slice(&s, &mut result_bytes).unwrap();
-        result_bytes.reverse();
-        T(result_bytes.into())
-    }
-}
-
-impl fmt::Debug for T {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", print_hash(&self.0))
-    }
-}
-
-impl T {
-    pub fn new(data: [u8; 32]) -> T {
-        T{0: data}
-    }
-}
-
-impl std::convert::From<[u8; 32]> for T {
-    fn from(data: [u8; 32]) -> T {
-        T{ 0: data }
-    }
-}
-
-impl std::convert::AsRef<[u8; 32]> for T {
-    fn as_ref(&self) -> &[u8; 32] {
-        &self.0
-    }
-}
-
-impl rusqlite::ToSql for T {
-    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
-        Ok(ToSqlOutput::from(self.0.iter().as_slice()))
-    }
-}
-
-impl rusqlite::types::FromSql for T {
-    fn column_result(value: ValueRef<'_>) -> FromSqlResult<T> {
-        let slice = value.as_blob()?;
-        let slice: [u8; 32] = slice.try_into().map_err(|_| rusqlite::types::FromSqlError::InvalidBlobSize { expected_size: 32, blob_size: slice.len() })?;
-        Ok(T { 0: slice })
-    }
-}
-
-}
-
-fn print_hash(h: &Hash256) -> String {
-    // Since these are stored in reverse byte order, we need to iterate backwards.
-    format!("{:02x}", h.iter().rev().format(""))
-}
-
-// Value is always denominated in Satoshis. (1e-8 BTC)
-pub type Value = u64;
-
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-pub struct Transaction {
-    pub id: TxHash,
-    pub version: u32,
-    pub block: BlockHash,
-    pub block_height: u32,
-    pub size: u32,
-}
-
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-pub struct Block {
-    pub id: BlockHash,
-    pub version: u32,
-    pub prev_block_id: BlockHash,
-    pub merkle_root: MerkleRoot,
-    pub unix_time: u32,
-    pub tx_count: u32,
-    pub height: u32,
-}
-
-// We define the following three struct types to denote inputs and outputs of Bitcoin transactions.
-
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
-// An InputOutputPair is a "link" between two transactions. `source` is the parent transaction, and
-// `dest` is the child. Note that source must exist, but dest might not (if the relevant output is
-// unspent).
-pub struct InputOutputPair {
-    pub source: Output,
-    pub dest: Option<Input>,
-}
-
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
-pub struct Output {
-    pub src_tx: TxHash,
-    pub src_index: u32,
-    pub value: Value,
-}
-
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
-pub struct Input {
-    pub dest_tx: TxHash,
-    pub dest_index: u32,
-}
+use crate::script::{DataProtocol, ScriptType};
+use duplicate::duplicate;
+use itertools::Itertools;
+use rusqlite::types::{FromSqlResult, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ord, PartialOrd};
+use std::fmt;
+use std::hash::Hash;
+use std::string::String;
+
+// Note that Hash256 types are stored internally in little-endian! (reverse byte order). This is
+// due to an accident in the original Bitcoin protocol, and we keep it this way for efficiency
+// reasons.
+pub type Hash256 = [u8; 32];
+
+// Define distinct hash types to avoid mixing them up accidentally. But keep the same
+// implementations of all three.
+duplicate! {
+    [ T; [TxHash]; [BlockHash]; [MerkleRoot] ]
+#[derive(Clone, Copy, PartialEq, Hash, Eq, Serialize, Deserialize, PartialOrd, Ord)]
+pub struct T(Hash256);
+
+impl T {
+    pub fn new_from_str(s: &str) -> T {
+        let s = s.trim();
+        assert_eq!(s.len(), 64);
+        let mut result_bytes: [u8; 32] = [0; 32];
+        hex::decode_to_slice(&s, &mut result_bytes).unwrap();
+        result_bytes.reverse();
+        T(result_bytes.into())
+    }
+}
+
+impl fmt::Debug for T {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", print_hash(&self.0))
+    }
+}
+
+impl T {
+    pub fn new(data: [u8; 32]) -> T {
+        T{0: data}
+    }
+
+    // The hash in the byte order it is displayed in (big-endian), as opposed to how it is stored.
+    pub fn to_display_bytes(&self) -> Hash256 {
+        let mut bytes = self.0;
+        bytes.reverse();
+        bytes
+    }
+}
+
+impl std::convert::From<[u8; 32]> for T {
+    fn from(data: [u8; 32]) -> T {
+        T{ 0: data }
+    }
+}
+
+impl std::convert::AsRef<[u8; 32]> for T {
+    fn as_ref(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl rusqlite::ToSql for T {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.0.iter().as_slice()))
+    }
+}
+
+impl rusqlite::types::FromSql for T {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<T> {
+        let slice = value.as_blob()?;
+        let slice: [u8; 32] = slice.try_into().map_err(|_| rusqlite::types::FromSqlError::InvalidBlobSize { expected_size: 32, blob_size: slice.len() })?;
+        Ok(T { 0: slice })
+    }
+}
+
+}
+
+// Coinbase transactions have a single input that spends this "null" outpoint. Iopairs with this
+// source stand for the coins the miner claimed: their value is the total of the coinbase's outputs,
+// and their destination is the coinbase transaction. That total is the block subsidy plus the fees,
+// unless the miner claimed less than allowed.
+pub const COINBASE_SOURCE_TX: TxHash = TxHash([0; 32]);
+pub const COINBASE_SOURCE_INDEX: u32 = u32::MAX;
+
+fn print_hash(h: &Hash256) -> String {
+    // Since these are stored in reverse byte order, we need to iterate backwards.
+    format!("{:02x}", h.iter().rev().format(""))
+}
+
+// Value is always denominated in Satoshis. (1e-8 BTC)
+pub type Value = u64;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Transaction {
+    // The txid, which does not commit to witness data.
+    pub id: TxHash,
+    // The witness txid (BIP 141). Same as `id` for transactions without witnesses.
+    pub wtxid: TxHash,
+    pub version: u32,
+    pub block: BlockHash,
+    pub block_height: u32,
+    pub size: u32,
+    pub input_count: u32,
+    pub output_count: u32,
+    pub lock_time: u32,
+    // The weight (BIP 141): three times the size without witness data, plus the full size. The
+    // virtual size is the weight divided by four, rounded up.
+    pub weight: u32,
+    pub vsize: u32,
+    pub is_coinbase: bool,
+    // What the inputs spend minus what the outputs pay out, and that divided by `vsize`. `None` for
+    // coinbase transactions, and for transactions whose inputs were not all found in the parsed
+    // data.
+    pub fee: Option<Value>,
+    pub feerate: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Block {
+    pub id: BlockHash,
+    pub version: u32,
+    pub prev_block_id: BlockHash,
+    pub merkle_root: MerkleRoot,
+    pub unix_time: u32,
+    pub tx_count: u32,
+    pub height: u32,
+}
+
+// We define the following three struct types to denote inputs and outputs of Bitcoin transactions.
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+// An InputOutputPair is a "link" between two transactions. `source` is the parent transaction, and
+// `dest` is the child. Note that source must exist, but dest might not (if the relevant output is
+// unspent). Both transactions are identified by their txid together with their block, since two
+// transactions can share a txid (see BIP 30).
+pub struct InputOutputPair {
+    pub source: Output,
+    pub dest: Option<Input>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Output {
+    pub src_tx: TxHash,
+    // Block of the source transaction.
+    pub src_block: BlockHash,
+    pub src_index: u32,
+    pub value: Value,
+    // The kind of scriptPubKey the output pays to, and the address it pays to, if it has one.
+    pub script_type: ScriptType,
+    pub address: Option<String>,
+    // For OP_RETURN outputs, the data they carry and the protocol it belongs to, if recognized.
+    pub data: Option<Vec<u8>>,
+    pub data_protocol: Option<DataProtocol>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Input {
+    pub dest_tx: TxHash,
+    // Block of the dest transaction.
+    pub dest_block: BlockHash,
+    pub dest_index: u32,
+    // The sequence number of the input, used to signal replaceability (BIP 125) and relative
+    // timelocks (BIP 68).
+    pub sequence: u32,
+}
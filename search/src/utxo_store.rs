@@ -0,0 +1,489 @@
+use crate::parser::{OutputHashAndIndex, UnmatchedOutput};
+use anyhow::{bail, Context};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+
+// Memory an entry of the `SQLiteUtxoStore` cache takes up besides its address: the key and entry in
+// the hash map's table plus a control byte, and the table's spare room, since at most 7/8 of it is
+// used.
+const CACHE_ENTRY_OVERHEAD: usize = (size_of::<(OutputHashAndIndex, CacheEntry)>() + 1) * 8 / 7;
+
+// Where the parser keeps the outputs that have not been spent yet. On the full chain, that is the
+// whole UTXO set, so it may have to live on disk.
+pub trait UtxoStore {
+    // Adds an output. If `may_exist` is set, an output may already be stored under the same key,
+    // and is replaced and returned. Otherwise, the caller knows there is none, which saves the store
+    // from looking for it. Only coinbases can share their txid with an earlier transaction (see
+    // `Parser::resolve_duplicate_output`).
+    fn insert(
+        &mut self,
+        key: OutputHashAndIndex,
+        output: UnmatchedOutput,
+        may_exist: bool,
+    ) -> anyhow::Result<Option<UnmatchedOutput>>;
+
+    fn remove(&mut self, key: &OutputHashAndIndex) -> anyhow::Result<Option<UnmatchedOutput>>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Calls `f` on every output that was not written out yet, and marks it as written. Stops at the
+    // first error of `f`.
+    fn write_unwritten(
+        &mut self,
+        f: &mut dyn FnMut(&OutputHashAndIndex, &UnmatchedOutput) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()>;
+
+    // Makes the outputs available to a later run, which resumes from a checkpoint.
+    fn into_saved(self: Box<Self>) -> anyhow::Result<SavedOutputs>;
+}
+
+// The unspent outputs as stored in a checkpoint.
+#[derive(Serialize, Deserialize)]
+pub enum SavedOutputs {
+    InMemory(HashMap<OutputHashAndIndex, UnmatchedOutput>),
+    // The outputs are in the `SQLiteUtxoStore` database at this path.
+    OnDisk(PathBuf),
+}
+
+// Keeps all outputs in memory. This is the fastest store, but needs tens of GB on the full chain.
+#[derive(Default)]
+pub struct MemoryUtxoStore(HashMap<OutputHashAndIndex, UnmatchedOutput>);
+
+impl UtxoStore for MemoryUtxoStore {
+    fn insert(
+        &mut self,
+        key: OutputHashAndIndex,
+        output: UnmatchedOutput,
+        _may_exist: bool,
+    ) -> anyhow::Result<Option<UnmatchedOutput>> {
+        Ok(self.0.insert(key, output))
+    }
+
+    fn remove(&mut self, key: &OutputHashAndIndex) -> anyhow::Result<Option<UnmatchedOutput>> {
+        Ok(self.0.remove(key))
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn write_unwritten(
+        &mut self,
+        f: &mut dyn FnMut(&OutputHashAndIndex, &UnmatchedOutput) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        for (k, v) in self.0.iter_mut() {
+            if !v.written {
+                f(k, v)?;
+                v.written = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn into_saved(self: Box<Self>) -> anyhow::Result<SavedOutputs> {
+        Ok(SavedOutputs::InMemory(self.0))
+    }
+}
+
+// Keeps outputs in an SQLite database on disk, with a cache in front of it. Most outputs are spent
+// soon after they are created, so they come and go while still in the cache, without ever touching
+// the disk. Like Bitcoin Core's dbcache, the whole cache is written out once it is full.
+pub struct SQLiteUtxoStore {
+    conn: rusqlite::Connection,
+    path: PathBuf,
+    cache: HashMap<OutputHashAndIndex, CacheEntry>,
+    // Memory the cache may take up, and the memory it takes up now, in bytes.
+    cache_size: usize,
+    cache_bytes: usize,
+    len: usize,
+    // Whether the database has been changed since it was opened. See `open`.
+    in_use: bool,
+}
+
+struct CacheEntry {
+    // `None` if the output was removed, but may still be in the database.
+    output: Option<UnmatchedOutput>,
+    // Whether the entry differs from what is in the database.
+    dirty: bool,
+    // Whether the output is known not to be in the database, so removing it doesn't have to
+    // delete it there.
+    fresh: bool,
+}
+
+impl CacheEntry {
+    // Memory the entry takes up in the cache, in bytes.
+    fn size(&self) -> usize {
+        let address = self.output.as_ref().and_then(|o| o.address.as_ref());
+        CACHE_ENTRY_OVERHEAD + address.map_or(0, |a| a.capacity())
+    }
+}
+
+impl SQLiteUtxoStore {
+    // Creates an empty store at `path`, replacing any outputs stored there before. The cache may
+    // take up about `cache_size` bytes.
+    pub fn create(path: &Path, cache_size: usize) -> anyhow::Result<SQLiteUtxoStore> {
+        let store = SQLiteUtxoStore::connect(path, cache_size)?;
+        store
+            .conn
+            .execute_batch(
+                "
+        DROP TABLE IF EXISTS utxos;
+        CREATE TABLE utxos (
+            tx                  BLOB NOT NULL,
+            idx                 UNSIGNED INT4 NOT NULL,
+            block               BLOB NOT NULL,
+            value               UNSIGNED INT8 NOT NULL,
+            script_type         TEXT NOT NULL,
+            address             TEXT,
+            written             BOOLEAN NOT NULL,
+            PRIMARY KEY (tx, idx)
+        ) WITHOUT ROWID;
+        PRAGMA user_version = 0;",
+            )
+            .with_context(|| format!("failed to create UTXO database {}", path.display()))?;
+        Ok(store)
+    }
+
+    // Opens the store that a checkpoint refers to. Changes are written to the database while
+    // parsing, so if the run that saved the checkpoint was followed by one that didn't finish, the
+    // database no longer matches the checkpoint, and this fails.
+    pub fn open(path: &Path, cache_size: usize) -> anyhow::Result<SQLiteUtxoStore> {
+        if !path.exists() {
+            bail!("UTXO database {} does not exist", path.display());
+        }
+        let mut store = SQLiteUtxoStore::connect(path, cache_size)?;
+        let in_use: u32 = store
+            .conn
+            .pragma_query_value(None, "user_version", |r| r.get(0))
+            .with_context(|| format!("failed to read UTXO database {}", path.display()))?;
+        if in_use != 0 {
+            bail!(
+                "UTXO database {} was changed by a run that did not finish, so it no longer \
+                matches the checkpoint; parse from scratch instead",
+                path.display()
+            );
+        }
+        let len: u64 = store
+            .conn
+            .query_row("SELECT COUNT(*) FROM utxos;", [], |r| r.get(0))
+            .with_context(|| format!("failed to read UTXO database {}", path.display()))?;
+        store.len = len as usize;
+        Ok(store)
+    }
+
+    fn connect(path: &Path, cache_size: usize) -> anyhow::Result<SQLiteUtxoStore> {
+        let conn = rusqlite::Connection::open(path)
+            .with_context(|| format!("failed to open UTXO database {}", path.display()))?;
+        conn.pragma_update(None, "journal_mode", "memory")?;
+        conn.pragma_update(None, "synchronous", "off")?;
+        Ok(SQLiteUtxoStore {
+            conn,
+            path: path.to_path_buf(),
+            cache: HashMap::new(),
+            cache_size,
+            cache_bytes: 0,
+            len: 0,
+            in_use: false,
+        })
+    }
+
+    // Looks up `key`, in the cache first and then in the database. Outputs found in the database
+    // are added to the cache.
+    fn lookup(&mut self, key: &OutputHashAndIndex) -> anyhow::Result<Option<UnmatchedOutput>> {
+        if let Some(e) = self.cache.get(key) {
+            return Ok(e.output.clone());
+        }
+        let output = self
+            .conn
+            .prepare_cached(
+                "SELECT block, value, script_type, address, written FROM utxos \
+                WHERE tx = ?1 AND idx = ?2;",
+            )
+            .and_then(|mut s| {
+                s.query_row(params![key.tx, key.index], |r| {
+                    Ok(UnmatchedOutput {
+                        block: r.get(0)?,
+                        value: r.get(1)?,
+                        script_type: r.get(2)?,
+                        address: r.get(3)?,
+                        written: r.get(4)?,
+                    })
+                })
+                .optional()
+            })
+            .with_context(|| format!("failed to read UTXO database {}", self.path.display()))?;
+        if let Some(o) = &output {
+            self.cache_entry(
+                *key,
+                CacheEntry {
+                    output: Some(o.clone()),
+                    dirty: false,
+                    fresh: false,
+                },
+            );
+        }
+        Ok(output)
+    }
+
+    // Adds or replaces the cache entry for `key`.
+    fn cache_entry(&mut self, key: OutputHashAndIndex, entry: CacheEntry) {
+        self.cache_bytes += entry.size();
+        if let Some(old) = self.cache.insert(key, entry) {
+            self.cache_bytes -= old.size();
+        }
+    }
+
+    fn uncache_entry(&mut self, key: &OutputHashAndIndex) {
+        if let Some(old) = self.cache.remove(key) {
+            self.cache_bytes -= old.size();
+        }
+    }
+
+    // Records in the database that it is being changed, before the first change is written.
+    fn mark_in_use(&mut self) -> rusqlite::Result<()> {
+        if !self.in_use {
+            self.conn.pragma_update(None, "user_version", 1)?;
+            self.in_use = true;
+        }
+        Ok(())
+    }
+
+    // Writes the changes in the cache to the database and empties the cache.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.write_cache()
+            .with_context(|| format!("failed to write UTXO database {}", self.path.display()))
+    }
+
+    fn write_cache(&mut self) -> rusqlite::Result<()> {
+        if self.cache.values().any(|e| e.dirty) {
+            self.mark_in_use()?;
+        }
+
+        let tx = self.conn.transaction()?;
+        {
+            let mut inserter = tx.prepare_cached(
+                "INSERT OR REPLACE INTO utxos VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7);",
+            )?;
+            let mut deleter = tx.prepare_cached("DELETE FROM utxos WHERE tx = ?1 AND idx = ?2;")?;
+            for (k, e) in self.cache.drain().filter(|(_, e)| e.dirty) {
+                match e.output {
+                    Some(o) => inserter.execute(params![
+                        k.tx,
+                        k.index,
+                        o.block,
+                        o.value,
+                        o.script_type,
+                        o.address,
+                        o.written
+                    ])?,
+                    None => deleter.execute(params![k.tx, k.index])?,
+                };
+            }
+        }
+        self.cache_bytes = 0;
+        tx.commit()
+    }
+
+    fn flush_if_full(&mut self) -> anyhow::Result<()> {
+        match self.cache_bytes >= self.cache_size {
+            true => self.flush(),
+            false => Ok(()),
+        }
+    }
+}
+
+impl UtxoStore for SQLiteUtxoStore {
+    fn insert(
+        &mut self,
+        key: OutputHashAndIndex,
+        output: UnmatchedOutput,
+        may_exist: bool,
+    ) -> anyhow::Result<Option<UnmatchedOutput>> {
+        // An output that was removed may still be in the database, but then the cache knows.
+        let existing = match may_exist || self.cache.contains_key(&key) {
+            true => self.lookup(&key)?,
+            false => None,
+        };
+        // Whether the database may have the output is still known after replacing it.
+        let fresh = self.cache.get(&key).is_none_or(|e| e.fresh);
+        self.cache_entry(
+            key,
+            CacheEntry {
+                output: Some(output),
+                dirty: true,
+                fresh,
+            },
+        );
+        if existing.is_none() {
+            self.len += 1;
+        }
+        self.flush_if_full()?;
+        Ok(existing)
+    }
+
+    fn remove(&mut self, key: &OutputHashAndIndex) -> anyhow::Result<Option<UnmatchedOutput>> {
+        let existing = match self.lookup(key)? {
+            Some(o) => o,
+            None => return Ok(None),
+        };
+        match self.cache[key].fresh {
+            true => self.uncache_entry(key),
+            false => self.cache_entry(
+                *key,
+                CacheEntry {
+                    output: None,
+                    dirty: true,
+                    fresh: false,
+                },
+            ),
+        }
+        self.len -= 1;
+        self.flush_if_full()?;
+        Ok(Some(existing))
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn write_unwritten(
+        &mut self,
+        f: &mut dyn FnMut(&OutputHashAndIndex, &UnmatchedOutput) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        self.flush()?;
+        let read_error = || format!("failed to read UTXO database {}", self.path.display());
+        let mut query = self
+            .conn
+            .prepare(
+                "SELECT tx, idx, block, value, script_type, address FROM utxos WHERE written = 0;",
+            )
+            .with_context(read_error)?;
+        let rows = query
+            .query_map([], |r| {
+                Ok((
+                    OutputHashAndIndex {
+                        tx: r.get(0)?,
+                        index: r.get(1)?,
+                    },
+                    UnmatchedOutput {
+                        block: r.get(2)?,
+                        value: r.get(3)?,
+                        script_type: r.get(4)?,
+                        address: r.get(5)?,
+                        written: false,
+                    },
+                ))
+            })
+            .with_context(read_error)?;
+        for row in rows {
+            let (k, v) = row.with_context(read_error)?;
+            f(&k, &v)?;
+        }
+        drop(query);
+        self.mark_in_use()
+            .and_then(|_| {
+                self.conn
+                    .execute("UPDATE utxos SET written = 1 WHERE written = 0;", [])
+            })
+            .with_context(|| format!("failed to write UTXO database {}", self.path.display()))?;
+        Ok(())
+    }
+
+    fn into_saved(mut self: Box<Self>) -> anyhow::Result<SavedOutputs> {
+        self.flush()?;
+        self.conn
+            .pragma_update(None, "user_version", 0)
+            .with_context(|| format!("failed to save UTXO database {}", self.path.display()))?;
+        Ok(SavedOutputs::OnDisk(self.path.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::ScriptType;
+    use crate::transaction::{BlockHash, TxHash};
+
+    fn key(i: u8) -> OutputHashAndIndex {
+        OutputHashAndIndex {
+            tx: TxHash::new([i; 32]),
+            index: 0,
+        }
+    }
+
+    fn output(block: u8) -> UnmatchedOutput {
+        UnmatchedOutput {
+            block: BlockHash::new([block; 32]),
+            value: 5000,
+            script_type: ScriptType::P2wpkh,
+            address: Some(String::from("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4")),
+            written: false,
+        }
+    }
+
+    // A store whose cache is written out after every change.
+    fn uncached_store(dir: &tempfile::TempDir) -> SQLiteUtxoStore {
+        SQLiteUtxoStore::create(&dir.path().join("utxos.db"), 0).unwrap()
+    }
+
+    #[test]
+    fn keeps_outputs_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = uncached_store(&dir);
+        for i in 0..3 {
+            assert!(store.insert(key(i), output(i), false).unwrap().is_none());
+        }
+        assert!(store.cache.is_empty());
+        assert_eq!(store.len(), 3);
+
+        let removed = store.remove(&key(1)).unwrap().unwrap();
+        assert_eq!(removed.block, BlockHash::new([1; 32]));
+        assert!(store.remove(&key(1)).unwrap().is_none());
+        assert_eq!(store.len(), 2);
+
+        let mut unwritten = Vec::new();
+        store
+            .write_unwritten(&mut |k, _| {
+                unwritten.push(*k);
+                Ok(())
+            })
+            .unwrap();
+        unwritten.sort_by_key(|k| k.tx);
+        assert_eq!(unwritten, vec![key(0), key(2)]);
+    }
+
+    #[test]
+    fn finds_duplicates_on_disk_when_asked_to() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = uncached_store(&dir);
+        store.insert(key(0), output(1), false).unwrap();
+
+        let existing = store.insert(key(0), output(2), true).unwrap().unwrap();
+        assert_eq!(existing.block, BlockHash::new([1; 32]));
+        assert_eq!(store.len(), 1);
+        let current = store.remove(&key(0)).unwrap().unwrap();
+        assert_eq!(current.block, BlockHash::new([2; 32]));
+    }
+
+    #[test]
+    fn counts_addresses_against_the_cache_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = SQLiteUtxoStore::create(&dir.path().join("utxos.db"), 1 << 20).unwrap();
+        let mut without_address = output(0);
+        without_address.address = None;
+        store.insert(key(0), without_address, false).unwrap();
+        assert_eq!(store.cache_bytes, CACHE_ENTRY_OVERHEAD);
+
+        store.insert(key(1), output(0), false).unwrap();
+        assert_eq!(store.cache_bytes, 2 * CACHE_ENTRY_OVERHEAD + 42);
+        store.remove(&key(1)).unwrap();
+        assert_eq!(store.cache_bytes, CACHE_ENTRY_OVERHEAD);
+    }
+}
@@ -1,8 +1,9 @@
 use clap::Parser;
 use futures::{future, prelude::*};
-use search::custom_format::load_data_sorted;
+use search::custom_format::{load_data_sorted, sorted_data_network, SortedData};
+use search::network::Network;
 use search::rpc_service::Search;
-use search::transaction::{Block, BlockHash, InputOutputPair, Transaction, TxHash};
+use search::transaction::{Block, BlockHash, InputOutputPair, Transaction, TxHash, Value};
 use std::fmt::Debug;
 use std::net::{IpAddr, Ipv4Addr};
 use std::sync::Arc;
@@ -22,27 +23,57 @@ struct Args {
 
 #[derive(Clone)]
 struct SearchWorker {
+    network: Network,
     txs: Arc<Vec<Transaction>>,
+    // Positions in `txs` of the transactions that have a fee, sorted by fee and by feerate.
+    txs_by_fee: Arc<Vec<u32>>,
+    txs_by_feerate: Arc<Vec<u32>>,
     blocks: Arc<Vec<Block>>,
     iopairs_sorted_src: Arc<Vec<InputOutputPair>>,
     iopairs_sorted_dest: Arc<Vec<InputOutputPair>>,
+    // Positions in `iopairs_sorted_src` of the iopairs whose output has an address, sorted by it.
+    iopairs_by_address: Arc<Vec<u32>>,
+    // Positions in `iopairs_sorted_src` of the iopairs of OP_RETURN outputs, sorted by their data.
+    iopairs_by_data: Arc<Vec<u32>>,
 }
 
 impl SearchWorker {
-    fn new() -> SearchWorker {
-        let (txs, blocks, iopairs_sorted_src, iopairs_sorted_dest) = load_data_sorted();
+    fn new(network: Network) -> SearchWorker {
+        SearchWorker::with_data(network, load_data_sorted())
+    }
+
+    fn with_data(network: Network, data: SortedData) -> SearchWorker {
+        let (
+            txs,
+            txs_by_fee,
+            txs_by_feerate,
+            blocks,
+            iopairs_sorted_src,
+            iopairs_sorted_dest,
+            iopairs_by_address,
+            iopairs_by_data,
+        ) = data;
 
         SearchWorker {
+            network,
             txs,
+            txs_by_fee,
+            txs_by_feerate,
             blocks,
             iopairs_sorted_src,
             iopairs_sorted_dest,
+            iopairs_by_address,
+            iopairs_by_data,
         }
     }
 }
 
 #[tarpc::server]
 impl Search for SearchWorker {
+    async fn network(self, _: Context) -> Network {
+        self.network
+    }
+
     async fn transactions_by_sources(
         self,
         _: Context,
@@ -94,8 +125,10 @@ impl Search for SearchWorker {
             find_elements_in_sorted_vec(&self.txs, |x| x.id, t, &mut result);
         }
 
-        result.sort_unstable_by_key(|k| k.id);
-        result.dedup_by_key(|k| k.id);
+        // A txid can belong to more than one transaction (see BIP 30), so the block is part of the
+        // key here.
+        result.sort_unstable_by_key(|k| (k.id, k.block));
+        result.dedup_by_key(|k| (k.id, k.block));
 
         result
     }
@@ -112,27 +145,161 @@ impl Search for SearchWorker {
 
         result
     }
+
+    async fn transactions_by_fee(self, _: Context, min: Value, max: Value) -> Vec<Transaction> {
+        let mut result: Vec<Transaction> = Vec::new();
+        find_elements_in_sorted_range(
+            &self.txs,
+            &self.txs_by_fee,
+            |x| x.fee,
+            Some(min),
+            Some(max),
+            &mut result,
+        );
+
+        result
+    }
+
+    async fn transactions_by_feerate(self, _: Context, min: f64, max: f64) -> Vec<Transaction> {
+        let mut result: Vec<Transaction> = Vec::new();
+        find_elements_in_sorted_range(
+            &self.txs,
+            &self.txs_by_feerate,
+            |x| x.feerate,
+            Some(min),
+            Some(max),
+            &mut result,
+        );
+
+        result
+    }
+
+    async fn outputs_by_address(self, _: Context, targets: Vec<String>) -> Vec<InputOutputPair> {
+        self.find_by_address(targets)
+    }
+
+    async fn spends_by_address(self, _: Context, targets: Vec<String>) -> Vec<InputOutputPair> {
+        let mut result = self.find_by_address(targets);
+        result.retain(|x| x.dest.is_some());
+
+        result
+    }
+
+    async fn op_returns_by_prefix(self, _: Context, prefix: Vec<u8>) -> Vec<InputOutputPair> {
+        let v = &self.iopairs_sorted_src;
+        let order = &self.iopairs_by_data;
+        let data = |i: &u32| op_return_data(&v[*i as usize]);
+        let start_index = order.partition_point(|i| data(i) < prefix.as_slice());
+        let end_index =
+            start_index + order[start_index..].partition_point(|i| data(i).starts_with(&prefix));
+
+        order[start_index..end_index]
+            .iter()
+            .map(|i| v[*i as usize].clone())
+            .collect()
+    }
+
+    // There is no index for this, so all OP_RETURN outputs are searched. An empty needle matches
+    // nothing, rather than every OP_RETURN output.
+    async fn op_returns_containing(self, _: Context, needle: Vec<u8>) -> Vec<InputOutputPair> {
+        if needle.is_empty() {
+            return Vec::new();
+        }
+        self.iopairs_by_data
+            .iter()
+            .map(|i| &self.iopairs_sorted_src[*i as usize])
+            .filter(|x| op_return_data(x).windows(needle.len()).any(|w| w == needle))
+            .cloned()
+            .collect()
+    }
+}
+
+impl SearchWorker {
+    fn find_by_address(&self, targets: Vec<String>) -> Vec<InputOutputPair> {
+        let mut result: Vec<InputOutputPair> = Vec::new();
+
+        for t in targets.into_iter() {
+            find_elements_in_sorted_order(
+                &self.iopairs_sorted_src,
+                &self.iopairs_by_address,
+                |x| x.source.address.as_deref(),
+                Some(t.as_str()),
+                &mut result,
+            );
+        }
+
+        result.sort_unstable();
+        result.dedup();
+
+        result
+    }
 }
 
 // This function finds the elements `x` in `v` that match `F(x) == y` and appends them to
 // `collector`. Note that `v` must be pre-sorted in such a way that all the elements that match
 // `F(x) == y` must be consecutive, and all of the elements that match `F(x) < y` must be before
 // the elements that match `F(x) == y`.
-fn find_elements_in_sorted_vec<T: Copy, F, Y: Ord + Debug>(
-    v: &Vec<T>,
+fn find_elements_in_sorted_vec<T: Clone, F, Y: Ord + Debug>(
+    v: &[T],
     f: F,
     y: Y,
     collector: &mut Vec<T>,
-) -> ()
-where
+) where
     F: Fn(&T) -> Y,
 {
     let start_index = v.partition_point(|x| f(x) < y);
     let end_index = v.partition_point(|x| f(x) <= y);
 
-    for i in start_index..end_index {
-        collector.push(v[i])
-    }
+    collector.extend_from_slice(&v[start_index..end_index]);
+}
+
+// Like `find_elements_in_sorted_vec`, but for the elements of `v` at the positions in `order`, which
+// must be sorted by `F(x)` instead of `v` itself.
+fn find_elements_in_sorted_order<'v, T: Clone, F, Y: Ord>(
+    v: &'v [T],
+    order: &[u32],
+    f: F,
+    y: Y,
+    collector: &mut Vec<T>,
+) where
+    F: Fn(&'v T) -> Y,
+{
+    let start_index = order.partition_point(|i| f(&v[*i as usize]) < y);
+    let end_index = order.partition_point(|i| f(&v[*i as usize]) <= y);
+
+    collector.extend(
+        order[start_index..end_index]
+            .iter()
+            .map(|i| v[*i as usize].clone()),
+    );
+}
+
+// The data carried by an iopair from `iopairs_by_data`.
+fn op_return_data(x: &InputOutputPair) -> &[u8] {
+    x.source.data.as_deref().unwrap_or_default()
+}
+
+// Like `find_elements_in_sorted_order`, but finds the elements that match `min <= F(x) <= max`.
+fn find_elements_in_sorted_range<T: Clone, F, Y: PartialOrd>(
+    v: &[T],
+    order: &[u32],
+    f: F,
+    min: Y,
+    max: Y,
+    collector: &mut Vec<T>,
+) where
+    F: Fn(&T) -> Y,
+{
+    let start_index = order.partition_point(|i| f(&v[*i as usize]) < min);
+    let end_index = order
+        .partition_point(|i| f(&v[*i as usize]) <= max)
+        .max(start_index);
+
+    collector.extend(
+        order[start_index..end_index]
+            .iter()
+            .map(|i| v[*i as usize].clone()),
+    );
 }
 
 #[tokio::main]
@@ -141,7 +308,9 @@ async fn main() -> anyhow::Result<()> {
 
     let server_addr = (IpAddr::V4(Ipv4Addr::UNSPECIFIED), args.port);
 
-    println!("loading data...");
+    // The files of a shard are copied to the worker separately, so make sure they belong together.
+    let network = sorted_data_network()?;
+    println!("loading {} data...", network);
     let _ = load_data_sorted();
     println!("data loaded...");
 
@@ -158,7 +327,7 @@ async fn main() -> anyhow::Result<()> {
         // serve is generated by the service attribute. It takes as input any type implementing
         // the generated World trait.
         .map(|channel| {
-            let server = SearchWorker::new();
+            let server = SearchWorker::new(network);
             println!(
                 "Connected to master {:?}",
                 channel.transport().peer_addr().unwrap()
@@ -172,3 +341,211 @@ async fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use search::custom_format::index_sorted_data;
+    use search::script::ScriptType;
+    use search::transaction::{Input, Output};
+    use tarpc::context;
+
+    // An output of transaction `n` that pays to `address`, or carries `data` if that is set.
+    fn output(n: u8, address: &str, data: Option<&[u8]>) -> InputOutputPair {
+        InputOutputPair {
+            source: Output {
+                src_tx: TxHash::new([n; 32]),
+                src_block: BlockHash::new([0; 32]),
+                src_index: 0,
+                value: 1000,
+                script_type: match data {
+                    Some(_) => ScriptType::OpReturn,
+                    None => ScriptType::P2wpkh,
+                },
+                address: data.is_none().then(|| address.to_string()),
+                data: data.map(|d| d.to_vec()),
+                data_protocol: None,
+            },
+            dest: None,
+        }
+    }
+
+    // Outputs of transactions 1 to 7, sorted by source tx. Transaction 3 is spent.
+    fn iopairs() -> Vec<InputOutputPair> {
+        let mut spent = output(3, "bc1a", None);
+        spent.dest = Some(Input {
+            dest_tx: TxHash::new([8; 32]),
+            dest_block: BlockHash::new([0; 32]),
+            dest_index: 0,
+            sequence: u32::MAX,
+        });
+        vec![
+            output(1, "bc1c", None),
+            output(2, "", Some(&[1, 2, 3])),
+            spent,
+            output(4, "", Some(&[0xff])),
+            output(5, "", Some(&[])),
+            output(6, "bc1b", None),
+            output(7, "", Some(&[1, 2])),
+        ]
+    }
+
+    // Transaction `n`, with a fee if it is set.
+    fn tx(n: u8, fee: Option<Value>, vsize: u32) -> Transaction {
+        Transaction {
+            id: TxHash::new([n; 32]),
+            wtxid: TxHash::new([n; 32]),
+            version: 2,
+            block: BlockHash::new([0; 32]),
+            block_height: 1,
+            size: vsize,
+            input_count: 1,
+            output_count: 1,
+            lock_time: 0,
+            weight: vsize * 4,
+            vsize,
+            is_coinbase: fee.is_none(),
+            fee,
+            feerate: fee.map(|f| f as f64 / vsize as f64),
+        }
+    }
+
+    // Transactions 1 to 5, sorted by txid. Transaction 2 is a coinbase.
+    fn transactions() -> Vec<Transaction> {
+        vec![
+            tx(1, Some(500), 100),
+            tx(2, None, 100),
+            tx(3, Some(100), 200),
+            tx(4, Some(500), 250),
+            tx(5, Some(10_000), 100),
+        ]
+    }
+
+    fn worker() -> SearchWorker {
+        let iopairs = iopairs();
+        let spent = iopairs
+            .iter()
+            .filter(|p| p.dest.is_some())
+            .cloned()
+            .collect();
+        let data = index_sorted_data(transactions(), Vec::new(), iopairs, spent);
+        SearchWorker::with_data(Network::Regtest, data)
+    }
+
+    // The transactions of the outputs in `result`.
+    fn txs(result: &[InputOutputPair]) -> Vec<u8> {
+        result.iter().map(|p| p.source.src_tx.as_ref()[0]).collect()
+    }
+
+    // The transactions in `result`.
+    fn ids(result: &[Transaction]) -> Vec<u8> {
+        result.iter().map(|t| t.id.as_ref()[0]).collect()
+    }
+
+    #[test]
+    fn indexes_transactions_by_fee_and_feerate() {
+        let worker = worker();
+        assert_eq!(*worker.txs_by_fee, [2, 0, 3, 4]);
+        assert_eq!(*worker.txs_by_feerate, [2, 3, 0, 4]);
+    }
+
+    #[test]
+    fn finds_transactions_in_a_fee_range() {
+        let find = |min: Value, max: Value| {
+            let result = block_on(worker().transactions_by_fee(context::current(), min, max));
+            ids(&result)
+        };
+        assert_eq!(find(0, Value::MAX), [3, 1, 4, 5]);
+        assert_eq!(find(0, 100), [3]);
+        assert_eq!(find(500, 500), [1, 4]);
+        assert_eq!(find(10_000, 10_000), [5]);
+        assert!(find(101, 499).is_empty());
+        assert!(find(10_001, Value::MAX).is_empty());
+        assert!(find(600, 400).is_empty());
+    }
+
+    #[test]
+    fn finds_transactions_in_a_feerate_range() {
+        let find = |min: f64, max: f64| {
+            let result = block_on(worker().transactions_by_feerate(context::current(), min, max));
+            ids(&result)
+        };
+        assert_eq!(find(0.0, f64::INFINITY), [3, 4, 1, 5]);
+        assert_eq!(find(0.5, 2.0), [3, 4]);
+        assert_eq!(find(5.0, 100.0), [1, 5]);
+        assert!(find(0.0, 0.4).is_empty());
+        assert!(find(2.5, 4.0).is_empty());
+        assert!(find(100.5, f64::INFINITY).is_empty());
+        assert!(find(5.0, 2.0).is_empty());
+    }
+
+    #[test]
+    fn indexes_outputs_by_address() {
+        let worker = worker();
+        assert_eq!(*worker.iopairs_by_address, [2, 5, 0]);
+    }
+
+    #[test]
+    fn indexes_op_returns_by_data() {
+        let worker = worker();
+        assert_eq!(*worker.iopairs_by_data, [4, 6, 1, 3]);
+    }
+
+    #[test]
+    fn finds_outputs_by_address() {
+        let worker = worker();
+        let find = |address: &str| {
+            let mut result = Vec::new();
+            find_elements_in_sorted_order(
+                &worker.iopairs_sorted_src,
+                &worker.iopairs_by_address,
+                |x| x.source.address.as_deref(),
+                Some(address),
+                &mut result,
+            );
+            txs(&result)
+        };
+        assert_eq!(find("bc1a"), [3]);
+        assert_eq!(find("bc1c"), [1]);
+        assert!(find("bc0").is_empty());
+        assert!(find("bc1bb").is_empty());
+        assert!(find("bc1d").is_empty());
+
+        let spends = block_on(worker.clone().spends_by_address(
+            context::current(),
+            vec!["bc1a".to_string(), "bc1b".to_string()],
+        ));
+        assert_eq!(txs(&spends), [3]);
+    }
+
+    #[test]
+    fn finds_op_returns_by_prefix() {
+        let find = |prefix: &[u8]| {
+            let result =
+                block_on(worker().op_returns_by_prefix(context::current(), prefix.to_vec()));
+            txs(&result)
+        };
+        assert_eq!(find(&[]), [5, 7, 2, 4]);
+        assert_eq!(find(&[1]), [7, 2]);
+        assert_eq!(find(&[1, 2, 3]), [2]);
+        assert_eq!(find(&[0xff]), [4]);
+        assert!(find(&[0]).is_empty());
+        assert!(find(&[1, 3]).is_empty());
+        assert!(find(&[0xff, 0]).is_empty());
+    }
+
+    #[test]
+    fn finds_op_returns_containing_a_needle() {
+        let find = |needle: &[u8]| {
+            let result =
+                block_on(worker().op_returns_containing(context::current(), needle.to_vec()));
+            txs(&result)
+        };
+        assert!(find(&[]).is_empty());
+        assert_eq!(find(&[2]), [7, 2]);
+        assert_eq!(find(&[2, 3]), [2]);
+        assert_eq!(find(&[0xff]), [4]);
+        assert!(find(&[9]).is_empty());
+    }
+}
@@ -0,0 +1,200 @@
+use base64::Engine;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+// A stand-in for Bitcoin Core's JSON-RPC interface that serves a fixed set of blocks, for testing
+// the parser's RPC block source without a node. It only knows the calls the parser makes.
+pub struct MockNode {
+    url: String,
+    chain: Arc<Mutex<Chain>>,
+}
+
+struct Chain {
+    // Block hashes (in hex, as shown by Bitcoin Core) and raw blocks (in hex), by height.
+    blocks: Vec<(String, String)>,
+    heights: HashMap<String, usize>,
+    // Number of blocks on the best chain. Blocks above it can still be fetched by hash, like stale
+    // blocks in Bitcoin Core.
+    best_len: usize,
+    // Once the header of the best chain's tip has been served, the best chain ends at this height.
+    truncate_after_headers: Option<usize>,
+    // Expected value of the HTTP `Authorization` header.
+    authorization: Option<String>,
+}
+
+// An error as Bitcoin Core reports it.
+struct RpcError {
+    code: i32,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i32, message: &str) -> RpcError {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl MockNode {
+    // Serves `blocks`, given as raw blocks in hex ordered by height, starting at the genesis block.
+    // If `auth` is given as `user:password`, requests have to authenticate with it.
+    pub fn start(blocks: &[&str], auth: Option<&str>) -> MockNode {
+        let mut heights = HashMap::new();
+        let blocks: Vec<(String, String)> = blocks
+            .iter()
+            .enumerate()
+            .map(|(height, b)| {
+                let raw = hex::decode(b).unwrap();
+                let mut hash: [u8; 32] = Sha256::digest(Sha256::digest(&raw[..80])).into();
+                hash.reverse();
+                let hash = hex::encode(hash);
+                heights.insert(hash.clone(), height);
+                (hash, b.to_lowercase())
+            })
+            .collect();
+        let chain = Arc::new(Mutex::new(Chain {
+            best_len: blocks.len(),
+            blocks,
+            heights,
+            truncate_after_headers: None,
+            authorization: auth.map(|a| {
+                format!(
+                    "Basic {}",
+                    base64::engine::general_purpose::STANDARD.encode(a)
+                )
+            }),
+        }));
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let served = chain.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let chain = served.clone();
+                std::thread::spawn(move || serve(&chain, stream));
+            }
+        });
+        MockNode { url, chain }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    // Makes the best chain end at `height` once the header of its current tip has been served, as
+    // if the blocks above had been invalidated right after the parser indexed them.
+    pub fn truncate_after_headers(&self, height: usize) {
+        self.chain.lock().unwrap().truncate_after_headers = Some(height);
+    }
+}
+
+impl Chain {
+    fn height_of(&self, hash: &Value) -> Result<usize, RpcError> {
+        hash.as_str()
+            .and_then(|h| self.heights.get(h))
+            .copied()
+            .ok_or_else(|| RpcError::new(-5, "Block not found"))
+    }
+
+    fn call(&mut self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        match method {
+            "getblockcount" => Ok(json!(self.best_len - 1)),
+            "getblockhash" => params[0]
+                .as_u64()
+                .filter(|h| (*h as usize) < self.best_len)
+                .map(|h| json!(self.blocks[h as usize].0))
+                .ok_or_else(|| RpcError::new(-8, "Block height out of range")),
+            "getblock" => match params[1].as_u64() {
+                Some(0) => Ok(json!(self.blocks[self.height_of(&params[0])?].1)),
+                _ => Err(RpcError::new(-8, "only verbosity 0 is supported")),
+            },
+            "getblockheader" => match params[1].as_bool() {
+                Some(false) => {
+                    let height = self.height_of(&params[0])?;
+                    if height + 1 == self.best_len {
+                        if let Some(h) = self.truncate_after_headers.take() {
+                            self.best_len = h + 1;
+                        }
+                    }
+                    Ok(json!(&self.blocks[height].1[..160]))
+                }
+                _ => Err(RpcError::new(-8, "only verbose=false is supported")),
+            },
+            _ => Err(RpcError::new(-32601, "Method not found")),
+        }
+    }
+}
+
+// Answers requests on one connection until the client closes it.
+fn serve(chain: &Mutex<Chain>, stream: TcpStream) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line)? == 0 {
+            return Ok(());
+        }
+
+        let mut content_length = 0;
+        let mut authorization = None;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "content-length" => content_length = value.trim().parse().unwrap_or(0),
+                    "authorization" => authorization = Some(value.trim().to_string()),
+                    _ => {}
+                }
+            }
+        }
+        let mut body = vec![0u8; content_length];
+        reader.read_exact(&mut body)?;
+
+        let mut chain = chain.lock().unwrap();
+        if chain.authorization.is_some() && authorization != chain.authorization {
+            write!(
+                writer,
+                "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n"
+            )?;
+            continue;
+        }
+
+        let request: Value = serde_json::from_slice(&body).unwrap_or_default();
+        let method = request["method"].as_str().unwrap_or_default();
+        let (status, response) = match chain.call(method, &request["params"]) {
+            Ok(result) => (
+                "200 OK",
+                json!({"result": result, "error": null, "id": request["id"]}),
+            ),
+            Err(e) => (
+                "500 Internal Server Error",
+                json!({
+                    "result": null,
+                    "error": {"code": e.code, "message": e.message},
+                    "id": request["id"],
+                }),
+            ),
+        };
+        drop(chain);
+
+        let response = response.to_string();
+        write!(
+            writer,
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            response.len(),
+            response
+        )?;
+    }
+}
@@ -0,0 +1,161 @@
+mod mock_rpc;
+
+use anyhow::bail;
+use mock_rpc::MockNode;
+use search::block_source::BlockSource;
+use search::node_rpc::{RpcAuth, RpcEndpoint};
+use search::output_writer::OutputWriter;
+use search::parser::Parser;
+use search::script::ScriptType;
+use search::transaction::{BlockHash, InputOutputPair, Transaction, TxHash};
+
+// The first three blocks of the mainnet chain.
+const BLOCKS: [&str; 3] = [
+    "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c0101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000",
+    "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051fd1e4ba744bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e362990101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0704ffff001d0104ffffffff0100f2052a0100000043410496b538e853519c726a2c91e61ec11600ae1390813a627c66fb8be7947be63c52da7589379515d4e0a604f8141781e62294721166bf621e73a82cbf2342c858eeac00000000",
+    "010000004860eb18bf1b1620e37e9490fc8a427514416fd75159ab86688e9a8300000000d5fdcc541e25de1c7a5addedf24858b8bb665c9f36ef744ee42c316022c90f9bb0bc6649ffff001d08d2bd610101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0704ffff001d010bffffffff0100f2052a010000004341047211a824f55b505228e4c3d5194c1fcfaa15a456abdf37f9b9d97a4040afc073dee6c89064984f03385237d92167c13e236446b417ab79a0fcae412ae3316b77ac00000000",
+];
+
+const BLOCK_IDS: [&str; 3] = [
+    "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+    "00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048",
+    "000000006a625f06636b8bb6ac7b960a8d03705d1ace08b1a19da3fdcc99ddbd",
+];
+
+const COINBASE_TXIDS: [&str; 3] = [
+    "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+    "0e3e2357e806b6cdb1f70b54c3a3a17b6714ee1f0e68bebb44a74b1efd512098",
+    "9b0fc92260312ce44e74ef369f5c66bbb85848f2eddd5a7a1cde251e54ccfdd5",
+];
+
+// The P2PKH addresses of the keys the coinbases pay to.
+const ADDRESSES: [&str; 3] = [
+    "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa",
+    "12c6DSiU4Rq3P4ZxziKxzrL5LmMBrzjrJX",
+    "1HLoD9E4SDFFPDiYfNYnkBLQ85Y51J3Zb1",
+];
+
+// Keeps everything the parser writes.
+#[derive(Default)]
+struct Collected {
+    txs: Vec<Transaction>,
+    blocks: Vec<search::transaction::Block>,
+    iopairs: Vec<InputOutputPair>,
+}
+
+impl OutputWriter for Collected {
+    fn insert_tx(&mut self, tx: Transaction) -> anyhow::Result<()> {
+        self.txs.push(tx);
+        Ok(())
+    }
+
+    fn insert_block(&mut self, b: search::transaction::Block) -> anyhow::Result<()> {
+        self.blocks.push(b);
+        Ok(())
+    }
+
+    fn insert_iopair(&mut self, iopair: InputOutputPair) -> anyhow::Result<()> {
+        self.iopairs.push(iopair);
+        Ok(())
+    }
+
+    fn retract_block(&mut self, b: search::transaction::Block) -> anyhow::Result<()> {
+        bail!("unexpected retraction of block {:?}", b.id)
+    }
+}
+
+fn parse(node: &MockNode, auth: RpcAuth) -> anyhow::Result<Collected> {
+    let mut out = Collected::default();
+    let mut parser = Parser::new(&mut out);
+    parser.parse(&BlockSource::Rpc(RpcEndpoint {
+        url: node.url().to_string(),
+        auth,
+    }))?;
+    drop(parser);
+    Ok(out)
+}
+
+fn assert_parsed_all_blocks(out: &Collected) {
+    let blocks: Vec<(BlockHash, u32)> = out.blocks.iter().map(|b| (b.id, b.height)).collect();
+    let expected: Vec<(BlockHash, u32)> = BLOCK_IDS
+        .iter()
+        .zip(0..)
+        .map(|(id, height)| (BlockHash::new_from_str(id), height))
+        .collect();
+    assert_eq!(blocks, expected);
+
+    let txs: Vec<(TxHash, u32, bool)> = out
+        .txs
+        .iter()
+        .map(|t| (t.id, t.block_height, t.is_coinbase))
+        .collect();
+    let expected: Vec<(TxHash, u32, bool)> = COINBASE_TXIDS
+        .iter()
+        .zip(0..)
+        .map(|(id, height)| (TxHash::new_from_str(id), height, true))
+        .collect();
+    assert_eq!(txs, expected);
+
+    let mut unspent: Vec<(TxHash, u64, ScriptType, Option<&str>)> = out
+        .iopairs
+        .iter()
+        .filter(|p| p.dest.is_none())
+        .map(|p| {
+            (
+                p.source.src_tx,
+                p.source.value,
+                p.source.script_type,
+                p.source.address.as_deref(),
+            )
+        })
+        .collect();
+    unspent.sort();
+    let mut expected: Vec<(TxHash, u64, ScriptType, Option<&str>)> = COINBASE_TXIDS
+        .iter()
+        .zip(ADDRESSES)
+        .map(|(id, a)| {
+            (
+                TxHash::new_from_str(id),
+                50_0000_0000,
+                ScriptType::P2pk,
+                Some(a),
+            )
+        })
+        .collect();
+    expected.sort();
+    assert_eq!(unspent, expected);
+}
+
+#[test]
+fn parses_the_best_chain_of_a_node() {
+    let node = MockNode::start(&BLOCKS, None);
+    let out = parse(&node, RpcAuth::None).unwrap();
+    assert_parsed_all_blocks(&out);
+}
+
+#[test]
+fn fetches_the_indexed_blocks_when_the_best_chain_changes() {
+    // Block 2 leaves the node's best chain after the parser indexed its header. It is still on the
+    // chain the parser found, so it has to be fetched by its hash rather than by its height.
+    let node = MockNode::start(&BLOCKS, None);
+    node.truncate_after_headers(1);
+    let out = parse(&node, RpcAuth::None).unwrap();
+    assert_parsed_all_blocks(&out);
+}
+
+#[test]
+fn authenticates_to_the_node() {
+    let node = MockNode::start(&BLOCKS, Some("user:secret"));
+    let auth = |password: &str| RpcAuth::UserPassword {
+        user: String::from("user"),
+        password: password.to_string(),
+    };
+
+    let out = parse(&node, auth("secret")).unwrap();
+    assert_eq!(out.blocks.len(), 3);
+
+    let e = parse(&node, auth("wrong")).err().unwrap();
+    assert!(format!("{:#}", e).contains("rejected the RPC credentials"));
+    let e = parse(&node, RpcAuth::None).err().unwrap();
+    assert!(format!("{:#}", e).contains("rejected the RPC credentials"));
+}